@@ -0,0 +1,397 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use terrors::OneOf;
+
+use crate::bmap::{Bmap, BmapBuilder};
+use crate::checksum::Algorithm;
+use crate::flash::{self, FlashPhase, FlashStatus, ProcessStoppedByUser, Progress};
+
+/// Amount of data read from the drive at once.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Granularity at which blocks of zeroes are left out of images, which is
+/// also the block size of their block maps.
+const BLOCK_SIZE: usize = 4096;
+
+/// How the image of a drive is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupCompression {
+    None,
+    Xz,
+    Zstd,
+}
+
+impl BackupCompression {
+    /// Parses the value of the `backup-compression` setting.
+    pub fn from_setting(value: &str) -> Self {
+        match value {
+            "xz" => Self::Xz,
+            "zstd" => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::None => "img",
+            Self::Xz => "img.xz",
+            Self::Zstd => "img.zst",
+        }
+    }
+}
+
+/// User preferences that change how a drive is backed up.
+#[derive(Clone, Copy, Debug)]
+pub struct BackupOptions {
+    pub compression: BackupCompression,
+    /// Stop at the end of the last partition and leave out the gaps between them.
+    pub used_space_only: bool,
+    /// Save a block map of the image next to it.
+    pub bmap: bool,
+}
+
+/// Reads a drive into an image file.
+pub struct BackupRequest {
+    source: udisks::Object,
+    destination: PathBuf,
+    options: BackupOptions,
+    status: Arc<Mutex<FlashStatus>>,
+    is_running: Arc<AtomicBool>,
+    /// Whether the image file was created, as only then it is removed on errors.
+    created: AtomicBool,
+}
+
+impl BackupRequest {
+    pub const fn new(
+        source: udisks::Object,
+        destination: PathBuf,
+        options: BackupOptions,
+        status: Arc<Mutex<FlashStatus>>,
+        is_running: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            source,
+            destination,
+            options,
+            status,
+            is_running,
+            created: AtomicBool::new(false),
+        }
+    }
+
+    pub async fn perform(self) {
+        match self.perform_job().await {
+            Ok(()) => {
+                set_status(&self.status, FlashStatus::Done(None));
+            }
+            Err(e) => {
+                if self.created.load(Ordering::SeqCst)
+                    && let Err(e) = std::fs::remove_file(&self.destination)
+                {
+                    error!(
+                        "Failed to remove incomplete image {}: {e}",
+                        self.destination.display()
+                    );
+                }
+
+                // A block map left from before wouldn't match the new image
+                if self.options.bmap && self.created.load(Ordering::SeqCst) {
+                    let _ = std::fs::remove_file(self.bmap_path());
+                }
+
+                if let Err(e) = e.narrow::<ProcessStoppedByUser, _>() {
+                    error!("Backup failed: {e}");
+                    set_status(&self.status, FlashStatus::Done(Some(e.to_string())));
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::single_range_in_vec_init)]
+    async fn perform_job(
+        &self,
+    ) -> Result<(), OneOf<(ProcessStoppedByUser, std::io::Error, udisks::Error)>> {
+        info!(
+            "Backing up {:?} to {} ({:?})",
+            self.source.object_path(),
+            self.destination.display(),
+            self.options
+        );
+
+        let client = udisks::Client::new().await.map_err(OneOf::new)?;
+
+        // File systems that are mounted can change while they are read
+        if let Err(e) = flash::unmount_partitions(&client, &self.source).await {
+            error!("Error unmounting partitions, will be ignored: {e}");
+        }
+
+        let block = self.source.block().await.map_err(OneOf::new)?;
+        let size = block.size().await.map_err(OneOf::new)?;
+
+        let regions = if self.options.used_space_only {
+            match used_regions(&client, &self.source, size).await {
+                Ok(regions) => regions,
+                Err(e) => {
+                    warn!("Failed to read the partition table, backing up the whole drive: {e}");
+                    vec![0..size]
+                }
+            }
+        } else {
+            vec![0..size]
+        };
+        info!("Backing up {regions:?}");
+
+        let source = flash::udisks_open_for_reading(&block)
+            .await
+            .map_err(OneOf::new)?
+            .into_std()
+            .await;
+
+        let destination = File::create(&self.destination).map_err(OneOf::new)?;
+        self.created.store(true, Ordering::SeqCst);
+
+        let options = self.options;
+        let status = self.status.clone();
+        let is_running = self.is_running.clone();
+
+        let bmap = tokio::task::spawn_blocking(move || {
+            copy_drive(source, destination, &regions, options, &status, &is_running)
+        })
+        .await
+        .map_err(|e| OneOf::new(std::io::Error::other(e)))?
+        .map_err(OneOf::broaden)?;
+
+        if self.options.bmap {
+            std::fs::write(self.bmap_path(), bmap.to_xml()).map_err(OneOf::new)?;
+        }
+
+        info!("Backup completed successfully");
+
+        Ok(())
+    }
+
+    /// Where the block map is saved, so that it is found next to the image.
+    fn bmap_path(&self) -> PathBuf {
+        let mut path = self.destination.clone().into_os_string();
+        path.push(".bmap");
+        PathBuf::from(path)
+    }
+}
+
+/// Parts of the drive up to the end of its last partition that are worth
+/// backing up: everything in front of the first partition, as boot loaders
+/// often live there, and the partitions themselves.
+///
+/// The backup GPT header at the very end of the drive is left out, partition
+/// tools recreate it once the image is written.
+async fn used_regions(
+    client: &udisks::Client,
+    object: &udisks::Object,
+    size: u64,
+) -> udisks::Result<Vec<Range<u64>>> {
+    let partition_table = object.partition_table().await?;
+
+    let mut partitions = Vec::new();
+    for partition in client.partitions(&partition_table).await {
+        let offset = partition.offset().await?;
+        partitions.push(offset..offset + partition.size().await?);
+    }
+    partitions.sort_by_key(|partition| partition.start);
+
+    let mut regions: Vec<Range<u64>> = Vec::new();
+    for partition in partitions {
+        // Rounded to whole blocks, so that zero blocks line up with the block map
+        let block_size = BLOCK_SIZE as u64;
+        let start = partition.start / block_size * block_size;
+        let end = partition
+            .end
+            .div_ceil(block_size)
+            .saturating_mul(block_size);
+
+        match regions.last_mut() {
+            None => regions.push(0..end),
+            Some(last) if last.end >= start => last.end = last.end.max(end),
+            Some(_) => regions.push(start..end),
+        }
+    }
+
+    for region in &mut regions {
+        region.end = region.end.min(size);
+    }
+
+    if regions.is_empty() {
+        regions.push(0..size);
+    }
+
+    Ok(regions)
+}
+
+fn set_status(status: &Mutex<FlashStatus>, new_status: FlashStatus) {
+    if let Ok(mut lock) = status.lock() {
+        *lock = new_status;
+    }
+}
+
+/// Where the image of a drive goes.
+trait Sink: Write {
+    /// Leaves `len` bytes of zeroes, as a hole where the format allows.
+    fn skip(&mut self, len: u64) -> std::io::Result<()>;
+}
+
+impl Sink for File {
+    fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        let len = i64::try_from(len).map_err(std::io::Error::other)?;
+        self.seek(SeekFrom::Current(len)).map(|_| ())
+    }
+}
+
+impl<W: Write> Sink for xz2::write::XzEncoder<W> {
+    fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        write_zeroes(self, len)
+    }
+}
+
+impl<W: Write> Sink for zstd::Encoder<'_, W> {
+    fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        write_zeroes(self, len)
+    }
+}
+
+fn write_zeroes(target: &mut impl Write, mut len: u64) -> std::io::Result<()> {
+    static ZEROES: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+    while len > 0 {
+        #[allow(clippy::cast_possible_truncation)]
+        let x = len.min(BLOCK_SIZE as u64) as usize;
+        target.write_all(&ZEROES[..x])?;
+        len -= x as u64;
+    }
+
+    Ok(())
+}
+
+fn copy_drive(
+    mut source: File,
+    mut destination: File,
+    regions: &[Range<u64>],
+    options: BackupOptions,
+    status: &Mutex<FlashStatus>,
+    is_running: &AtomicBool,
+) -> Result<Bmap, OneOf<(ProcessStoppedByUser, std::io::Error)>> {
+    let (destination, bmap) = match options.compression {
+        BackupCompression::None => {
+            let bmap = copy_into(&mut source, &mut destination, regions, status, is_running)?;
+            // Holes at the end are only part of the file once its size covers them
+            destination.set_len(bmap.image_size).map_err(OneOf::new)?;
+            (destination, bmap)
+        }
+        BackupCompression::Xz => {
+            let threads = std::thread::available_parallelism()
+                .map_or(1, |threads| u32::try_from(threads.get()).unwrap_or(1));
+            let stream = xz2::stream::MtStreamBuilder::new()
+                .preset(6)
+                .threads(threads)
+                .encoder()
+                .map_err(|e| OneOf::new(std::io::Error::from(e)))?;
+
+            let mut encoder = xz2::write::XzEncoder::new_stream(destination, stream);
+            let bmap = copy_into(&mut source, &mut encoder, regions, status, is_running)?;
+            (encoder.finish().map_err(OneOf::new)?, bmap)
+        }
+        BackupCompression::Zstd => {
+            let mut encoder = zstd::Encoder::new(destination, 0).map_err(OneOf::new)?;
+            let bmap = copy_into(&mut source, &mut encoder, regions, status, is_running)?;
+            (encoder.finish().map_err(OneOf::new)?, bmap)
+        }
+    };
+
+    destination.sync_all().map_err(OneOf::new)?;
+
+    Ok(bmap)
+}
+
+/// Copies `regions` of the drive to `target` and leaves everything else,
+/// just like blocks of zeroes, as holes. Returns the block map of the image.
+fn copy_into(
+    source: &mut File,
+    target: &mut impl Sink,
+    regions: &[Range<u64>],
+    status: &Mutex<FlashStatus>,
+    is_running: &AtomicBool,
+) -> Result<Bmap, OneOf<(ProcessStoppedByUser, std::io::Error)>> {
+    let mut last_set = Instant::now();
+    let mut buf = vec![0; CHUNK_SIZE].into_boxed_slice();
+    let mut bmap = BmapBuilder::new(BLOCK_SIZE as u64, Algorithm::Sha256);
+
+    let size = regions.iter().map(|region| region.end - region.start).sum();
+    let mut copied = 0_u64;
+    // Where the image written so far ends
+    let mut position = 0_u64;
+
+    set_status(
+        status,
+        FlashStatus::Active(FlashPhase::Backup, Progress::Fraction(0.0)),
+    );
+
+    for region in regions {
+        target.skip(region.start - position).map_err(OneOf::new)?;
+        position = region.start;
+        source
+            .seek(SeekFrom::Start(region.start))
+            .map_err(OneOf::new)?;
+
+        while position < region.end {
+            #[allow(clippy::cast_possible_truncation)]
+            let len = (region.end - position).min(CHUNK_SIZE as u64) as usize;
+            read_exact(source, &mut buf[..len], position).map_err(OneOf::new)?;
+
+            for block in buf[..len].chunks(BLOCK_SIZE) {
+                if block.iter().all(|byte| *byte == 0) {
+                    target.skip(block.len() as u64).map_err(OneOf::new)?;
+                } else {
+                    target.write_all(block).map_err(OneOf::new)?;
+                    bmap.add(position, block);
+                }
+                position += block.len() as u64;
+            }
+            copied += len as u64;
+
+            if !is_running.load(Ordering::SeqCst) {
+                return Err(OneOf::new(ProcessStoppedByUser));
+            }
+
+            if last_set.elapsed() >= Duration::from_millis(250) {
+                set_status(
+                    status,
+                    FlashStatus::Active(FlashPhase::Backup, Progress::from((copied, size))),
+                );
+                last_set = Instant::now();
+            }
+        }
+    }
+
+    Ok(bmap.finish(position))
+}
+
+fn read_exact(source: &mut File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    source.read_exact(buf).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "The drive ended before offset {}",
+                    offset + buf.len() as u64
+                ),
+            )
+        } else {
+            e
+        }
+    })
+}
@@ -0,0 +1,519 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use sha2::digest::DynDigest;
+
+use crate::checksum::{Algorithm, Digest};
+
+/// Extensions that are stripped from an image name when looking for its block map.
+const STRIPPED_EXTENSIONS: [&str; 10] = [
+    "xz", "gz", "bz2", "zst", "zip", "iso", "img", "raw", "bin", "wic",
+];
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid block map: {0}")]
+pub struct InvalidBmap(String);
+
+/// Bytes `start..end` of an image that hold data, with their expected checksum.
+#[derive(Debug, Clone)]
+pub struct MappedRange {
+    pub start: u64,
+    pub end: u64,
+    pub digest: Option<Digest>,
+}
+
+/// A block map as produced by `bmaptool create`, listing which parts of an
+/// image have to be written.
+#[derive(Debug, Clone)]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub algorithm: Algorithm,
+    pub ranges: Vec<MappedRange>,
+}
+
+impl Bmap {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Parses a block map of format version 1.x or 2.x.
+    pub fn parse(contents: &str) -> Result<Self, InvalidBmap> {
+        let doc = roxmltree::Document::parse(contents).map_err(|e| InvalidBmap(e.to_string()))?;
+        let root = doc.root_element();
+
+        let version = root.attribute("version").unwrap_or_default();
+        let major_version = version.split('.').next().unwrap_or_default();
+        if !root.has_tag_name("bmap") || !matches!(major_version, "1" | "2") {
+            return Err(InvalidBmap(format!("unsupported version {version:?}")));
+        }
+
+        let text_of = |tag: &str| {
+            root.children()
+                .find(|node| node.has_tag_name(tag))
+                .and_then(|node| node.text())
+                .map(str::trim)
+        };
+        let number_of = |tag: &str| {
+            text_of(tag)
+                .and_then(|text| text.parse::<u64>().ok())
+                .ok_or_else(|| InvalidBmap(format!("missing or invalid {tag}")))
+        };
+
+        let image_size = number_of("ImageSize")?;
+        let block_size = number_of("BlockSize")?;
+        if block_size == 0 {
+            return Err(InvalidBmap("block size is zero".to_owned()));
+        }
+
+        // Version 1 block maps always use SHA-1 and call the attribute after it
+        let (algorithm, checksum_attribute, file_checksum_tag) = if major_version == "1" {
+            (Algorithm::Sha1, "sha1", "BmapFileSHA1")
+        } else {
+            let algorithm = match text_of("ChecksumType") {
+                Some("sha1") => Algorithm::Sha1,
+                Some("sha256") => Algorithm::Sha256,
+                other => return Err(InvalidBmap(format!("unsupported checksum type {other:?}"))),
+            };
+            (algorithm, "chksum", "BmapFileChecksum")
+        };
+
+        if let Some(expected) = text_of(file_checksum_tag) {
+            check_file_checksum(contents, expected, algorithm)?;
+        }
+
+        let block_map = root
+            .children()
+            .find(|node| node.has_tag_name("BlockMap"))
+            .ok_or_else(|| InvalidBmap("missing BlockMap".to_owned()))?;
+
+        let mut ranges = Vec::new();
+        for node in block_map
+            .children()
+            .filter(|node| node.has_tag_name("Range"))
+        {
+            let text = node.text().unwrap_or_default().trim();
+            let (first, last) = text.split_once('-').unwrap_or((text, text));
+
+            let (Ok(first), Ok(last)) = (first.trim().parse::<u64>(), last.trim().parse::<u64>())
+            else {
+                return Err(InvalidBmap(format!("invalid range {text:?}")));
+            };
+
+            let start = first.saturating_mul(block_size);
+            let end = last
+                .saturating_add(1)
+                .saturating_mul(block_size)
+                .min(image_size);
+
+            if first > last
+                || start >= end
+                || ranges.last().is_some_and(|r: &MappedRange| r.end > start)
+            {
+                return Err(InvalidBmap(format!("invalid range {text:?}")));
+            }
+
+            ranges.push(MappedRange {
+                start,
+                end,
+                digest: node.attribute(checksum_attribute).map(|value| Digest {
+                    algorithm,
+                    value: value.trim().to_ascii_lowercase(),
+                }),
+            });
+        }
+
+        Ok(Self {
+            image_size,
+            block_size,
+            algorithm,
+            ranges,
+        })
+    }
+
+    /// Number of bytes that are actually written.
+    pub fn mapped_size(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    /// Serializes the block map in the format version 2.0 of `bmaptool`.
+    pub fn to_xml(&self) -> String {
+        let algorithm = self.algorithm.name().replace('-', "").to_ascii_lowercase();
+        let placeholder = "0".repeat(self.algorithm.hasher().output_size() * 2);
+
+        let mut xml = String::from("<?xml version=\"1.0\" ?>\n<bmap version=\"2.0\">\n");
+        let _ = writeln!(xml, "    <ImageSize> {} </ImageSize>", self.image_size);
+        let _ = writeln!(xml, "    <BlockSize> {} </BlockSize>", self.block_size);
+        let _ = writeln!(
+            xml,
+            "    <BlocksCount> {} </BlocksCount>",
+            self.image_size.div_ceil(self.block_size)
+        );
+        let _ = writeln!(
+            xml,
+            "    <MappedBlocksCount> {} </MappedBlocksCount>",
+            self.mapped_size().div_ceil(self.block_size)
+        );
+        let _ = writeln!(xml, "    <ChecksumType> {algorithm} </ChecksumType>");
+        let _ = writeln!(
+            xml,
+            "    <BmapFileChecksum> {placeholder} </BmapFileChecksum>"
+        );
+        xml.push_str("    <BlockMap>\n");
+        for range in &self.ranges {
+            let first = range.start / self.block_size;
+            let last = (range.end - 1) / self.block_size;
+            let blocks = if first == last {
+                first.to_string()
+            } else {
+                format!("{first}-{last}")
+            };
+            match &range.digest {
+                Some(digest) => {
+                    let _ = writeln!(
+                        xml,
+                        "        <Range chksum=\"{}\"> {blocks} </Range>",
+                        digest.value
+                    );
+                }
+                None => {
+                    let _ = writeln!(xml, "        <Range> {blocks} </Range>");
+                }
+            }
+        }
+        xml.push_str("    </BlockMap>\n</bmap>\n");
+
+        let mut hasher = self.algorithm.hasher();
+        hasher.update(xml.as_bytes());
+        xml.replacen(&placeholder, &hex::encode(hasher.finalize()), 1)
+    }
+}
+
+/// Builds the block map of an image while it is created, from the blocks
+/// that hold data.
+pub struct BmapBuilder {
+    block_size: u64,
+    algorithm: Algorithm,
+    ranges: Vec<MappedRange>,
+    /// The range that is still being extended, with the hasher of its data.
+    current: Option<(u64, u64, Box<dyn DynDigest + Send>)>,
+}
+
+impl BmapBuilder {
+    pub fn new(block_size: u64, algorithm: Algorithm) -> Self {
+        Self {
+            block_size,
+            algorithm,
+            ranges: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Marks `data` at `offset`, which starts at a block, as holding data.
+    pub fn add(&mut self, offset: u64, data: &[u8]) {
+        debug_assert!(offset.is_multiple_of(self.block_size));
+
+        match &mut self.current {
+            Some((_, end, hasher)) if *end == offset => {
+                hasher.update(data);
+                *end += data.len() as u64;
+            }
+            _ => {
+                self.finish_range();
+                let mut hasher = self.algorithm.hasher();
+                hasher.update(data);
+                self.current = Some((offset, offset + data.len() as u64, hasher));
+            }
+        }
+    }
+
+    fn finish_range(&mut self) {
+        if let Some((start, end, hasher)) = self.current.take() {
+            self.ranges.push(MappedRange {
+                start,
+                end,
+                digest: Some(Digest {
+                    algorithm: self.algorithm,
+                    value: hex::encode(hasher.finalize()),
+                }),
+            });
+        }
+    }
+
+    pub fn finish(mut self, image_size: u64) -> Bmap {
+        self.finish_range();
+
+        Bmap {
+            image_size,
+            block_size: self.block_size,
+            algorithm: self.algorithm,
+            ranges: self.ranges,
+        }
+    }
+}
+
+/// The checksum of a block map covers the file with the checksum itself
+/// replaced by zeros.
+fn check_file_checksum(
+    contents: &str,
+    expected: &str,
+    algorithm: Algorithm,
+) -> Result<(), InvalidBmap> {
+    let zeroed = contents.replacen(expected, &"0".repeat(expected.len()), 1);
+
+    let mut hasher = algorithm.hasher();
+    hasher.update(zeroed.as_bytes());
+
+    if hex::encode(hasher.finalize()).eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(InvalidBmap("the block map is corrupted".to_owned()))
+    }
+}
+
+/// Looks for the block map that usually ships next to an image, like
+/// `image.wic.bmap` for `image.wic.xz`.
+pub fn find_sibling(image: &Path) -> Option<PathBuf> {
+    let mut candidate = image.to_path_buf();
+
+    loop {
+        let mut name = candidate.as_os_str().to_owned();
+        name.push(".bmap");
+        let bmap = PathBuf::from(name);
+        if bmap.is_file() {
+            return Some(bmap);
+        }
+
+        let extension = candidate.extension()?.to_str()?.to_ascii_lowercase();
+        if !STRIPPED_EXTENSIONS.contains(&extension.as_str()) {
+            return None;
+        }
+        candidate.set_extension("");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block map as written by `bmaptool` 3.x, with format version 1.4.
+    const VERSION_1_4: &str = r#"<?xml version="1.0" ?>
+<!-- This file contains the block map for an image file, which is basically
+     a list of useful (mapped) block numbers in the image file. -->
+<bmap version="1.4">
+    <!-- Image size in bytes: 8.0 MiB -->
+    <ImageSize> 8388608 </ImageSize>
+
+    <!-- Size of a block in bytes -->
+    <BlockSize> 4096 </BlockSize>
+
+    <!-- Count of blocks in the image file -->
+    <BlocksCount> 2048 </BlocksCount>
+
+    <!-- Count of mapped blocks: 20.0 KiB or 0.2% -->
+    <MappedBlocksCount> 5 </MappedBlocksCount>
+
+    <!-- The checksum of this bmap file. When it is calculated, the value of
+         the SHA1 checksum has be zero (40 ASCII "0" symbols). -->
+    <BmapFileSHA1> 8602bc6bded90b3f69d45c9c20f129dc3ff4298c </BmapFileSHA1>
+
+    <!-- The block map which consists of elements which may either be a
+         range of blocks or a single block. The 'sha1' attribute (if present)
+         is the SHA1 checksum of this blocks range. -->
+    <BlockMap>
+        <Range sha1="3a8b5bc9a5e0c1b6b1d4d2c2bd6f3d8a1b0c9e7f"> 0-1 </Range>
+        <Range sha1="DA39A3EE5E6B4B0D3255BFEF95601890AFD80709"> 100 </Range>
+        <Range> 2046-2047 </Range>
+    </BlockMap>
+</bmap>
+"#;
+
+    /// A block map in format version 2.0, with an image size that isn't a
+    /// multiple of the block size.
+    const VERSION_2_0: &str = r#"<?xml version="1.0" ?>
+<!-- This file contains the block map for an image file, which is basically
+     a list of useful (mapped) block numbers in the image file. -->
+<bmap version="2.0">
+    <!-- Image size in bytes: 1.0 MiB -->
+    <ImageSize> 1048000 </ImageSize>
+
+    <!-- Size of a block in bytes -->
+    <BlockSize> 4096 </BlockSize>
+
+    <!-- Count of blocks in the image file -->
+    <BlocksCount> 256 </BlocksCount>
+
+    <!-- Count of mapped blocks: 12.0 KiB or 4.7% -->
+    <MappedBlocksCount> 3 </MappedBlocksCount>
+
+    <!-- Type of checksum used in this file -->
+    <ChecksumType> sha256 </ChecksumType>
+
+    <!-- The checksum of this bmap file. When it is calculated, the value of
+         the checksum has be zero (all ASCII "0" symbols). -->
+    <BmapFileChecksum> a4c79015e98021b095034933057c7461fcc38f9fb01dac9d0f0173e07f836217 </BmapFileChecksum>
+
+    <BlockMap>
+        <Range chksum="9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"> 0-1 </Range>
+        <Range chksum="e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"> 255 </Range>
+    </BlockMap>
+</bmap>
+"#;
+
+    fn blocks(bmap: &Bmap) -> Vec<(u64, u64)> {
+        bmap.ranges
+            .iter()
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    #[test]
+    fn parses_version_1_4() {
+        let bmap = Bmap::parse(VERSION_1_4).expect("Valid block map was rejected");
+
+        assert_eq!(bmap.image_size, 8 * 1024 * 1024);
+        assert_eq!(bmap.block_size, 4096);
+        assert_eq!(bmap.algorithm, Algorithm::Sha1);
+        assert_eq!(
+            blocks(&bmap),
+            [(0, 8192), (409_600, 413_696), (8_380_416, 8_388_608)]
+        );
+        assert_eq!(bmap.mapped_size(), 5 * 4096);
+
+        assert_eq!(
+            bmap.ranges[1].digest,
+            Some(Digest {
+                algorithm: Algorithm::Sha1,
+                value: "da39a3ee5e6b4b0d3255bfef95601890afd80709".to_owned(),
+            })
+        );
+        assert_eq!(bmap.ranges[2].digest, None);
+    }
+
+    #[test]
+    fn parses_version_2_0() {
+        let bmap = Bmap::parse(VERSION_2_0).expect("Valid block map was rejected");
+
+        assert_eq!(bmap.algorithm, Algorithm::Sha256);
+        // The last block is cut short at the end of the image
+        assert_eq!(blocks(&bmap), [(0, 8192), (1_044_480, 1_048_000)]);
+        assert_eq!(
+            bmap.ranges[0]
+                .digest
+                .as_ref()
+                .map(|digest| digest.value.as_str()),
+            Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_block_maps() {
+        let corrupted = VERSION_2_0.replace("> 255 <", "> 254 <");
+        assert!(Bmap::parse(&corrupted).is_err());
+
+        let corrupted = VERSION_1_4.replace("> 100 <", "> 101 <");
+        assert!(Bmap::parse(&corrupted).is_err());
+    }
+
+    /// A block map without a file checksum, which is optional.
+    fn unchecked(version: &str, block_size: u64, ranges: &str) -> String {
+        format!(
+            "<bmap version=\"{version}\"><ImageSize>65536</ImageSize>\
+             <BlockSize>{block_size}</BlockSize><ChecksumType>sha256</ChecksumType>\
+             <BlockMap>{ranges}</BlockMap></bmap>"
+        )
+    }
+
+    #[test]
+    fn accepts_block_maps_without_a_file_checksum() {
+        let bmap = Bmap::parse(&unchecked(
+            "2.0",
+            4096,
+            "<Range>1-2</Range><Range>4</Range>",
+        ))
+        .expect("Valid block map was rejected");
+
+        assert_eq!(blocks(&bmap), [(4096, 12288), (16384, 20480)]);
+    }
+
+    #[test]
+    fn rejects_invalid_block_maps() {
+        assert!(Bmap::parse(&unchecked("3.0", 4096, "<Range>0</Range>")).is_err());
+        assert!(Bmap::parse(&unchecked("2.0", 0, "<Range>0</Range>")).is_err());
+        assert!(Bmap::parse(&unchecked("2.0", 4096, "<Range>2-1</Range>")).is_err());
+        assert!(
+            Bmap::parse(&unchecked(
+                "2.0",
+                4096,
+                "<Range>0-2</Range><Range>1</Range>"
+            ))
+            .is_err()
+        );
+        assert!(Bmap::parse(&unchecked("2.0", 4096, "<Range>x</Range>")).is_err());
+        // Past the end of the image
+        assert!(Bmap::parse(&unchecked("2.0", 4096, "<Range>16</Range>")).is_err());
+        assert!(Bmap::parse("<bmap version=\"2.0\">").is_err());
+    }
+
+    #[test]
+    fn round_trips_built_block_maps() {
+        let mut builder = BmapBuilder::new(4096, Algorithm::Sha256);
+        builder.add(0, &[1; 4096]);
+        builder.add(4096, &[2; 4096]);
+        builder.add(5 * 4096, &[3; 4096]);
+        builder.add(6 * 4096, &[4; 1000]);
+        let bmap = builder.finish(6 * 4096 + 1000);
+
+        assert_eq!(blocks(&bmap), [(0, 8192), (20480, 25576)]);
+
+        let mut hasher = Algorithm::Sha256.hasher();
+        hasher.update(&[1; 4096]);
+        hasher.update(&[2; 4096]);
+        assert_eq!(
+            bmap.ranges[0]
+                .digest
+                .as_ref()
+                .map(|digest| digest.value.clone()),
+            Some(hex::encode(hasher.finalize()))
+        );
+
+        let xml = bmap.to_xml();
+        assert!(xml.contains("<bmap version=\"2.0\">"));
+        assert!(!xml.contains(&"0".repeat(64)));
+
+        let parsed = Bmap::parse(&xml).expect("Written block map was rejected");
+        assert_eq!(parsed.image_size, bmap.image_size);
+        assert_eq!(parsed.block_size, bmap.block_size);
+        assert_eq!(parsed.algorithm, bmap.algorithm);
+        assert_eq!(blocks(&parsed), blocks(&bmap));
+        assert_eq!(
+            parsed
+                .ranges
+                .iter()
+                .map(|range| range.digest.clone())
+                .collect::<Vec<_>>(),
+            bmap.ranges
+                .iter()
+                .map(|range| range.digest.clone())
+                .collect::<Vec<_>>()
+        );
+
+        // The file checksum covers the ranges
+        assert!(Bmap::parse(&xml.replace("> 0-1 <", "> 0-2 <")).is_err());
+    }
+
+    #[test]
+    fn writes_single_blocks_without_a_range() {
+        let mut builder = BmapBuilder::new(512, Algorithm::Sha1);
+        builder.add(1024, &[1; 512]);
+        let xml = builder.finish(4096).to_xml();
+
+        assert!(xml.contains("<ChecksumType> sha1 </ChecksumType>"));
+        assert!(xml.contains("> 2 </Range>"));
+        assert!(Bmap::parse(&xml).is_ok());
+    }
+}
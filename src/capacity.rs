@@ -0,0 +1,382 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::bmap::Bmap;
+use crate::get_size_string;
+use crate::source::ImageStream;
+use crate::sparse;
+use crate::vdisk::VirtualDiskReader;
+use crate::window::{Compression, DiskImage};
+
+const XZ_HEADER_SIZE: u64 = 12;
+const XZ_FOOTER_SIZE: u64 = 12;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "The image needs {} but the drive only holds {}",
+    get_size_string(*.needed),
+    get_size_string(*.available)
+)]
+pub struct ImageTooLarge {
+    pub needed: u64,
+    pub available: u64,
+}
+
+/// Checks that `needed` bytes fit on a drive of `available` bytes.
+pub const fn check(needed: Option<u64>, available: u64) -> Result<(), ImageTooLarge> {
+    match needed {
+        Some(needed) if needed > available => Err(ImageTooLarge { needed, available }),
+        _ => Ok(()),
+    }
+}
+
+/// How much room `image` takes up on a drive, as far as it can be told
+/// without decompressing it, or `None` if it can't.
+pub async fn declared_size(image: &DiskImage) -> Option<u64> {
+    let size = match image {
+        DiskImage::Local {
+            bmap: Some(path), ..
+        } => Bmap::load(path).map(|bmap| Some(bmap.image_size)),
+        DiskImage::Local {
+            path, compression, ..
+        } => {
+            let path = path.clone();
+            let compression = compression.clone();
+            tokio::task::spawn_blocking(move || declared_file_size(&path, &compression))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+        }
+        DiskImage::Drive { object, .. } => match object.block().await {
+            Ok(block) => block.size().await.map(Some).map_err(std::io::Error::other),
+            Err(e) => Err(std::io::Error::other(e)),
+        },
+        DiskImage::Online { .. } => Ok(None),
+    };
+
+    size.inspect_err(|e| warn!("Failed to tell the size of the image: {e}"))
+        .ok()
+        .flatten()
+}
+
+/// How much room the image at `path` takes up on a drive, decompressing
+/// all of it if its size isn't declared anywhere.
+///
+/// `on_progress` is called with the number of bytes decompressed so far;
+/// returning [`ControlFlow::Break`] stops decompressing and yields `None`.
+pub async fn decoded_size<F: FnMut(u64) -> ControlFlow<()>>(
+    path: &Path,
+    compression: &Compression,
+    mut on_progress: F,
+) -> std::io::Result<Option<u64>> {
+    let mut image = ImageStream::open(path, compression)?;
+    let mut size = 0_u64;
+    let mut first_chunk = true;
+
+    while let Some(chunk) = image.next_chunk().await {
+        let chunk = chunk?;
+
+        if std::mem::take(&mut first_chunk)
+            && let Some(expanded) = sparse::expanded_size(&chunk)
+        {
+            return Ok(Some(expanded));
+        }
+
+        size += chunk.len() as u64;
+
+        if on_progress(size).is_break() {
+            return Ok(None);
+        }
+    }
+
+    info!("Decompressed {} to {size} bytes", path.display());
+
+    Ok(Some(size))
+}
+
+fn declared_file_size(path: &Path, compression: &Compression) -> std::io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+
+    match compression {
+        Compression::Raw => {
+            let mut header = Vec::new();
+            (&file).take(64).read_to_end(&mut header)?;
+            Ok(Some(match sparse::expanded_size(&header) {
+                Some(expanded) => expanded,
+                None => file.metadata()?.len(),
+            }))
+        }
+        Compression::Xz => xz_size(&mut file),
+        Compression::Zstd => zstd_size(&mut file),
+        Compression::Zip { entry } => {
+            let mut archive = zip::ZipArchive::new(file)?;
+            let size = archive.by_name(entry)?.size();
+            Ok(Some(size))
+        }
+        Compression::VirtualDisk(format) => {
+            Ok(Some(VirtualDiskReader::open(file, *format)?.size()))
+        }
+        // Gzip only keeps the size modulo 4 GiB, and bzip2 not at all
+        Compression::Gzip | Compression::Bzip2 => Ok(None),
+    }
+}
+
+/// Adds up the uncompressed sizes in the index of a single stream XZ file.
+fn xz_size(file: &mut File) -> std::io::Result<Option<u64>> {
+    let len = file.metadata()?.len();
+    if len < XZ_HEADER_SIZE + XZ_FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    let mut footer = [0; 12];
+    file.seek(SeekFrom::Start(len - XZ_FOOTER_SIZE))?;
+    file.read_exact(&mut footer)?;
+    if &footer[10..] != b"YZ" {
+        return Ok(None);
+    }
+
+    let index_size = (u64::from(u32::from_le_bytes([
+        footer[4], footer[5], footer[6], footer[7],
+    ])) + 1)
+        * 4;
+    let Some(index_start) = (len - XZ_FOOTER_SIZE).checked_sub(index_size) else {
+        return Ok(None);
+    };
+
+    #[allow(clippy::cast_possible_truncation)]
+    let mut index = vec![0; index_size as usize];
+    file.seek(SeekFrom::Start(index_start))?;
+    file.read_exact(&mut index)?;
+
+    if index.first() != Some(&0) {
+        return Ok(None);
+    }
+    let mut position = 1;
+    let Some(records) = read_varint(&index, &mut position) else {
+        return Ok(None);
+    };
+
+    let mut blocks_size = 0_u64;
+    let mut size = 0_u64;
+    for _ in 0..records {
+        let (Some(unpadded), Some(uncompressed)) = (
+            read_varint(&index, &mut position),
+            read_varint(&index, &mut position),
+        ) else {
+            return Ok(None);
+        };
+        blocks_size += unpadded.next_multiple_of(4);
+        size += uncompressed;
+    }
+
+    // Files of several streams have more than this one index to add up
+    if XZ_HEADER_SIZE + blocks_size + index_size + XZ_FOOTER_SIZE != len {
+        return Ok(None);
+    }
+
+    Ok(Some(size))
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value = 0_u64;
+
+    for shift in (0..63).step_by(7) {
+        let byte = *bytes.get(*position)?;
+        *position += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Reads the content size from the header of a Zstandard file of a single frame.
+fn zstd_size(file: &mut File) -> std::io::Result<Option<u64>> {
+    let mut header = Vec::new();
+    file.by_ref().take(18).read_to_end(&mut header)?;
+
+    if !header.starts_with(&ZSTD_MAGIC) || header.len() < 5 {
+        return Ok(None);
+    }
+
+    let descriptor = header[4];
+    let single_segment = descriptor & 0x20 != 0;
+    let dictionary_id_size = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+    let size_size = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => return Ok(None),
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+
+    let start = 5 + usize::from(!single_segment) + dictionary_id_size;
+    let Some(field) = header.get(start..start + size_size) else {
+        return Ok(None);
+    };
+
+    let mut bytes = [0; 8];
+    bytes[..size_size].copy_from_slice(field);
+    let size = u64::from_le_bytes(bytes);
+
+    // Files of several frames have more than this one size to add up
+    let frame_end = zstd_frame_end(file, (start + size_size) as u64, descriptor)?;
+    if frame_end != Some(file.metadata()?.len()) {
+        return Ok(None);
+    }
+
+    // Two byte sizes leave out the first 256 values, which fit in one byte
+    Ok(Some(if size_size == 2 { size + 256 } else { size }))
+}
+
+/// Finds where the Zstandard frame whose blocks start at `position` ends,
+/// by following the sizes in the headers of its blocks.
+fn zstd_frame_end(
+    file: &mut File,
+    mut position: u64,
+    descriptor: u8,
+) -> std::io::Result<Option<u64>> {
+    loop {
+        let mut header = [0; 3];
+        file.seek(SeekFrom::Start(position))?;
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+        // Blocks of a single repeated byte only store that byte
+        let block_size = match (header >> 1) & 0b11 {
+            0 | 2 => header >> 3,
+            1 => 1,
+            _ => return Ok(None),
+        };
+        position += 3 + u64::from(block_size);
+
+        if header & 1 != 0 {
+            break;
+        }
+    }
+
+    // The checksum of the content comes after the last block
+    if descriptor & 0x04 != 0 {
+        position += 4;
+    }
+
+    Ok(Some(position))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Calls `f` with a temporary file holding `data`.
+    fn with_file<T>(name: &str, data: &[u8], f: impl FnOnce(&mut File) -> std::io::Result<T>) -> T {
+        let path = std::env::temp_dir().join(format!("impression-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).expect("Failed to write the image");
+
+        let result = File::open(&path).and_then(|mut file| f(&mut file));
+        std::fs::remove_file(&path).expect("Failed to remove the image");
+
+        result.expect("Failed to read the image")
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(data).expect("Failed to compress");
+        encoder.finish().expect("Failed to compress")
+    }
+
+    fn image() -> Vec<u8> {
+        (0..300_000_u32).flat_map(u32::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn reads_varints() {
+        let bytes = [0x05, 0xE5, 0x8E, 0x26, 0x80];
+        let mut position = 0;
+
+        assert_eq!(read_varint(&bytes, &mut position), Some(5));
+        assert_eq!(position, 1);
+        assert_eq!(read_varint(&bytes, &mut position), Some(624_485));
+        assert_eq!(position, 4);
+        // The last byte says that more follow, but nothing does
+        assert_eq!(read_varint(&bytes, &mut position), None);
+
+        let too_long = [0xFF; 10];
+        assert_eq!(read_varint(&too_long, &mut 0), None);
+    }
+
+    #[test]
+    fn reads_xz_sizes() {
+        let image = image();
+
+        assert_eq!(
+            with_file("size.xz", &xz(&image), xz_size),
+            Some(image.len() as u64)
+        );
+        assert_eq!(with_file("empty.xz", &xz(&[]), xz_size), Some(0));
+    }
+
+    #[test]
+    fn leaves_out_xz_files_it_cant_add_up() {
+        let image = image();
+        let streams = [xz(&image), xz(&image)].concat();
+        assert_eq!(with_file("streams.xz", &streams, xz_size), None);
+
+        let mut truncated = xz(&image);
+        truncated.truncate(truncated.len() - 1);
+        assert_eq!(with_file("truncated.xz", &truncated, xz_size), None);
+    }
+
+    #[test]
+    fn reads_zstd_sizes() {
+        let image = image();
+
+        for (len, level) in [
+            (0, 3),
+            (200, 3),
+            (1000, 3),
+            (image.len(), 3),
+            (image.len(), 19),
+        ] {
+            let compressed =
+                zstd::bulk::compress(&image[..len], level).expect("Failed to compress");
+            assert_eq!(
+                with_file("size.zst", &compressed, zstd_size),
+                Some(len as u64),
+                "{len} bytes at level {level}"
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_out_zstd_files_it_cant_add_up() {
+        let image = image();
+        let frame = zstd::bulk::compress(&image, 3).expect("Failed to compress");
+
+        let frames = [frame.clone(), frame.clone()].concat();
+        assert_eq!(with_file("frames.zst", &frames, zstd_size), None);
+
+        let truncated = &frame[..frame.len() - 1];
+        assert_eq!(with_file("truncated.zst", truncated, zstd_size), None);
+
+        // Streamed frames don't say how big they are
+        let mut encoder = zstd::Encoder::new(Vec::new(), 3).expect("Failed to compress");
+        encoder
+            .include_contentsize(false)
+            .expect("Failed to compress");
+        encoder.write_all(&image).expect("Failed to compress");
+        let streamed = encoder.finish().expect("Failed to compress");
+        assert_eq!(with_file("streamed.zst", &streamed, zstd_size), None);
+    }
+}
@@ -0,0 +1,323 @@
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::time::SystemTime;
+
+use sha2::digest::DynDigest;
+use tokio::{fs::File, io::AsyncReadExt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    /// Guesses the algorithm from the length of a hex encoded digest.
+    const fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(Self::Md5),
+            40 => Some(Self::Sha1),
+            64 => Some(Self::Sha256),
+            128 => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Reads the algorithm from its name, as in `sha256:…` or `SHA-256:…`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Some(Self::Md5),
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "MD5",
+            Self::Sha1 => "SHA-1",
+            Self::Sha256 => "SHA-256",
+            Self::Sha512 => "SHA-512",
+        }
+    }
+
+    pub fn hasher(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            Self::Md5 => Box::new(md5::Md5::default()),
+            Self::Sha1 => Box::new(sha1::Sha1::default()),
+            Self::Sha256 => Box::new(sha2::Sha256::default()),
+            Self::Sha512 => Box::new(sha2::Sha512::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub algorithm: Algorithm,
+    /// Lowercase hex encoding of the digest.
+    pub value: String,
+}
+
+impl Digest {
+    /// Parses a hex digest as typed by the user, optionally prefixed with the
+    /// algorithm (`sha256:…`) or followed by a file name (`… image.iso`).
+    ///
+    /// A prefix has to name the algorithm that the length of the digest is of.
+    pub fn parse(text: &str) -> Option<Self> {
+        let token = text.split_whitespace().next()?;
+        match token.split_once(':') {
+            Some((name, value)) => Self::from_hex(value)
+                .filter(|digest| Algorithm::from_name(name) == Some(digest.algorithm)),
+            None => Self::from_hex(token),
+        }
+    }
+
+    fn from_hex(value: &str) -> Option<Self> {
+        if !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        Some(Self {
+            algorithm: Algorithm::from_hex_len(value.len())?,
+            value: value.to_ascii_lowercase(),
+        })
+    }
+
+    /// Finds the digest of `file_name` in the contents of a checksum file.
+    ///
+    /// Both the GNU (`<digest>  <file>`) and the BSD (`SHA256 (<file>) = <digest>`)
+    /// formats are understood. A file holding a single bare digest is accepted
+    /// as well, as some projects publish one `.sha256` file per image.
+    pub fn from_sums_file(contents: &str, file_name: &str) -> Option<Self> {
+        let lines = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect::<Vec<_>>();
+
+        if let [line] = lines.as_slice()
+            && line.split_whitespace().count() == 1
+        {
+            return Self::from_hex(line);
+        }
+
+        lines.into_iter().find_map(|line| {
+            let (name, value) = if let Some((head, value)) = line.rsplit_once(") = ") {
+                let (_, name) = head.split_once(" (")?;
+                (name, value)
+            } else {
+                let (value, name) = line.split_once(char::is_whitespace)?;
+                (name.trim_start().trim_start_matches('*'), value)
+            };
+
+            let name = name.rsplit('/').next().unwrap_or(name);
+            if name == file_name {
+                Self::from_hex(value.trim())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.value)
+    }
+}
+
+/// A digest computed earlier, which only counts while the file is the same
+/// size and hasn't been modified since.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDigest {
+    pub digest: Digest,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileDigest {
+    /// Computes the digest of the file at `path`, like [`compute_file`].
+    pub async fn compute<F: FnMut(u64, u64) -> ControlFlow<()>>(
+        path: &Path,
+        algorithm: Algorithm,
+        on_progress: F,
+    ) -> std::io::Result<Option<Self>> {
+        let (len, modified) = stamp(path)?;
+
+        Ok(compute_file(path, algorithm, on_progress)
+            .await?
+            .map(|digest| Self {
+                digest,
+                len,
+                modified,
+            }))
+    }
+
+    /// Whether this is the digest of the file at `path` as it is now.
+    pub fn is_current(&self, path: &Path) -> bool {
+        stamp(path).is_ok_and(|stamp| stamp == (self.len, self.modified))
+    }
+}
+
+fn stamp(path: &Path) -> std::io::Result<(u64, Option<SystemTime>)> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+/// Computes the digest of the file at `path`.
+///
+/// `on_progress` is called with the number of bytes hashed so far and the
+/// file size; returning [`ControlFlow::Break`] stops hashing and yields `None`.
+pub async fn compute_file<F: FnMut(u64, u64) -> ControlFlow<()>>(
+    path: &Path,
+    algorithm: Algorithm,
+    mut on_progress: F,
+) -> std::io::Result<Option<Digest>> {
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await.map_or(0, |meta| meta.len());
+
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0; 1024 * 1024].into_boxed_slice();
+    let mut total = 0_u64;
+
+    loop {
+        let x = file.read(&mut buf).await?;
+
+        if x == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..x]);
+        total += x as u64;
+
+        if on_progress(total, size).is_break() {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(Digest {
+        algorithm,
+        value: hex::encode(hasher.finalize()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn digest(algorithm: Algorithm, value: &str) -> Digest {
+        Digest {
+            algorithm,
+            value: value.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parses_typed_digests() {
+        let expected = Some(digest(Algorithm::Sha256, SHA256));
+
+        assert_eq!(
+            Digest::parse(&format!("  {}  ", SHA256.to_uppercase())),
+            expected
+        );
+        assert_eq!(Digest::parse(&format!("sha256:{SHA256}")), expected);
+        assert_eq!(Digest::parse(&format!("{SHA256}  image.iso")), expected);
+
+        let md5 = "d41d8cd98f00b204e9800998ecf8427e";
+        assert_eq!(Digest::parse(md5), Some(digest(Algorithm::Md5, md5)));
+        let sha1 = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+        assert_eq!(Digest::parse(sha1), Some(digest(Algorithm::Sha1, sha1)));
+        let sha512 = "ab".repeat(64);
+        assert_eq!(
+            Digest::parse(&sha512),
+            Some(digest(Algorithm::Sha512, &sha512))
+        );
+    }
+
+    #[test]
+    fn checks_algorithm_prefixes() {
+        let expected = Some(digest(Algorithm::Sha256, SHA256));
+        assert_eq!(Digest::parse(&format!("SHA-256:{SHA256}")), expected);
+
+        let sha512 = "ab".repeat(64);
+        assert_eq!(
+            Digest::parse(&format!("sha512:{sha512}")),
+            Some(digest(Algorithm::Sha512, &sha512))
+        );
+
+        assert_eq!(Digest::parse(&format!("sha512:{SHA256}")), None);
+        assert_eq!(Digest::parse(&format!("md5:{SHA256}")), None);
+        assert_eq!(Digest::parse(&format!("blake2b:{SHA256}")), None);
+        assert_eq!(Digest::parse(&format!(":{SHA256}")), None);
+    }
+
+    #[test]
+    fn rejects_unknown_lengths_and_non_hex() {
+        assert_eq!(Digest::parse(""), None);
+        assert_eq!(Digest::parse("abc123"), None);
+        assert_eq!(Digest::parse(&SHA256[1..]), None);
+        assert_eq!(Digest::parse(&format!("{SHA256}00")), None);
+        assert_eq!(Digest::parse(&SHA256.replace('f', "g")), None);
+    }
+
+    #[test]
+    fn finds_digests_in_gnu_sums_files() {
+        let contents = format!(
+            "# SHA256SUMS\n{}  other.iso\n{SHA256}  image.iso\n{}  image.iso.sig\n",
+            "0".repeat(64),
+            "1".repeat(64),
+        );
+
+        assert_eq!(
+            Digest::from_sums_file(&contents, "image.iso"),
+            Some(digest(Algorithm::Sha256, SHA256))
+        );
+        assert_eq!(Digest::from_sums_file(&contents, "missing.iso"), None);
+    }
+
+    #[test]
+    fn finds_digests_marked_binary_or_in_directories() {
+        let contents = format!("{SHA256} *images/image.iso\n");
+
+        assert_eq!(
+            Digest::from_sums_file(&contents, "image.iso"),
+            Some(digest(Algorithm::Sha256, SHA256))
+        );
+    }
+
+    #[test]
+    fn finds_digests_in_bsd_sums_files() {
+        let contents = format!(
+            "SHA256 (other.iso) = {}\nSHA256 (image.iso) = {SHA256}\n",
+            "0".repeat(64)
+        );
+
+        assert_eq!(
+            Digest::from_sums_file(&contents, "image.iso"),
+            Some(digest(Algorithm::Sha256, SHA256))
+        );
+    }
+
+    #[test]
+    fn accepts_files_with_a_single_bare_digest() {
+        assert_eq!(
+            Digest::from_sums_file(&format!("{SHA256}\n"), "image.iso"),
+            Some(digest(Algorithm::Sha256, SHA256))
+        );
+    }
+
+    #[test]
+    fn skips_digests_of_unknown_lengths() {
+        assert_eq!(
+            Digest::from_sums_file("abcdef  image.iso\n", "image.iso"),
+            None
+        );
+        assert_eq!(Digest::from_sums_file("abcdef\n", "image.iso"), None);
+    }
+}
@@ -0,0 +1,468 @@
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use reqwest::StatusCode;
+use reqwest::header::{CONTENT_RANGE, ETAG, HeaderMap, IF_RANGE, LAST_MODIFIED, RANGE};
+use terrors::OneOf;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+#[derive(thiserror::Error, Debug)]
+#[error("The image changed on the server during the download")]
+pub struct ImageChangedOnServer;
+
+/// How a download to a file begins, depending on what an earlier download
+/// of it left behind.
+pub enum Start {
+    /// The file was downloaded completely before and is `len` bytes long.
+    Complete { len: u64 },
+    /// `response` holds what comes after the first `offset` bytes that are
+    /// in the file already, or all of it if `offset` is 0.
+    Response {
+        response: reqwest::Response,
+        offset: u64,
+    },
+}
+
+impl Start {
+    /// Size of the whole file, if the server told it.
+    pub fn total_size(&self) -> Option<u64> {
+        match self {
+            Self::Complete { len } => Some(*len),
+            Self::Response {
+                response,
+                offset: 0,
+            } => response.content_length(),
+            Self::Response { response, offset } => content_range(response.headers())
+                .and_then(|(_, length)| length)
+                .or_else(|| response.content_length().map(|len| offset + len)),
+        }
+    }
+}
+
+/// Requests `url`, asking only for what is missing from `path` if an earlier
+/// download to it was interrupted and the file didn't change on the server.
+pub async fn start(
+    client: &reqwest::Client,
+    url: &url::Url,
+    path: &Path,
+) -> reqwest::Result<Start> {
+    let resume_path = resume_info_path(path);
+
+    let partial_len = tokio::fs::metadata(path)
+        .await
+        .map_or(0, |metadata| metadata.len());
+    let mut resume = match tokio::fs::read_to_string(&resume_path).await {
+        Ok(validator) if partial_len > 0 => Some((partial_len, validator)),
+        _ => None,
+    };
+
+    loop {
+        let mut request = client.get(url.to_owned());
+        if let Some((offset, validator)) = &resume {
+            info!("Resuming download of {url} from byte {offset}");
+            request = request
+                .header(RANGE, format!("bytes={offset}-"))
+                .header(IF_RANGE, validator.trim());
+        }
+
+        let response = request.send().await?;
+
+        let Some((offset, _)) = &resume else {
+            return Ok(Start::Response {
+                response,
+                offset: 0,
+            });
+        };
+
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT
+                if content_range(response.headers()).map(|(start, _)| start) == Some(*offset) =>
+            {
+                return Ok(Start::Response {
+                    response,
+                    offset: *offset,
+                });
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE
+                if content_range(response.headers()).and_then(|(_, length)| length)
+                    == Some(*offset) =>
+            {
+                info!("{} is already downloaded", path.display());
+                tokio::fs::remove_file(&resume_path).await.ok();
+                return Ok(Start::Complete { len: *offset });
+            }
+            // The partial file can't be continued from this response, start over
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+                resume = None;
+            }
+            // Either ranges aren't supported or the file changed on the server
+            _ => {
+                return Ok(Start::Response {
+                    response,
+                    offset: 0,
+                });
+            }
+        }
+    }
+}
+
+/// Opens `path` to write a response with `headers` to, after the first
+/// `offset` bytes, and keeps what is needed to resume the download later.
+pub async fn open_file(path: &Path, offset: u64, headers: &HeaderMap) -> std::io::Result<File> {
+    if offset > 0 {
+        return tokio::fs::OpenOptions::new().append(true).open(path).await;
+    }
+
+    let file = File::create(path).await?;
+
+    let resume_path = resume_info_path(path);
+    if let Some(validator) = range_validator(headers) {
+        tokio::fs::write(&resume_path, validator).await?;
+    } else {
+        tokio::fs::remove_file(&resume_path).await.ok();
+    }
+
+    Ok(file)
+}
+
+/// Forgets how to resume the download to `path`, once it is complete.
+pub async fn finish(path: &Path) {
+    tokio::fs::remove_file(resume_info_path(path)).await.ok();
+}
+
+/// Downloads `url` to `path`, resuming an earlier download that was interrupted.
+///
+/// `on_progress` is called with the number of bytes downloaded so far and the
+/// size of the file if known; returning [`ControlFlow::Break`] stops the download.
+pub async fn to_file<F: FnMut(u64, Option<u64>) -> ControlFlow<()>>(
+    client: &reqwest::Client,
+    url: &url::Url,
+    path: &Path,
+    mut on_progress: F,
+) -> Result<ControlFlow<()>, OneOf<(std::io::Error, reqwest::Error)>> {
+    let start = start(client, url, path).await.map_err(OneOf::new)?;
+    let total_size = start.total_size();
+
+    let (response, mut downloaded) = match start {
+        Start::Complete { .. } => return Ok(ControlFlow::Continue(())),
+        Start::Response { response, offset } => {
+            (response.error_for_status().map_err(OneOf::new)?, offset)
+        }
+    };
+
+    let mut file = open_file(path, downloaded, response.headers())
+        .await
+        .map_err(OneOf::new)?;
+
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+        let chunk = chunk.map_err(OneOf::new)?;
+
+        file.write_all(&chunk).await.map_err(OneOf::new)?;
+        downloaded += chunk.len() as u64;
+
+        if on_progress(downloaded, total_size).is_break() {
+            return Ok(ControlFlow::Break(()));
+        }
+    }
+
+    file.flush().await.map_err(OneOf::new)?;
+
+    file.sync_all().await.map_err(OneOf::new)?;
+
+    // Keep what is needed to resume, as the download did not finish
+    if total_size.is_some_and(|total_size| downloaded < total_size) {
+        return Err(OneOf::new(ended_early()));
+    }
+
+    finish(path).await;
+
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Requests the part of `url` after the first `offset` bytes, as long as
+/// `validator` shows that the file on the server is still the same.
+pub async fn request_remainder(
+    client: &reqwest::Client,
+    url: &url::Url,
+    offset: u64,
+    validator: Option<&str>,
+) -> Result<reqwest::Response, OneOf<(ImageChangedOnServer, reqwest::Error)>> {
+    let validator = validator.ok_or_else(|| OneOf::new(ImageChangedOnServer))?;
+
+    info!("Continuing download of {url} from byte {offset}");
+
+    let response = client
+        .get(url.to_owned())
+        .header(RANGE, format!("bytes={offset}-"))
+        .header(IF_RANGE, validator)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(OneOf::new)?;
+
+    if response.status() == StatusCode::PARTIAL_CONTENT
+        && content_range(response.headers()).map(|(start, _)| start) == Some(offset)
+    {
+        Ok(response)
+    } else {
+        Err(OneOf::new(ImageChangedOnServer))
+    }
+}
+
+pub fn ended_early() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "The download ended before the whole image was received",
+    )
+}
+
+/// Where the validator of a partially downloaded file is kept, next to it.
+fn resume_info_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".resume");
+    PathBuf::from(name)
+}
+
+/// A validator that can be sent as `If-Range`: a strong `ETag`, or else `Last-Modified`.
+pub fn range_validator(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Parses `Content-Range: bytes <start>-<end>/<length>` into the start and length.
+///
+/// For unsatisfiable ranges (`bytes */<length>`) the start is reported as 0.
+fn content_range(headers: &HeaderMap) -> Option<(u64, Option<u64>)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, length) = value.strip_prefix("bytes ")?.split_once('/')?;
+
+    let start = match range.split_once('-') {
+        Some((start, _)) => start.parse().ok()?,
+        None if range == "*" => 0,
+        None => return None,
+    };
+
+    Some((start, length.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    const IMAGE: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// Serves `IMAGE` over HTTP with `etag`, answering range requests only
+    /// if `ranges` is set, and records the headers of every request.
+    fn serve(etag: &'static str, ranges: bool) -> (url::Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to listen");
+        let url = url::Url::parse(&format!(
+            "http://{}/image.img",
+            listener.local_addr().expect("No local address")
+        ))
+        .expect("Invalid URL");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let log = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("Failed to accept");
+
+                let mut headers = String::new();
+                let mut reader = BufReader::new(&stream);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).expect("Failed to read request");
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    headers.push_str(&line.to_ascii_lowercase());
+                }
+
+                let header = |name: &str| {
+                    headers.lines().find_map(|line| {
+                        line.strip_prefix(name)
+                            .and_then(|value| value.strip_prefix(':'))
+                            .map(|value| value.trim().to_owned())
+                    })
+                };
+                let offset = header("range")
+                    .filter(|_| ranges)
+                    .filter(|_| header("if-range").is_none_or(|validator| validator == etag))
+                    .and_then(|range| {
+                        range
+                            .strip_prefix("bytes=")?
+                            .strip_suffix('-')?
+                            .parse()
+                            .ok()
+                    });
+
+                let (status, range, body) = match offset {
+                    Some(offset) if offset >= IMAGE.len() => (
+                        "416 Range Not Satisfiable",
+                        format!("Content-Range: bytes */{}\r\n", IMAGE.len()),
+                        &[][..],
+                    ),
+                    Some(offset) => (
+                        "206 Partial Content",
+                        format!(
+                            "Content-Range: bytes {offset}-{}/{}\r\n",
+                            IMAGE.len() - 1,
+                            IMAGE.len()
+                        ),
+                        &IMAGE[offset..],
+                    ),
+                    None => ("200 OK", String::new(), IMAGE),
+                };
+
+                log.lock().expect("Poisoned").push(headers);
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nETag: {etag}\r\n{range}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .and_then(|()| stream.write_all(body))
+                .expect("Failed to respond");
+            }
+        });
+
+        (url, requests)
+    }
+
+    /// Leaves behind what an interrupted download to a new file would have.
+    fn partial_download(name: &str, contents: &[u8], validator: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("impression-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).expect("Failed to write partial file");
+        std::fs::write(resume_info_path(&path), validator).expect("Failed to write validator");
+        path
+    }
+
+    fn download(url: &url::Url, path: &Path) -> Vec<(u64, Option<u64>)> {
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .expect("Failed to build client");
+        let mut progress = Vec::new();
+
+        let flow = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build runtime")
+            .block_on(to_file(&client, url, path, |downloaded, total| {
+                progress.push((downloaded, total));
+                ControlFlow::Continue(())
+            }))
+            .expect("Download failed");
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(
+            std::fs::read(path).expect("Downloaded file is missing"),
+            IMAGE
+        );
+        assert!(!resume_info_path(path).exists());
+        std::fs::remove_file(path).ok();
+
+        progress
+    }
+
+    #[test]
+    fn resumes_from_the_end_of_the_partial_file() {
+        let (url, requests) = serve("\"v1\"", true);
+        let path = partial_download("resume", &IMAGE[..10], "\"v1\"");
+
+        let progress = download(&url, &path);
+
+        let requests = requests.lock().expect("Poisoned").clone();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].contains("range: bytes=10-"));
+        assert!(requests[0].contains("if-range: \"v1\""));
+        assert_eq!(
+            progress.last(),
+            Some(&(IMAGE.len() as u64, Some(IMAGE.len() as u64)))
+        );
+    }
+
+    #[test]
+    fn starts_over_if_ranges_are_ignored() {
+        let (url, _) = serve("\"v1\"", false);
+        let path = partial_download("ignored", b"XXXXXXXXXX", "\"v1\"");
+
+        download(&url, &path);
+    }
+
+    #[test]
+    fn starts_over_if_the_file_changed_on_the_server() {
+        let (url, requests) = serve("\"v2\"", true);
+        let path = partial_download("changed", b"XXXXXXXXXX", "\"v1\"");
+
+        download(&url, &path);
+
+        assert!(requests.lock().expect("Poisoned")[0].contains("if-range: \"v1\""));
+    }
+
+    #[test]
+    fn finishes_downloads_that_were_complete() {
+        let (url, _) = serve("\"v1\"", true);
+        let path = partial_download("complete", IMAGE, "\"v1\"");
+
+        assert_eq!(download(&url, &path), []);
+    }
+
+    #[test]
+    fn keeps_resume_info_next_to_the_file() {
+        assert_eq!(
+            resume_info_path(Path::new("/tmp/image.iso")),
+            Path::new("/tmp/image.iso.resume")
+        );
+    }
+
+    #[test]
+    fn prefers_strong_etags_as_validators() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(range_validator(&headers), None);
+
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        headers.insert(ETAG, HeaderValue::from_static("W/\"weak\""));
+        assert_eq!(
+            range_validator(&headers).as_deref(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+
+        headers.insert(ETAG, HeaderValue::from_static("\"strong\""));
+        assert_eq!(range_validator(&headers).as_deref(), Some("\"strong\""));
+    }
+
+    #[test]
+    fn parses_content_ranges() {
+        let parse = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_RANGE, HeaderValue::from_static(value));
+            content_range(&headers)
+        };
+
+        assert_eq!(parse("bytes 10-35/36"), Some((10, Some(36))));
+        assert_eq!(parse("bytes 10-35/*"), Some((10, None)));
+        assert_eq!(parse("bytes */36"), Some((0, Some(36))));
+        assert_eq!(parse("bytes 10/36"), None);
+        assert_eq!(parse("items 10-35/36"), None);
+        assert_eq!(parse("bytes x-35/36"), None);
+        assert_eq!(content_range(&HeaderMap::new()), None);
+    }
+}
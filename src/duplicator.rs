@@ -0,0 +1,225 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::StreamExt;
+use log::{error, info, warn};
+use tokio::sync::mpsc;
+
+use crate::flash::{Destination, FlashOptions, FlashPhase, FlashRequest, FlashStatus, Progress};
+use crate::get_size_string;
+use crate::widgets::device_list;
+use crate::window::DiskImage;
+
+const BLOCK_INTERFACE: &str = "org.freedesktop.UDisks2.Block";
+const PARTITION_INTERFACE: &str = "org.freedesktop.UDisks2.Partition";
+
+/// How often the duplicator checks whether it was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a new drive is left alone, so that probing and automounting
+/// are done before it is unmounted again for writing.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// What a drive reports about itself, which the allowlist is matched against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriveModel {
+    pub vendor: String,
+    pub model: String,
+    pub size: u64,
+}
+
+impl DriveModel {
+    /// Reads the model of the drive behind `object`, if it is a removable drive.
+    pub async fn of(
+        client: &udisks::Client,
+        object: &udisks::Object,
+    ) -> udisks::Result<Option<Self>> {
+        let block = object.block().await?;
+        let Ok(drive) = client.drive_for_block(&block).await else {
+            return Ok(None);
+        };
+
+        if !drive.removable().await.unwrap_or(true) {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            vendor: drive.vendor().await?.trim().to_owned(),
+            model: drive.model().await?.trim().to_owned(),
+            size: block.size().await?,
+        }))
+    }
+
+    /// Parses the value of the `duplicator-allowlist` setting.
+    pub fn from_setting(value: Vec<(String, String, u64)>) -> Vec<Self> {
+        value
+            .into_iter()
+            .map(|(vendor, model, size)| Self {
+                vendor,
+                model,
+                size,
+            })
+            .collect()
+    }
+
+    pub fn to_setting(allowlist: &[Self]) -> Vec<(String, String, u64)> {
+        allowlist
+            .iter()
+            .map(|x| (x.vendor.clone(), x.model.clone(), x.size))
+            .collect()
+    }
+
+    pub fn name(&self) -> String {
+        format!("{} {}", self.vendor, self.model).trim().to_owned()
+    }
+
+    pub fn size_string(&self) -> String {
+        get_size_string(self.size)
+    }
+}
+
+/// A drive that was plugged in while the duplicator was running.
+pub struct NewDrive {
+    pub model: DriveModel,
+    /// The device file of the drive, to tell drives of the same model apart.
+    pub device: String,
+    /// How writing to the drive goes, or `None` if it is not on the allowlist.
+    pub destination: Option<Destination>,
+}
+
+/// Writes an image to every drive on the allowlist that is plugged in,
+/// until it is stopped.
+pub struct Duplicator {
+    source: DiskImage,
+    allowlist: Vec<DriveModel>,
+    options: FlashOptions,
+    is_running: Arc<AtomicBool>,
+    sender: mpsc::UnboundedSender<NewDrive>,
+}
+
+impl Duplicator {
+    pub const fn new(
+        source: DiskImage,
+        allowlist: Vec<DriveModel>,
+        options: FlashOptions,
+        is_running: Arc<AtomicBool>,
+        sender: mpsc::UnboundedSender<NewDrive>,
+    ) -> Self {
+        Self {
+            source,
+            allowlist,
+            options,
+            is_running,
+            sender,
+        }
+    }
+
+    pub async fn perform(self) {
+        if let Err(e) = self.perform_job().await {
+            error!("Duplicator failed: {e}");
+            self.is_running.store(false, Ordering::SeqCst);
+        }
+    }
+
+    async fn perform_job(&self) -> udisks::Result<()> {
+        info!("Duplicating {:?} onto {:?}", self.source, self.allowlist);
+
+        let client = udisks::Client::new().await?;
+        let mut added = client.object_manager().receive_interfaces_added().await?;
+
+        while self.is_running.load(Ordering::SeqCst) {
+            let signal = match tokio::time::timeout(POLL_INTERVAL, added.next()).await {
+                Ok(Some(signal)) => signal,
+                Ok(None) => break,
+                Err(_) => continue,
+            };
+
+            let Ok(args) = signal.args() else {
+                continue;
+            };
+
+            // Whole drives show up as block devices that are not partitions
+            let interfaces = args.interfaces_and_properties();
+            if !interfaces.keys().any(|x| x.as_str() == BLOCK_INTERFACE)
+                || interfaces.keys().any(|x| x.as_str() == PARTITION_INTERFACE)
+            {
+                continue;
+            }
+
+            let Ok(object) = client.object(args.object_path().to_owned());
+
+            match self.new_drive(&client, object).await {
+                Ok(Some(new_drive)) => {
+                    if self.sender.send(new_drive).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to look at a new block device: {e}"),
+            }
+        }
+
+        info!("Duplicator stopped");
+
+        Ok(())
+    }
+
+    /// Starts writing to `object` if it is a drive on the allowlist.
+    async fn new_drive(
+        &self,
+        client: &udisks::Client,
+        object: udisks::Object,
+    ) -> udisks::Result<Option<NewDrive>> {
+        let Some(model) = DriveModel::of(client, &object).await? else {
+            return Ok(None);
+        };
+
+        // Card readers without a card in them
+        if model.size == 0 {
+            return Ok(None);
+        }
+
+        let device = device_list::preferred_device_display_string(&object)
+            .await
+            .unwrap_or_default();
+
+        if !self.allowlist.contains(&model) {
+            info!("Leaving {device} alone, {model:?} is not on the allowlist");
+            return Ok(Some(NewDrive {
+                model,
+                device,
+                destination: None,
+            }));
+        }
+
+        info!("Writing to {device} ({model:?})");
+
+        let destination = Destination::new(
+            object,
+            FlashStatus::Active(FlashPhase::Copy, Progress::Pulse),
+        );
+
+        let flash_job = FlashRequest::new(
+            self.source.clone(),
+            vec![destination.clone()],
+            Arc::new(Mutex::new(FlashStatus::Active(
+                FlashPhase::Copy,
+                Progress::Pulse,
+            ))),
+            self.is_running.clone(),
+            self.options,
+        );
+
+        tokio::spawn(async move {
+            tokio::time::sleep(SETTLE_TIME).await;
+            flash_job.perform().await;
+        });
+
+        Ok(Some(NewDrive {
+            model,
+            device,
+            destination: Some(destination),
+        }))
+    }
+}
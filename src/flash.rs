@@ -1,19 +1,56 @@
-use log::{error, info};
+use log::{error, info, warn};
+use reqwest::StatusCode;
 use std::collections::HashMap;
-use std::process::Stdio;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::time::Duration;
-use terrors::OneOf;
+use terrors::{E3, E4, OneOf};
 use tokio::time::Instant;
-use tokio::{fs::File, io::AsyncWriteExt};
-
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::bmap::Bmap;
+use crate::capacity::{self, ImageTooLarge};
+use crate::checksum::{self, Algorithm, Digest, FileDigest};
+use crate::download::{self, ImageChangedOnServer, Start};
+use crate::probe;
+use crate::report::{self, Verification};
+use crate::source::{DownloadSink, ImageStream};
+use crate::sparse::{self, InvalidSparseImage, SparseExpander};
 use crate::window::{Compression, DiskImage};
+use crate::writer::{self, FanOut, ZeroBlocks};
 
 #[derive(Clone, Debug)]
 pub enum FlashPhase {
-    Download,
+    Checksum,
+    /// Downloading an image of `total` bytes, if the server told its size.
+    Download {
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// Writing an image while it is still downloading.
+    DownloadAndCopy {
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// Waiting to try a failed download again.
+    Retry {
+        attempt: u32,
+        attempts: u32,
+    },
     Copy,
+    Verify,
+    /// Reading a drive into an image file.
+    Backup,
+    /// Reading a compressed image to the end before anything is erased.
+    Test,
+    /// Creating a new partition table and file system on a drive.
+    Format,
+    /// Overwriting everything on a drive.
+    Wipe,
 }
 
 #[derive(Clone, Debug)]
@@ -40,39 +77,187 @@ pub enum FlashStatus {
     Done(Option<String>),
 }
 
+/// User preferences that change how an image is written.
+#[derive(Clone, Copy, Debug, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct FlashOptions {
+    /// Read the drive back after writing and compare it with the image.
+    pub verify: bool,
+    /// Write online images to the drive as they are downloaded.
+    pub write_while_downloading: bool,
+    /// How many times a download is tried before giving up.
+    pub download_attempts: u32,
+    /// Zero out the drive first and skip writing blocks of zeroes.
+    pub skip_zero_blocks: bool,
+    /// Decompress local images once before erasing, to find damage early.
+    pub test_image: bool,
+}
+
+/// A drive that an image is written to, with how writing to it goes.
+#[derive(Clone, Debug)]
+pub struct Destination {
+    pub object: udisks::Object,
+    pub status: Arc<std::sync::Mutex<FlashStatus>>,
+}
+
+impl Destination {
+    pub fn new(object: udisks::Object, status: FlashStatus) -> Self {
+        Self {
+            object,
+            status: Arc::new(std::sync::Mutex::new(status)),
+        }
+    }
+
+    pub fn status(&self) -> Option<FlashStatus> {
+        self.status.lock().ok().map(|status| status.clone())
+    }
+
+    fn set_status(&self, status: FlashStatus) {
+        if let Ok(mut lock) = self.status.lock() {
+            *lock = status;
+        }
+    }
+
+    /// The error that writing to this drive ended with, if it failed.
+    fn error(&self) -> Option<String> {
+        match self.status() {
+            Some(FlashStatus::Done(error)) => error,
+            _ => None,
+        }
+    }
+}
+
+/// A destination drive that is open for writing.
+struct OpenDestination<'a> {
+    destination: &'a Destination,
+    block: udisks::block::BlockProxy<'static>,
+    drive: udisks::drive::DriveProxy<'static>,
+    file: File,
+    zero_blocks: ZeroBlocks,
+    report: report::Entry,
+}
+
 pub struct FlashRequest {
     source: DiskImage,
-    destination: udisks::Object,
+    destinations: Vec<Destination>,
     status: std::sync::Arc<std::sync::Mutex<FlashStatus>>,
     is_running: Arc<AtomicBool>,
+    options: FlashOptions,
 }
 
 #[derive(thiserror::Error, Debug)]
 #[error("Process was stopped by the user")]
-struct ProcessStoppedByUser;
+pub struct ProcessStoppedByUser;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to read image: {0}")]
+pub struct ImageReadFailed(std::io::Error);
+
+#[derive(thiserror::Error, Debug)]
+#[error("The image is damaged, no drive was changed: {0}")]
+struct ImageDamaged(std::io::Error);
 
 #[derive(thiserror::Error, Debug)]
-#[error("Total size could not be determined")]
-struct TotalSizeCouldNotBeDetermined;
+#[error(
+    "Verification failed: drive content differs from the image in the {block_len} bytes at offset {block_offset}"
+)]
+pub struct VerificationFailed {
+    /// Start of the checksummed block that differs, not of the first byte that does.
+    block_offset: u64,
+    block_len: usize,
+}
 
 #[derive(thiserror::Error, Debug)]
-#[error("XZ extraction failed: {details:?}")]
-struct XzExtractionFailed {
-    details: Option<String>,
+#[error("Checksum mismatch: expected {expected}, but the image has {computed}")]
+struct ChecksumMismatch {
+    expected: Digest,
+    computed: Digest,
+}
+
+/// Size of the blocks whose checksums are compared when verifying a write.
+const VERIFY_BLOCK_SIZE: usize = 64 * 1024;
+
+/// CRC32 checksum of a stretch of data written to a drive.
+#[derive(Debug)]
+struct WrittenBlock {
+    offset: u64,
+    len: usize,
+    checksum: u32,
+}
+
+/// CRC32 checksums of the data written to a drive, in blocks of at most
+/// [`VERIFY_BLOCK_SIZE`] bytes, leaving out the parts that were skipped.
+#[derive(Debug, Default)]
+pub struct BlockChecksums {
+    blocks: Vec<WrittenBlock>,
+    current: crc32fast::Hasher,
+    current_offset: u64,
+    current_len: usize,
+    total: u64,
+}
+
+impl BlockChecksums {
+    /// Records `data` as written right after the previously recorded data.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+
+        while !data.is_empty() {
+            let taken = std::cmp::min(VERIFY_BLOCK_SIZE - self.current_len, data.len());
+            self.current.update(&data[..taken]);
+            self.current_len += taken;
+            data = &data[taken..];
+
+            if self.current_len == VERIFY_BLOCK_SIZE {
+                self.finish_block();
+            }
+        }
+    }
+
+    /// Continues recording at `offset`, for data that is not written sequentially.
+    pub fn seek(&mut self, offset: u64) {
+        if offset != self.current_offset + self.current_len as u64 {
+            self.finish_block();
+            self.current_offset = offset;
+        }
+    }
+
+    fn finish_block(&mut self) {
+        if self.current_len > 0 {
+            self.blocks.push(WrittenBlock {
+                offset: self.current_offset,
+                len: self.current_len,
+                checksum: std::mem::take(&mut self.current).finalize(),
+            });
+            self.current_offset += self.current_len as u64;
+            self.current_len = 0;
+        }
+    }
+
+    #[must_use]
+    pub fn finish(mut self) -> Self {
+        self.finish_block();
+        self
+    }
+
+    pub const fn total(&self) -> u64 {
+        self.total
+    }
 }
 
 impl FlashRequest {
     pub const fn new(
         source: DiskImage,
-        destination: udisks::Object,
+        destinations: Vec<Destination>,
         status: std::sync::Arc<std::sync::Mutex<FlashStatus>>,
         is_running: Arc<AtomicBool>,
+        options: FlashOptions,
     ) -> Self {
         Self {
             source,
-            destination,
+            destinations,
             status,
             is_running,
+            options,
         }
     }
 
@@ -82,99 +267,460 @@ impl FlashRequest {
         }
     }
 
+    async fn verify_checksum(
+        &self,
+        path: &std::path::Path,
+        expected: &Digest,
+        computed: Option<&FileDigest>,
+    ) -> Result<Digest, OneOf<(ProcessStoppedByUser, ChecksumMismatch, std::io::Error)>> {
+        let computed = match computed {
+            // Computed already while the image was being picked
+            Some(computed)
+                if computed.digest.algorithm == expected.algorithm && computed.is_current(path) =>
+            {
+                info!(
+                    "Using the {} checksum of {} computed earlier",
+                    expected.algorithm.name(),
+                    path.display()
+                );
+                computed.digest.clone()
+            }
+            _ => self
+                .compute_checksum(path, expected.algorithm)
+                .await
+                .map_err(OneOf::broaden)?,
+        };
+
+        if computed == *expected {
+            Ok(computed)
+        } else {
+            Err(OneOf::new(ChecksumMismatch {
+                expected: expected.clone(),
+                computed,
+            }))
+        }
+    }
+
+    async fn compute_checksum(
+        &self,
+        path: &std::path::Path,
+        algorithm: Algorithm,
+    ) -> Result<Digest, OneOf<(ProcessStoppedByUser, std::io::Error)>> {
+        info!(
+            "Computing {} checksum of {}",
+            algorithm.name(),
+            path.display()
+        );
+
+        self.set_status(FlashStatus::Active(
+            FlashPhase::Checksum,
+            Progress::Fraction(0.0),
+        ));
+
+        let mut last_sent = Instant::now();
+
+        checksum::compute_file(path, algorithm, |hashed, size| {
+            if last_sent.elapsed() >= Duration::from_millis(250) {
+                self.set_status(FlashStatus::Active(
+                    FlashPhase::Checksum,
+                    Progress::from((hashed, size)),
+                ));
+                last_sent = Instant::now();
+            }
+
+            if self.stopped_running().is_ok() {
+                std::ops::ControlFlow::Continue(())
+            } else {
+                std::ops::ControlFlow::Break(())
+            }
+        })
+        .await
+        .map_err(OneOf::new)?
+        .ok_or_else(|| OneOf::new(ProcessStoppedByUser))
+    }
+
+    /// Waits before the next download attempt, showing that on the flashing page.
+    async fn wait_to_retry(
+        &self,
+        attempt: u32,
+        error: &(dyn std::fmt::Display + Sync),
+    ) -> Result<(), OneOf<(ProcessStoppedByUser,)>> {
+        let delay = retry_delay(attempt);
+        warn!("Download attempt {attempt} failed, retrying in {delay:?}: {error}");
+
+        self.set_status(FlashStatus::Active(
+            FlashPhase::Retry {
+                attempt: attempt + 1,
+                attempts: self.options.download_attempts,
+            },
+            Progress::Pulse,
+        ));
+
+        let retry_at = Instant::now() + delay;
+        while Instant::now() < retry_at {
+            self.stopped_running()?;
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+
+        self.stopped_running()
+    }
+
+    /// Downloads `url` to `downloading_path`, trying again on transient failures.
     async fn download_file(
         &self,
-        downloading_path: &std::path::PathBuf,
+        downloading_path: &Path,
+        url: &url::Url,
+    ) -> Result<(), OneOf<(ProcessStoppedByUser, std::io::Error, reqwest::Error)>> {
+        let mut attempt = 1;
+
+        loop {
+            match self.try_download_file(downloading_path, url).await {
+                Err(e)
+                    if attempt < self.options.download_attempts
+                        && match e.as_enum() {
+                            E3::B(e) => is_transient_io_error(e),
+                            E3::C(e) => is_transient_request_error(e),
+                            E3::A(_) => false,
+                        } =>
+                {
+                    self.wait_to_retry(attempt, &e)
+                        .await
+                        .map_err(OneOf::broaden)?;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Downloads `url` to `downloading_path`, resuming a previously interrupted
+    /// download of the same file if the server allows it.
+    async fn try_download_file(
+        &self,
+        downloading_path: &Path,
+        url: &url::Url,
+    ) -> Result<(), OneOf<(ProcessStoppedByUser, std::io::Error, reqwest::Error)>> {
+        let mut last_sent = Instant::now();
+
+        let flow = download::to_file(
+            &reqwest::Client::new(),
+            url,
+            downloading_path,
+            |downloaded, total| {
+                if last_sent.elapsed() >= Duration::from_millis(250) {
+                    self.set_status(FlashStatus::Active(
+                        FlashPhase::Download { downloaded, total },
+                        Progress::from((downloaded, total.unwrap_or_default())),
+                    ));
+
+                    last_sent = Instant::now();
+                }
+
+                if self.stopped_running().is_ok() {
+                    std::ops::ControlFlow::Continue(())
+                } else {
+                    std::ops::ControlFlow::Break(())
+                }
+            },
+        )
+        .await
+        .map_err(OneOf::broaden)?;
+
+        if flow.is_break() {
+            return Err(OneOf::new(ProcessStoppedByUser));
+        }
+
+        Ok(())
+    }
+
+    /// Feeds the download that `start` begins into `sink`, continuing where it
+    /// stopped after transient failures, and also saves it to `save_path` if given.
+    ///
+    /// What an earlier download to `save_path` left behind is fed in first.
+    async fn stream_download(
+        &self,
         url: &url::Url,
+        start: Start,
+        save_path: Option<&std::path::Path>,
+        sink: DownloadSink,
     ) -> Result<
-        File,
+        (),
         OneOf<(
             ProcessStoppedByUser,
-            TotalSizeCouldNotBeDetermined,
             std::io::Error,
             reqwest::Error,
+            ImageChangedOnServer,
         )>,
     > {
-        let mut file = File::create(downloading_path).await.map_err(OneOf::new)?;
+        let total_size = start.total_size();
+        let (mut response, offset) = match start {
+            Start::Complete { len } => (None, len),
+            Start::Response { response, offset } => (Some(response), offset),
+        };
 
-        let res = reqwest::get(url.to_owned()).await.map_err(OneOf::new)?;
+        let mut file = match (save_path, &response) {
+            (Some(path), Some(response)) => Some(
+                download::open_file(path, offset, response.headers())
+                    .await
+                    .map_err(OneOf::new)?,
+            ),
+            _ => None,
+        };
 
-        let total_size = res
-            .content_length()
-            .ok_or_else(|| OneOf::new(TotalSizeCouldNotBeDetermined))?;
-        let mut downloaded: u64 = 0;
-        let mut stream = res.bytes_stream();
+        let validator = response
+            .as_ref()
+            .and_then(|response| download::range_validator(response.headers()));
+        let client = reqwest::Client::new();
 
-        let mut last_sent = Instant::now();
+        let mut sent = 0;
+        let mut attempt = 1;
 
-        while let Some(Ok(chunk)) = futures::StreamExt::next(&mut stream).await {
-            tokio::io::AsyncWriteExt::write_all(&mut file, &chunk)
+        if let Some(path) = save_path.filter(|_| offset > 0)
+            && !self
+                .feed_saved(path, offset, &sink, &mut sent)
                 .await
-                .map_err(OneOf::new)?;
-            downloaded = std::cmp::min(downloaded + (chunk.len() as u64), total_size);
+                .map_err(OneOf::broaden)?
+        {
+            return Ok(());
+        }
 
-            if last_sent.elapsed() >= Duration::from_millis(250) {
-                self.set_status(FlashStatus::Active(
-                    FlashPhase::Download,
-                    Progress::from((downloaded, total_size)),
-                ));
+        loop {
+            let response = match response.take() {
+                Some(response) => Ok(response),
+                // All of it was downloaded before
+                None if total_size.is_some_and(|total_size| sent >= total_size) => break,
+                None => download::request_remainder(&client, url, sent, validator.as_deref()).await,
+            };
+
+            let result: Result<_, OneOf<(_, _, _, ImageChangedOnServer)>> = match response {
+                Ok(response) => self
+                    .feed_sink(response, &sink, file.as_mut(), &mut sent)
+                    .await
+                    .map_err(OneOf::broaden),
+                Err(e) => Err(e.broaden()),
+            };
+
+            match result {
+                // The writer only hangs up on errors, which it reports itself
+                Ok(false) => return Ok(()),
+                Ok(true) if total_size.is_none_or(|total_size| sent >= total_size) => break,
+                Ok(true) => {
+                    let error = download::ended_early();
+                    if attempt >= self.options.download_attempts || validator.is_none() {
+                        return Err(OneOf::new(error));
+                    }
+                    self.wait_to_retry(attempt, &error)
+                        .await
+                        .map_err(OneOf::broaden)?;
+                }
+                Err(e)
+                    if attempt < self.options.download_attempts
+                        && validator.is_some()
+                        && match e.as_enum() {
+                            E4::B(e) => is_transient_io_error(e),
+                            E4::C(e) => is_transient_request_error(e),
+                            E4::A(_) | E4::D(_) => false,
+                        } =>
+                {
+                    self.wait_to_retry(attempt, &e)
+                        .await
+                        .map_err(OneOf::broaden)?;
+                }
+                Err(e) => return Err(e),
+            }
 
-                last_sent = Instant::now();
+            attempt += 1;
+        }
+
+        drop(sink);
+
+        if let Some(file) = &mut file {
+            file.flush().await.map_err(OneOf::new)?;
+            file.sync_all().await.map_err(OneOf::new)?;
+        }
+        if let Some(path) = save_path {
+            download::finish(path).await;
+        }
+
+        Ok(())
+    }
+
+    /// Hands the first `len` bytes saved at `path` to `sink`, returning `false`
+    /// if the writer hung up.
+    async fn feed_saved(
+        &self,
+        path: &Path,
+        len: u64,
+        sink: &DownloadSink,
+        sent: &mut u64,
+    ) -> Result<bool, OneOf<(ProcessStoppedByUser, std::io::Error)>> {
+        info!(
+            "Writing the {len} bytes of {} downloaded before",
+            path.display()
+        );
+
+        let mut file = File::open(path).await.map_err(OneOf::new)?.take(len);
+
+        loop {
+            let mut chunk = vec![0; 1024 * 1024];
+            let x = file.read(&mut chunk).await.map_err(OneOf::new)?;
+            if x == 0 {
+                break;
             }
+            chunk.truncate(x);
+
+            if !sink.send(chunk).await {
+                return Ok(false);
+            }
+            *sent += x as u64;
 
             self.stopped_running().map_err(OneOf::broaden)?;
         }
 
-        file.flush().await.map_err(OneOf::new)?;
+        if *sent < len {
+            return Err(OneOf::new(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The partly downloaded image got shorter",
+            )));
+        }
 
-        file.sync_all().await.map_err(OneOf::new)?;
+        Ok(true)
+    }
 
-        File::open(downloading_path).await.map_err(OneOf::new)
+    /// Hands the body of `response` to `sink`, returning `false` if the writer hung up.
+    async fn feed_sink(
+        &self,
+        response: reqwest::Response,
+        sink: &DownloadSink,
+        mut file: Option<&mut File>,
+        sent: &mut u64,
+    ) -> Result<bool, OneOf<(ProcessStoppedByUser, std::io::Error, reqwest::Error)>> {
+        let mut stream = response.bytes_stream();
+
+        while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+            let chunk = chunk.map_err(OneOf::new)?;
+
+            if let Some(file) = &mut file {
+                file.write_all(&chunk).await.map_err(OneOf::new)?;
+            }
+
+            if !sink.send(chunk.to_vec()).await {
+                return Ok(false);
+            }
+            *sent += chunk.len() as u64;
+
+            self.stopped_running().map_err(OneOf::broaden)?;
+        }
+
+        Ok(true)
     }
 
-    async fn extract_xz_image(
+    /// Writes the image at `url` to the drives behind `writer` while it is being downloaded.
+    async fn download_and_load_file(
         &self,
-        input_path: &std::path::Path,
-        output_path: &std::path::Path,
-    ) -> Result<File, OneOf<(XzExtractionFailed, std::io::Error)>> {
-        let output_file = File::create(&output_path).await.map_err(OneOf::new)?;
-
-        self.set_status(FlashStatus::Active(FlashPhase::Copy, Progress::Pulse));
-
-        let mut extract_process = tokio::process::Command::new("xzcat")
-            .arg(input_path)
-            .arg("-k")
-            .arg("-T0")
-            .stdout(Stdio::from(output_file.into_std().await))
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(OneOf::new)?;
+        url: &url::Url,
+        save_path: Option<&std::path::Path>,
+        writer: &mut FanOut,
+    ) -> Result<
+        (),
+        OneOf<(
+            ProcessStoppedByUser,
+            std::io::Error,
+            reqwest::Error,
+            ImageReadFailed,
+            ImageChangedOnServer,
+        )>,
+    > {
+        let client = reqwest::Client::new();
+        let mut attempt = 1;
+
+        let start = loop {
+            // Only downloads that are saved can be resumed
+            let start = match save_path {
+                Some(path) => download::start(&client, url, path).await,
+                None => client
+                    .get(url.to_owned())
+                    .send()
+                    .await
+                    .map(|response| Start::Response {
+                        response,
+                        offset: 0,
+                    }),
+            };
+
+            match start.and_then(|start| match start {
+                Start::Response { response, offset } => response
+                    .error_for_status()
+                    .map(|response| Start::Response { response, offset }),
+                start @ Start::Complete { .. } => Ok(start),
+            }) {
+                Err(e)
+                    if attempt < self.options.download_attempts
+                        && is_transient_request_error(&e) =>
+                {
+                    self.wait_to_retry(attempt, &e)
+                        .await
+                        .map_err(OneOf::broaden)?;
+                    attempt += 1;
+                }
+                result => break result.map_err(OneOf::new)?,
+            }
+        };
 
-        let stderr = extract_process.stderr.take();
-
-        match extract_process.wait().await.map_err(OneOf::new)? {
-            x if x.success() => Ok(File::open(&output_path).await.map_err(OneOf::new)?),
-            _ => Err(OneOf::new(XzExtractionFailed {
-                details: match stderr {
-                    Some(mut stderr) => {
-                        let mut err_output = String::new();
-                        tokio::io::AsyncReadExt::read_to_string(&mut stderr, &mut err_output)
-                            .await
-                            .ok();
-                        Some(err_output)
-                    }
-                    None => None,
-                },
-            })),
+        let total_size = start.total_size();
+
+        match total_size {
+            Some(total_size) => info!("Writing {url} while downloading ({total_size} bytes)"),
+            None => info!("Writing {url} while downloading (size unknown)"),
         }
+
+        let (image, sink) = ImageStream::from_download(total_size);
+
+        let download = async {
+            self.stream_download(url, start, save_path, sink)
+                .await
+                .map_err(OneOf::broaden)
+        };
+        let write = async {
+            Self::load_file(
+                image,
+                writer,
+                |status| self.set_status(status),
+                self.is_running.clone(),
+            )
+            .await
+            .map_err(OneOf::broaden)
+        };
+
+        futures::future::try_join(download, write).await?;
+
+        Ok(())
     }
 
     pub async fn perform(self) {
         match self.perform_job().await {
             Ok(()) => {
-                self.set_status(FlashStatus::Done(None));
+                // The job only fails as a whole if no drive was written
+                let errors = self
+                    .destinations
+                    .iter()
+                    .filter_map(Destination::error)
+                    .collect::<Vec<_>>();
+
+                match errors.first() {
+                    Some(error) if errors.len() == self.destinations.len() => {
+                        self.set_status(FlashStatus::Done(Some(error.clone())));
+                    }
+                    _ => self.set_status(FlashStatus::Done(None)),
+                }
             }
             Err(e) => {
+                for destination in &self.destinations {
+                    if !matches!(destination.status(), Some(FlashStatus::Done(_))) {
+                        destination.set_status(FlashStatus::Done(Some(e.to_string())));
+                    }
+                }
+
                 if let Err(e) = e.narrow::<ProcessStoppedByUser, _>() {
                     error!("Flashing process failed: {e}");
                     self.set_status(FlashStatus::Done(Some(e.to_string())));
@@ -191,69 +737,129 @@ impl FlashRequest {
         }
     }
 
-    async fn get_source_file_from_image(
+    /// Checks the image before any drive is erased, returning its digest if
+    /// one was computed.
+    async fn check_source(
         &self,
     ) -> Result<
-        File,
+        Option<Digest>,
         OneOf<(
             ProcessStoppedByUser,
             std::io::Error,
-            reqwest::Error,
-            XzExtractionFailed,
-            TotalSizeCouldNotBeDetermined,
+            ChecksumMismatch,
+            ImageDamaged,
         )>,
     > {
-        match &self.source {
-            DiskImage::Local { path, compression } => match compression {
-                Compression::Raw => Ok(File::open(path).await.map_err(OneOf::new)?),
-                Compression::Xz => {
-                    let temp_dir = glib::user_cache_dir();
-
-                    std::fs::create_dir_all(&temp_dir).map_err(OneOf::new)?;
-
-                    let result_path = temp_dir.join(
-                        path.file_name()
-                            .and_then(|x| x.to_str())
-                            .unwrap_or("disk_image.iso"),
-                    );
+        let DiskImage::Local {
+            path,
+            compression,
+            digest,
+            computed_digest,
+            ..
+        } = &self.source
+        else {
+            return Ok(None);
+        };
 
-                    self.set_status(FlashStatus::Active(FlashPhase::Copy, Progress::Pulse));
+        let computed = match digest {
+            Some(expected) => Some(
+                self.verify_checksum(path, expected, computed_digest.as_ref())
+                    .await
+                    .map_err(OneOf::broaden)?,
+            ),
+            None => computed_digest
+                .as_ref()
+                .filter(|computed| computed.is_current(path))
+                .map(|computed| computed.digest.clone()),
+        };
 
-                    Ok(self
-                        .extract_xz_image(path, &result_path)
-                        .await
-                        .map_err(OneOf::broaden)?)
-                }
-            },
-            DiskImage::Online {
-                url, download_path, ..
-            } => Ok(self
-                .download_file(download_path, url)
+        if self.options.test_image && !matches!(compression, Compression::Raw) {
+            self.test_image(path, compression)
                 .await
-                .map_err(OneOf::broaden)?),
+                .map_err(OneOf::broaden)?;
         }
+
+        Ok(computed)
     }
 
-    async fn unmount_partitions(&self, client: &udisks::Client) -> Result<(), udisks::Error> {
-        let partition_table = self.destination.partition_table().await?;
+    /// Reads `path` to the end, so that the checks built into its compression
+    /// catch damage before any drive is erased.
+    async fn test_image(
+        &self,
+        path: &Path,
+        compression: &Compression,
+    ) -> Result<(), OneOf<(ProcessStoppedByUser, std::io::Error, ImageDamaged)>> {
+        info!("Testing {}", path.display());
 
-        for partition in client
-            .partitions(&partition_table)
-            .await
-            .iter()
-            .filter_map(|partition| client.object(partition.inner().path().clone()).ok())
-        {
-            if let Err(e) = udisks_unmount(&partition).await {
-                error!(
-                    "Failed to unmount partition {:?}, this will be ignored: {e}",
-                    partition.object_path()
-                );
+        self.set_status(FlashStatus::Active(
+            FlashPhase::Test,
+            Progress::Fraction(0.0),
+        ));
+
+        let mut image = ImageStream::open(path, compression).map_err(OneOf::new)?;
+        let mut last_set = Instant::now();
+
+        while let Some(chunk) = image.next_chunk().await {
+            chunk.map_err(|e| OneOf::new(ImageDamaged(e)))?;
+
+            self.stopped_running().map_err(OneOf::broaden)?;
+
+            if last_set.elapsed() >= Duration::from_millis(250) {
+                self.set_status(FlashStatus::Active(FlashPhase::Test, image.progress()));
+                last_set = Instant::now();
             }
         }
 
+        info!("The image is intact");
+
         Ok(())
     }
 
+    async fn get_source_stream_from_image(
+        &self,
+    ) -> Result<
+        ImageStream,
+        OneOf<(
+            ProcessStoppedByUser,
+            std::io::Error,
+            reqwest::Error,
+            udisks::Error,
+        )>,
+    > {
+        match &self.source {
+            DiskImage::Local {
+                path, compression, ..
+            } => ImageStream::open(path, compression).map_err(OneOf::new),
+            DiskImage::Online {
+                url, download_path, ..
+            } => {
+                let download_path = download_path.as_ref().ok_or_else(|| {
+                    OneOf::new(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Images that are not saved can only be written while downloading",
+                    ))
+                })?;
+
+                self.download_file(download_path.as_path(), url)
+                    .await
+                    .map_err(OneOf::broaden)?;
+
+                let compression = probe::compression(download_path).map_err(OneOf::new)?;
+
+                ImageStream::open(download_path, &compression).map_err(OneOf::new)
+            }
+            DiskImage::Drive { object, .. } => {
+                let block = object.block().await.map_err(OneOf::new)?;
+                let size = block.size().await.map_err(OneOf::new)?;
+                let file = udisks_open_for_reading(&block).await.map_err(OneOf::new)?;
+
+                info!("Source: {file:?} ({size} bytes)");
+
+                Ok(ImageStream::from_drive(file.into_std().await, size))
+            }
+        }
+    }
+
     async fn perform_job(
         &self,
     ) -> Result<
@@ -263,8 +869,11 @@ impl FlashRequest {
             std::io::Error,
             reqwest::Error,
             udisks::Error,
-            XzExtractionFailed,
-            TotalSizeCouldNotBeDetermined,
+            ImageReadFailed,
+            VerificationFailed,
+            ChecksumMismatch,
+            ImageChangedOnServer,
+            ImageDamaged,
         )>,
     > {
         self.stopped_running().map_err(OneOf::broaden)?;
@@ -272,124 +881,634 @@ impl FlashRequest {
         info!(
             "Flashing {:?} to {:?}",
             self.source,
-            self.destination.object_path()
+            self.destinations
+                .iter()
+                .map(|destination| destination.object.object_path())
+                .collect::<Vec<_>>()
         );
 
+        let computed_digest = self.check_source().await.map_err(OneOf::broaden)?;
+
+        // Read the block map before anything is erased, in case it is invalid
+        let bmap = match &self.source {
+            DiskImage::Local {
+                bmap: Some(path), ..
+            } => {
+                info!("Using block map {}", path.display());
+                Some(Bmap::load(path).map_err(OneOf::new)?)
+            }
+            _ => None,
+        };
+
+        // Drives that are too small are left alone, rather than failing halfway through
+        let needed = capacity::declared_size(&self.source).await;
+
         let client = udisks::Client::new().await.map_err(OneOf::new)?;
 
-        let destination_block = self.destination.block().await.map_err(OneOf::new)?;
+        if let DiskImage::Drive { object, .. } = &self.source {
+            if self
+                .destinations
+                .iter()
+                .any(|destination| destination.object.object_path() == object.object_path())
+            {
+                return Err(OneOf::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "A drive can't be cloned onto itself",
+                )));
+            }
 
-        let destination_drive = client
-            .drive_for_block(&destination_block)
+            // File systems that are mounted can change while they are read
+            if let Err(e) = unmount_partitions(&client, object).await {
+                error!("Error unmounting partitions of the source, will be ignored: {e}");
+            }
+        }
+
+        let mut targets = Vec::new();
+        for destination in &self.destinations {
+            match Self::open_destination(
+                &client,
+                destination,
+                &self.source,
+                computed_digest.as_ref(),
+                needed,
+                self.options,
+            )
             .await
-            .map_err(OneOf::new)?;
-        if let Err(e) = self.unmount_partitions(&client).await {
-            error!("Error unmounting partitions, will be ignored: {e}");
+            {
+                Ok(target) => targets.push(target),
+                Err(e) => {
+                    error!("Failed to open {:?}: {e}", destination.object.object_path());
+                    destination.set_status(FlashStatus::Done(Some(e.to_string())));
+                }
+            }
         }
 
         self.stopped_running().map_err(OneOf::broaden)?;
 
-        let destination_file = udisks_open(&destination_block).await.map_err(OneOf::new)?;
+        if targets.is_empty() {
+            return Ok(());
+        }
 
-        info!("Destination: {destination_file:?}");
+        let (fan_out, inboxes) = FanOut::new(targets.len());
+        let writes = targets
+            .iter_mut()
+            .zip(inboxes)
+            .map(|(target, inbox)| Self::write_destination(target, inbox));
 
-        let source_image = self
-            .get_source_file_from_image()
-            .await
-            .map_err(OneOf::broaden)?;
+        let (written, checksums) = futures::join!(
+            self.write_image(bmap.as_ref(), fan_out),
+            futures::future::join_all(writes)
+        );
 
-        info!("Source: {source_image:?}");
+        // Drives that failed on their own are already marked as such
+        let mut written_targets = targets
+            .iter_mut()
+            .zip(checksums)
+            .filter_map(|(target, checksums)| Some((target, checksums?)))
+            .collect::<Vec<_>>();
 
-        self.stopped_running().map_err(OneOf::broaden)?;
+        if written_targets.is_empty() {
+            return Ok(());
+        }
 
-        //TODO: we should probably spawn a UDIsks.Job for this operation,
-        //but udisks-rs does not support this yet
-        Self::load_file(
-            source_image,
-            destination_file,
-            |status| self.set_status(status),
-            self.is_running.clone(),
+        if let Err(e) = written {
+            for (target, _) in &written_targets {
+                target
+                    .report
+                    .record(Verification::Skipped, Some(e.to_string()));
+            }
+            return Err(OneOf::broaden(e));
+        }
+
+        futures::future::join_all(
+            written_targets
+                .iter_mut()
+                .map(|(target, checksums)| self.finish_destination(target, checksums)),
         )
         .await
+        .into_iter()
+        .collect::<Result<(), _>>()
         .map_err(OneOf::broaden)?;
 
-        if let Err(e) = destination_block.rescan(HashMap::new()).await {
+        info!("Flashing completed");
+
+        Ok(())
+    }
+
+    async fn open_destination<'a>(
+        client: &udisks::Client,
+        destination: &'a Destination,
+        source: &DiskImage,
+        computed_digest: Option<&Digest>,
+        needed: Option<u64>,
+        options: FlashOptions,
+    ) -> Result<OpenDestination<'a>, OneOf<(udisks::Error, ImageTooLarge)>> {
+        let block = destination.object.block().await.map_err(OneOf::new)?;
+        let drive = client.drive_for_block(&block).await.map_err(OneOf::new)?;
+        let size = block.size().await.map_err(OneOf::new)?;
+
+        // Drives that are turned down count as much as the ones written to
+        let mut report = report::Entry::start(source, computed_digest, &drive, size).await;
+
+        if let Err(e) = capacity::check(needed, size) {
+            report.record(Verification::Skipped, Some(e.to_string()));
+            return Err(OneOf::new(e));
+        }
+
+        if let Err(e) = unmount_partitions(client, &destination.object).await {
+            error!("Error unmounting partitions, will be ignored: {e}");
+        }
+
+        let file = match udisks_open(&block).await {
+            Ok(file) => file,
+            Err(e) => {
+                report.record(Verification::Skipped, Some(e.to_string()));
+                return Err(OneOf::new(e));
+            }
+        };
+
+        info!("Destination: {file:?}");
+
+        let zero_blocks = if options.skip_zero_blocks {
+            ZeroBlocks::prepare(&file, size).await
+        } else {
+            ZeroBlocks::Write
+        };
+
+        report.begin_writing();
+
+        Ok(OpenDestination {
+            destination,
+            block,
+            drive,
+            file,
+            zero_blocks,
+            report,
+        })
+    }
+
+    /// Writes what `inbox` gets to `target`, returning checksums of what
+    /// was written, or marking it as failed.
+    async fn write_destination(
+        target: &mut OpenDestination<'_>,
+        inbox: writer::Inbox,
+    ) -> Option<BlockChecksums> {
+        let destination = target.destination;
+
+        match writer::drain(&mut target.file, target.zero_blocks, inbox, |status| {
+            destination.set_status(status);
+        })
+        .await
+        {
+            Ok(checksums) => {
+                target.report.written(checksums.total());
+                Some(checksums)
+            }
+            Err(e) => {
+                error!(
+                    "Writing to {:?} failed: {e}",
+                    destination.object.object_path()
+                );
+                target
+                    .report
+                    .record(Verification::Skipped, Some(e.to_string()));
+                destination.set_status(FlashStatus::Done(Some(e.to_string())));
+                None
+            }
+        }
+    }
+
+    /// Verifies and ejects a drive that the image was written to.
+    async fn finish_destination(
+        &self,
+        target: &mut OpenDestination<'_>,
+        checksums: &BlockChecksums,
+    ) -> Result<(), OneOf<(ProcessStoppedByUser,)>> {
+        let destination = target.destination;
+
+        if self.options.verify {
+            if let Err(e) = self.stopped_running() {
+                target
+                    .report
+                    .record(Verification::Skipped, Some(e.to_string()));
+                return Err(e);
+            }
+
+            let verified = Self::verify_file(
+                &mut target.file,
+                checksums,
+                |status| {
+                    destination.set_status(status.clone());
+                    self.set_status(status);
+                },
+                self.is_running.clone(),
+            )
+            .await;
+
+            if let Err(e) = verified {
+                match e.narrow::<ProcessStoppedByUser, _>() {
+                    Ok(stopped) => {
+                        target
+                            .report
+                            .record(Verification::Skipped, Some(stopped.to_string()));
+                        return Err(OneOf::new(stopped));
+                    }
+                    Err(e) => {
+                        error!(
+                            "Verifying {:?} failed: {e}",
+                            destination.object.object_path()
+                        );
+                        target
+                            .report
+                            .record(Verification::Failed, Some(e.to_string()));
+                        destination.set_status(FlashStatus::Done(Some(e.to_string())));
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = target.block.rescan(HashMap::new()).await {
             error!("Error rescanning block device, will be ignored: {e}");
         }
 
-        if let Err(e) = destination_drive.eject(HashMap::new()).await {
+        if let Err(e) = target.drive.eject(HashMap::new()).await {
             error!("Error ejecting drive, will be ignored: {e}");
         }
 
-        info!("Flashing completed successfully");
+        target.report.record(
+            if self.options.verify {
+                Verification::Passed
+            } else {
+                Verification::Skipped
+            },
+            None,
+        );
+        destination.set_status(FlashStatus::Done(None));
 
         Ok(())
     }
 
-    async fn load_file<F: Fn(FlashStatus) + Send>(
-        image: File,
-        mut target_file: File,
+    /// Writes the image to the drives behind `fan_out`.
+    async fn write_image(
+        &self,
+        bmap: Option<&Bmap>,
+        mut fan_out: FanOut,
+    ) -> Result<
+        (),
+        OneOf<(
+            ProcessStoppedByUser,
+            std::io::Error,
+            reqwest::Error,
+            udisks::Error,
+            ImageReadFailed,
+            ImageChangedOnServer,
+        )>,
+    > {
+        //TODO: we should probably spawn a UDIsks.Job for this operation,
+        //but udisks-rs does not support this yet
+        if let DiskImage::Online {
+            url, download_path, ..
+        } = &self.source
+            && (self.options.write_while_downloading || download_path.is_none())
+        {
+            self.download_and_load_file(url, download_path.as_deref(), &mut fan_out)
+                .await
+                .map_err(OneOf::broaden)
+        } else {
+            let source_image = self
+                .get_source_stream_from_image()
+                .await
+                .map_err(OneOf::broaden)?;
+
+            self.stopped_running().map_err(OneOf::broaden)?;
+
+            if let Some(bmap) = bmap {
+                Self::load_mapped_file(
+                    source_image,
+                    bmap,
+                    &mut fan_out,
+                    |status| self.set_status(status),
+                    self.is_running.clone(),
+                )
+                .await
+                .map_err(OneOf::broaden)
+            } else {
+                Self::load_file(
+                    source_image,
+                    &mut fan_out,
+                    |status| self.set_status(status),
+                    self.is_running.clone(),
+                )
+                .await
+                .map_err(OneOf::broaden)
+            }
+        }
+    }
+
+    pub async fn load_file<F: Fn(FlashStatus) + Send>(
+        image: ImageStream,
+        writer: &mut FanOut,
         set_status: F,
         is_running: Arc<AtomicBool>,
-    ) -> Result<(), OneOf<(std::io::Error, ProcessStoppedByUser)>> {
+    ) -> Result<(), OneOf<(std::io::Error, ProcessStoppedByUser, ImageReadFailed)>> {
+        Self::load_chunks(None, image, writer, set_status, is_running).await
+    }
+
+    /// Writes `first_chunk`, if it was already read, and the rest of `image`.
+    async fn load_chunks<F: Fn(FlashStatus) + Send>(
+        mut first_chunk: Option<Vec<u8>>,
+        mut image: ImageStream,
+        writer: &mut FanOut,
+        set_status: F,
+        is_running: Arc<AtomicBool>,
+    ) -> Result<(), OneOf<(std::io::Error, ProcessStoppedByUser, ImageReadFailed)>> {
         let mut last_set = Instant::now();
-        let mut total = 0_u64;
 
-        let size = match image.metadata().await {
-            Ok(meta) => meta.len(),
-            Err(e) => {
-                error!("Failed to get image metadata: {e}");
-                0
+        info!("Writing image ({} bytes)", image.size());
+
+        let mut sparse = None;
+        let mut is_first = true;
+
+        while let Some(chunk) = match first_chunk.take() {
+            Some(chunk) => Some(Ok(chunk)),
+            None => image.next_chunk().await,
+        } {
+            let chunk = chunk.map_err(|e| OneOf::new(ImageReadFailed(e)))?;
+
+            if std::mem::take(&mut is_first) && sparse::is_sparse(&chunk) {
+                sparse = Some(SparseExpander::new());
             }
-        };
 
-        info!("Writing file {image:?} ({size} bytes)");
+            if let Some(expander) = &mut sparse {
+                expander.feed(&chunk, writer).await.map_err(|e| {
+                    match e.narrow::<InvalidSparseImage, _>() {
+                        Ok(e) => OneOf::new(ImageReadFailed(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            e,
+                        ))),
+                        Err(e) => OneOf::broaden(e),
+                    }
+                })?;
+            } else {
+                writer.write(&chunk).await.map_err(OneOf::new)?;
+            }
 
-        let mut source = tokio::io::BufReader::with_capacity(1024 * 1024, image);
-        let mut target = tokio::io::BufWriter::with_capacity(1024 * 1024, &mut target_file);
+            if !is_running.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(OneOf::new(ProcessStoppedByUser));
+            }
 
-        let mut buf = vec![0; 256 * 1024].into_boxed_slice();
+            if last_set.elapsed() >= Duration::from_millis(250) {
+                let status = FlashStatus::Active(image.phase(), image.progress());
+                writer.set_status(status.clone()).await;
+                set_status(status);
+                last_set = Instant::now();
+            }
+        }
 
-        loop {
-            let x = tokio::io::AsyncReadExt::read(&mut source, &mut buf)
-                .await
-                .map_err(OneOf::new)?;
+        if let Some(expander) = &sparse {
+            expander.finish().map_err(|e| {
+                OneOf::new(ImageReadFailed(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    e,
+                )))
+            })?;
+        }
 
-            if x == 0 {
-                break;
+        Ok(())
+    }
+
+    /// Writes only the ranges of `image` listed in `bmap`, checking each of
+    /// them against its checksum in the block map.
+    async fn load_mapped_file<F: Fn(FlashStatus) + Send>(
+        mut image: ImageStream,
+        bmap: &Bmap,
+        writer: &mut FanOut,
+        set_status: F,
+        is_running: Arc<AtomicBool>,
+    ) -> Result<(), OneOf<(std::io::Error, ProcessStoppedByUser, ImageReadFailed)>> {
+        let mut last_set = Instant::now();
+
+        info!(
+            "Writing {} of {} bytes mapped in the block map",
+            bmap.mapped_size(),
+            bmap.image_size
+        );
+
+        let mut ranges = bmap.ranges.iter().peekable();
+        let mut hasher = bmap.algorithm.hasher();
+        let mut offset = 0_u64;
+
+        while let Some(chunk) = image.next_chunk().await {
+            let chunk = chunk.map_err(|e| OneOf::new(ImageReadFailed(e)))?;
+
+            if offset == 0 && sparse::is_sparse(&chunk) {
+                // The block map describes the expanded image, and the sparse
+                // image already leaves out the blocks that don't need writing
+                info!("Ignoring the block map of an Android sparse image");
+                return Self::load_chunks(Some(chunk), image, writer, set_status, is_running).await;
             }
 
-            total += x as u64;
+            let chunk_end = offset + chunk.len() as u64;
 
-            tokio::io::AsyncWriteExt::write_all(&mut target, &buf[..x])
-                .await
-                .map_err(OneOf::new)?;
+            while let Some(&range) = ranges.peek()
+                && range.start < chunk_end
+            {
+                let from = range.start.max(offset);
+                let to = range.end.min(chunk_end);
+
+                #[allow(clippy::cast_possible_truncation)]
+                let data = &chunk[(from - offset) as usize..(to - offset) as usize];
+                writer.write_at(from, data).await.map_err(OneOf::new)?;
+                hasher.update(data);
+
+                if range.end > chunk_end {
+                    // The rest of this range is in the next chunk
+                    break;
+                }
+                ranges.next();
+
+                let computed = hex::encode(hasher.finalize_reset());
+                if range
+                    .digest
+                    .as_ref()
+                    .is_some_and(|digest| digest.value != computed)
+                {
+                    return Err(OneOf::new(ImageReadFailed(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "data at offset {} does not match its checksum in the block map",
+                            range.start
+                        ),
+                    ))));
+                }
+            }
+
+            offset = chunk_end;
 
             if !is_running.load(std::sync::atomic::Ordering::SeqCst) {
                 return Err(OneOf::new(ProcessStoppedByUser));
             }
 
             if last_set.elapsed() >= Duration::from_millis(250) {
-                set_status(FlashStatus::Active(
-                    FlashPhase::Copy,
-                    Progress::from((total, size)),
-                ));
+                let status = FlashStatus::Active(image.phase(), image.progress());
+                writer.set_status(status.clone()).await;
+                set_status(status);
                 last_set = Instant::now();
             }
         }
 
-        if let Err(e) = target.flush().await {
-            error!("Error flushing data to target, will be ignored: {e}");
+        if offset != bmap.image_size {
+            return Err(OneOf::new(ImageReadFailed(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "the image has {offset} bytes, but its block map describes {} bytes",
+                    bmap.image_size
+                ),
+            ))));
         }
 
-        if let Err(e) = target_file.sync_all().await {
-            error!("Error syncing data to target, will be ignored: {e}");
+        Ok(())
+    }
+
+    pub async fn verify_file<F: Fn(FlashStatus) + Send>(
+        target_file: &mut File,
+        checksums: &BlockChecksums,
+        set_status: F,
+        is_running: Arc<AtomicBool>,
+    ) -> Result<(), OneOf<(std::io::Error, ProcessStoppedByUser, VerificationFailed)>> {
+        let mut last_set = Instant::now();
+        let size = checksums.total();
+
+        info!("Verifying {size} bytes written to {target_file:?}");
+
+        set_status(FlashStatus::Active(
+            FlashPhase::Verify,
+            Progress::Fraction(0.0),
+        ));
+
+        drop_cached_pages(target_file);
+        target_file
+            .seek(std::io::SeekFrom::Start(0))
+            .await
+            .map_err(OneOf::new)?;
+
+        let mut target = tokio::io::BufReader::with_capacity(1024 * 1024, &mut *target_file);
+
+        let mut buf = vec![0; VERIFY_BLOCK_SIZE].into_boxed_slice();
+        let mut position = 0_u64;
+        let mut verified = 0_u64;
+
+        for block in &checksums.blocks {
+            let offset = block.offset;
+
+            if offset != position {
+                target
+                    .seek(std::io::SeekFrom::Start(offset))
+                    .await
+                    .map_err(OneOf::new)?;
+            }
+
+            match target.read_exact(&mut buf[..block.len]).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(OneOf::new(VerificationFailed {
+                        block_offset: offset,
+                        block_len: block.len,
+                    }));
+                }
+                Err(e) => return Err(OneOf::new(e)),
+            }
+
+            if crc32fast::hash(&buf[..block.len]) != block.checksum {
+                return Err(OneOf::new(VerificationFailed {
+                    block_offset: offset,
+                    block_len: block.len,
+                }));
+            }
+
+            position = offset + block.len as u64;
+            verified += block.len as u64;
+
+            if !is_running.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(OneOf::new(ProcessStoppedByUser));
+            }
+
+            if last_set.elapsed() >= Duration::from_millis(250) {
+                set_status(FlashStatus::Active(
+                    FlashPhase::Verify,
+                    Progress::from((verified, size)),
+                ));
+                last_set = Instant::now();
+            }
         }
 
+        info!("Verification completed successfully");
+
         Ok(())
     }
 }
 
+/// Whether a download that failed with `error` is worth trying again.
+fn is_transient_request_error(error: &reqwest::Error) -> bool {
+    error.status().map_or_else(
+        || error.is_timeout() || error.is_connect() || error.is_request() || error.is_body(),
+        |status| status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+    )
+}
+
+fn is_transient_io_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Time to wait after the given failed attempt, doubling from one second.
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.saturating_sub(1).min(5))
+}
+
+/// Asks the kernel to forget cached pages of `file` so that reading it back
+/// hits the drive instead of returning what was just written from memory.
+fn drop_cached_pages(file: &File) {
+    let fd = std::os::fd::AsRawFd::as_raw_fd(file);
+    // SAFETY: `fd` is a valid open file descriptor borrowed from `file`.
+    let result = unsafe { libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if result != 0 {
+        error!(
+            "Failed to drop cached pages, will be ignored: {}",
+            std::io::Error::from_raw_os_error(result)
+        );
+    }
+}
+
+pub async fn unmount_partitions(
+    client: &udisks::Client,
+    object: &udisks::Object,
+) -> Result<(), udisks::Error> {
+    let partition_table = object.partition_table().await?;
+
+    for partition in client
+        .partitions(&partition_table)
+        .await
+        .iter()
+        .filter_map(|partition| client.object(partition.inner().path().clone()).ok())
+    {
+        if let Err(e) = udisks_unmount(&partition).await {
+            error!(
+                "Failed to unmount partition {:?}, this will be ignored: {e}",
+                partition.object_path()
+            );
+        }
+    }
+
+    Ok(())
+}
+
 async fn udisks_unmount(object: &udisks::Object) -> udisks::Result<()> {
     let filesystem = object.filesystem().await?;
     let err = filesystem
@@ -401,10 +1520,17 @@ async fn udisks_unmount(object: &udisks::Object) -> udisks::Result<()> {
     Ok(())
 }
 
-async fn udisks_open(block: &udisks::block::BlockProxy<'_>) -> udisks::Result<File> {
+pub async fn udisks_open(block: &udisks::block::BlockProxy<'_>) -> udisks::Result<File> {
     let fd: std::os::fd::OwnedFd = block
         .open_device("rw", HashMap::from([("flags", libc::O_SYNC.into())]))
         .await?
         .into();
     Ok(std::fs::File::from(fd).into())
 }
+
+pub async fn udisks_open_for_reading(
+    block: &udisks::block::BlockProxy<'_>,
+) -> udisks::Result<File> {
+    let fd: std::os::fd::OwnedFd = block.open_device("r", HashMap::new()).await?.into();
+    Ok(std::fs::File::from(fd).into())
+}
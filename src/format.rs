@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info};
+use terrors::OneOf;
+
+use crate::flash::{self, FlashPhase, FlashStatus, ProcessStoppedByUser, Progress};
+
+/// Where the partition starts, which keeps it aligned for flash memory.
+const PARTITION_OFFSET: u64 = 1024 * 1024;
+
+/// How long to wait for udisks to pick up a new partition table.
+const PARTITION_TABLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionScheme {
+    Gpt,
+    Mbr,
+}
+
+impl PartitionScheme {
+    pub const ALL: [Self; 2] = [Self::Gpt, Self::Mbr];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Gpt => "GPT",
+            Self::Mbr => "MBR",
+        }
+    }
+
+    const fn udisks_type(self) -> &'static str {
+        match self {
+            Self::Gpt => "gpt",
+            Self::Mbr => "dos",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    Fat32,
+    Exfat,
+    Ntfs,
+    Ext4,
+}
+
+impl Filesystem {
+    pub const ALL: [Self; 4] = [Self::Fat32, Self::Exfat, Self::Ntfs, Self::Ext4];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Fat32 => "FAT32",
+            Self::Exfat => "exFAT",
+            Self::Ntfs => "NTFS",
+            Self::Ext4 => "ext4",
+        }
+    }
+
+    const fn udisks_type(self) -> &'static str {
+        match self {
+            Self::Fat32 => "vfat",
+            Self::Exfat => "exfat",
+            Self::Ntfs => "ntfs",
+            Self::Ext4 => "ext4",
+        }
+    }
+
+    /// The longest label that the file system can hold, in bytes.
+    pub const fn max_label_len(self) -> usize {
+        match self {
+            Self::Fat32 => 11,
+            Self::Exfat => 15,
+            Self::Ntfs => 32,
+            Self::Ext4 => 16,
+        }
+    }
+
+    /// The type of the partition that holds the file system.
+    const fn partition_type(self, scheme: PartitionScheme) -> &'static str {
+        match (scheme, self) {
+            (PartitionScheme::Gpt, Self::Ext4) => "0fc63daf-8483-4772-8e79-3d69d8477de4",
+            (PartitionScheme::Gpt, _) => "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7",
+            (PartitionScheme::Mbr, Self::Fat32) => "0x0c",
+            (PartitionScheme::Mbr, Self::Exfat | Self::Ntfs) => "0x07",
+            (PartitionScheme::Mbr, Self::Ext4) => "0x83",
+        }
+    }
+}
+
+/// How a drive is formatted.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    pub scheme: PartitionScheme,
+    pub filesystem: Filesystem,
+    pub label: String,
+}
+
+/// Turns a drive back into a plain storage drive with a single partition.
+pub struct FormatRequest {
+    drive: udisks::Object,
+    options: FormatOptions,
+    status: Arc<Mutex<FlashStatus>>,
+    is_running: Arc<AtomicBool>,
+}
+
+impl FormatRequest {
+    pub const fn new(
+        drive: udisks::Object,
+        options: FormatOptions,
+        status: Arc<Mutex<FlashStatus>>,
+        is_running: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            drive,
+            options,
+            status,
+            is_running,
+        }
+    }
+
+    pub async fn perform(self) {
+        match self.perform_job().await {
+            Ok(()) => self.set_status(FlashStatus::Done(None)),
+            Err(e) => {
+                if let Err(e) = e.narrow::<ProcessStoppedByUser, _>() {
+                    error!("Formatting failed: {e}");
+                    self.set_status(FlashStatus::Done(Some(e.to_string())));
+                }
+            }
+        }
+    }
+
+    fn set_status(&self, status: FlashStatus) {
+        if let Ok(mut lock) = self.status.lock() {
+            *lock = status;
+        }
+    }
+
+    fn stopped_running(&self) -> Result<(), OneOf<(ProcessStoppedByUser,)>> {
+        if self.is_running.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(OneOf::new(ProcessStoppedByUser))
+        }
+    }
+
+    async fn perform_job(&self) -> Result<(), OneOf<(ProcessStoppedByUser, udisks::Error)>> {
+        info!(
+            "Formatting {:?} ({:?})",
+            self.drive.object_path(),
+            self.options
+        );
+
+        self.set_status(FlashStatus::Active(FlashPhase::Format, Progress::Pulse));
+
+        let client = udisks::Client::new().await.map_err(OneOf::new)?;
+
+        if let Err(e) = flash::unmount_partitions(&client, &self.drive).await {
+            error!("Error unmounting partitions, will be ignored: {e}");
+        }
+
+        let block = self.drive.block().await.map_err(OneOf::new)?;
+
+        // Hybrid images leave ISO 9660 and partition table signatures behind
+        // that would otherwise still be picked up
+        block
+            .format("empty", HashMap::new())
+            .await
+            .map_err(OneOf::new)?;
+        self.stopped_running().map_err(OneOf::broaden)?;
+
+        block
+            .format(self.options.scheme.udisks_type(), HashMap::new())
+            .await
+            .map_err(OneOf::new)?;
+        self.stopped_running().map_err(OneOf::broaden)?;
+
+        let partition_table = self.partition_table().await.map_err(OneOf::new)?;
+
+        let filesystem = self.options.filesystem;
+        let label = truncate_label(&self.options.label, filesystem.max_label_len());
+
+        let mut format_options = HashMap::from([("label", label.into())]);
+        if filesystem == Filesystem::Ext4 {
+            // Otherwise only root could write to the drive
+            format_options.insert("take-ownership", true.into());
+        }
+
+        let partition = partition_table
+            .create_partition_and_format(
+                PARTITION_OFFSET,
+                0,
+                filesystem.partition_type(self.options.scheme),
+                if self.options.scheme == PartitionScheme::Gpt {
+                    label
+                } else {
+                    ""
+                },
+                HashMap::new(),
+                filesystem.udisks_type(),
+                format_options,
+            )
+            .await
+            .map_err(OneOf::new)?;
+
+        info!("Created {partition:?}");
+
+        Ok(())
+    }
+
+    /// Waits for the partition table that was just created to show up.
+    async fn partition_table(
+        &self,
+    ) -> udisks::Result<udisks::partitiontable::PartitionTableProxy<'static>> {
+        let deadline = tokio::time::Instant::now() + PARTITION_TABLE_TIMEOUT;
+
+        loop {
+            match self.drive.partition_table().await {
+                Ok(partition_table) => return Ok(partition_table),
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(250)).await,
+            }
+        }
+    }
+}
+
+/// Cuts the label down to `max_len` bytes without splitting a character.
+fn truncate_label(label: &str, max_len: usize) -> &str {
+    let end = label
+        .char_indices()
+        .map(|(index, c)| index + c.len_utf8())
+        .take_while(|&end| end <= max_len)
+        .last()
+        .unwrap_or(0);
+    &label[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_labels_by_bytes() {
+        assert_eq!(truncate_label("USB", 11), "USB");
+        assert_eq!(truncate_label("BACKUPDRIVE2", 11), "BACKUPDRIVE");
+        // 'é' takes two bytes, so only five of them fit in eleven
+        assert_eq!(truncate_label("éééééé", 11), "ééééé");
+        assert_eq!(truncate_label("日本", 2), "");
+    }
+}
@@ -0,0 +1,661 @@
+use std::path::Path;
+
+use log::info;
+
+use crate::source::ImageStream;
+use crate::sparse;
+use crate::window::Compression;
+
+/// How much of the start of an image is looked at, which is where boot
+/// records and partition tables are kept.
+const PREFIX_SIZE: usize = 8 * 1024 * 1024;
+
+const ISO_SECTOR_SIZE: usize = 2048;
+const MBR_SECTOR_SIZE: u64 = 512;
+
+const ESP_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+const MBR_TYPE_ESP: u8 = 0xEF;
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+
+/// Firmware that an El Torito boot catalog has an entry for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Bios,
+    Uefi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTable {
+    Mbr,
+    /// A GUID partition table, along with an MBR that lists partitions too.
+    Gpt {
+        hybrid: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub number: usize,
+    pub kind: String,
+    pub label: String,
+    pub offset: u64,
+    pub size: u64,
+    /// Whether this is an EFI system partition, which UEFI firmware boots from.
+    pub esp: bool,
+    /// The file system in the partition, if it starts close enough to be seen.
+    pub filesystem: Option<&'static str>,
+}
+
+/// What the start of an image shows about its layout and how it boots.
+#[derive(Debug, Clone, Default)]
+pub struct Inspection {
+    /// The volume identifier of an ISO 9660 image.
+    pub iso9660: Option<String>,
+    /// Platforms that the image boots on from an optical disc.
+    pub el_torito: Vec<Platform>,
+    /// A file system that takes up the whole image, without a partition table.
+    pub filesystem: Option<&'static str>,
+    pub partition_table: Option<PartitionTable>,
+    pub partitions: Vec<Partition>,
+    /// Whether the MBR holds boot code that BIOS firmware runs.
+    pub bios_bootable: bool,
+    /// Whether there is an EFI system partition for UEFI firmware to boot from.
+    pub uefi_bootable: bool,
+}
+
+impl Inspection {
+    /// Whether a drive that the image is written to starts a PC.
+    pub const fn is_bootable(&self) -> bool {
+        self.bios_bootable || self.uefi_bootable
+    }
+
+    /// Whether the image looks made for boards like the Raspberry Pi, whose
+    /// firmware starts from a FAT partition in the MBR rather than boot code.
+    pub fn has_fat_boot_partition(&self) -> bool {
+        self.partition_table == Some(PartitionTable::Mbr)
+            && self.partitions.iter().any(|partition| {
+                matches!(partition.filesystem, Some("FAT" | "FAT32"))
+                    || partition.kind.starts_with("FAT")
+            })
+    }
+}
+
+/// Looks at the start of the image at `path`, or returns `None` for images
+/// whose layout only shows once they are written, like Android sparse images.
+pub async fn inspect(
+    path: &Path,
+    compression: &Compression,
+) -> std::io::Result<Option<Inspection>> {
+    let mut image = ImageStream::open(path, compression)?;
+    let mut data = Vec::new();
+
+    while data.len() < PREFIX_SIZE
+        && let Some(chunk) = image.next_chunk().await
+    {
+        data.extend_from_slice(&chunk?);
+    }
+    data.truncate(PREFIX_SIZE);
+
+    if sparse::is_sparse(&data) {
+        return Ok(None);
+    }
+
+    let inspection = parse(&data);
+    info!("Inspected {}: {inspection:?}", path.display());
+
+    Ok(Some(inspection))
+}
+
+fn parse(data: &[u8]) -> Inspection {
+    let mut inspection = Inspection::default();
+
+    if let Some(volume_id) = iso9660_volume_id(data) {
+        inspection.iso9660 = Some(volume_id);
+        inspection.el_torito = el_torito_platforms(data);
+    } else {
+        inspection.filesystem = filesystem(data);
+    }
+
+    let mbr = mbr_partitions(data);
+    let gpt = gpt_partitions(data);
+
+    match (&gpt, &mbr) {
+        (Some(gpt), mbr) => {
+            let hybrid = mbr.as_ref().is_some_and(|mbr| {
+                mbr.iter()
+                    .any(|(partition_type, _)| *partition_type != MBR_TYPE_PROTECTIVE)
+            });
+            inspection.partition_table = Some(PartitionTable::Gpt { hybrid });
+            inspection.partitions.clone_from(gpt);
+        }
+        (None, Some(mbr)) => {
+            inspection.partition_table = Some(PartitionTable::Mbr);
+            inspection.partitions = mbr.iter().map(|(_, partition)| partition.clone()).collect();
+        }
+        (None, None) => {}
+    }
+
+    for partition in &mut inspection.partitions {
+        partition.filesystem = usize::try_from(partition.offset)
+            .ok()
+            .and_then(|offset| data.get(offset..))
+            .and_then(filesystem);
+    }
+
+    // File systems keep the boot signature too, but rarely code that boots
+    inspection.bios_bootable = inspection.filesystem.is_none()
+        && has_at(data, 510, b"\x55\xAA")
+        && data
+            .get(..440)
+            .is_some_and(|code| code.iter().any(|&x| x != 0));
+    inspection.uefi_bootable = inspection.partitions.iter().any(|partition| partition.esp);
+
+    inspection
+}
+
+fn has_at(data: &[u8], offset: usize, magic: &[u8]) -> bool {
+    data.get(offset..offset + magic.len())
+        .is_some_and(|bytes| bytes == magic)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Names the file system that starts at the beginning of `data`, if any.
+fn filesystem(data: &[u8]) -> Option<&'static str> {
+    if has_at(data, 0x8001, b"CD001") {
+        Some("ISO 9660")
+    } else if has_at(data, 3, b"EXFAT   ") {
+        Some("exFAT")
+    } else if has_at(data, 3, b"NTFS    ") {
+        Some("NTFS")
+    } else if has_at(data, 0x52, b"FAT32   ") {
+        Some("FAT32")
+    } else if has_at(data, 0x36, b"FAT1") {
+        Some("FAT")
+    } else if has_at(data, 0, b"hsqs") {
+        Some("SquashFS")
+    } else if has_at(data, 0, b"XFSB") {
+        Some("XFS")
+    } else if has_at(data, 1024, b"\xE2\xE1\xF5\xE0") {
+        Some("EROFS")
+    } else if has_at(data, 1024, b"H+") || has_at(data, 1024, b"HX") {
+        Some("HFS+")
+    } else if has_at(data, 1080, b"\x53\xEF") {
+        Some("ext4")
+    } else if has_at(data, 0x10040, b"_BHRfS_M") {
+        Some("Btrfs")
+    } else {
+        None
+    }
+}
+
+fn iso9660_volume_id(data: &[u8]) -> Option<String> {
+    (16..32)
+        .map(|sector| sector * ISO_SECTOR_SIZE)
+        .take_while(|&offset| has_at(data, offset + 1, b"CD001"))
+        .find(|&offset| data[offset] == 1)
+        .and_then(|offset| data.get(offset + 40..offset + 72))
+        .map(|volume_id| String::from_utf8_lossy(volume_id).trim().to_owned())
+}
+
+/// Lists the platforms that the El Torito boot catalog has bootable entries for.
+fn el_torito_platforms(data: &[u8]) -> Vec<Platform> {
+    let Some(catalog) = (16..32)
+        .map(|sector| sector * ISO_SECTOR_SIZE)
+        .take_while(|&offset| has_at(data, offset + 1, b"CD001"))
+        .find(|&offset| data[offset] == 0 && has_at(data, offset + 7, b"EL TORITO SPECIFICATION"))
+        .and_then(|offset| u32_at(data, offset + 0x47))
+        .and_then(|sector| usize::try_from(sector).ok())
+        .and_then(|sector| sector.checked_mul(ISO_SECTOR_SIZE))
+        .and_then(|offset| data.get(offset..))
+    else {
+        return Vec::new();
+    };
+
+    // The validation entry names the platform of the default entry after it
+    if catalog.first() != Some(&0x01) || !has_at(catalog, 30, b"\x55\xAA") {
+        return Vec::new();
+    }
+
+    let mut platforms = Vec::new();
+    let mut add = |platform_id: u8, boot_indicator: u8| {
+        let platform = match platform_id {
+            0x00 => Platform::Bios,
+            0xEF => Platform::Uefi,
+            _ => return,
+        };
+        if boot_indicator == 0x88 && !platforms.contains(&platform) {
+            platforms.push(platform);
+        }
+    };
+
+    add(catalog[1], catalog.get(32).copied().unwrap_or_default());
+
+    let mut offset = 64;
+    while let Some(&header) = catalog.get(offset)
+        && (header == 0x90 || header == 0x91)
+    {
+        let platform_id = catalog.get(offset + 1).copied().unwrap_or_default();
+        let entries = usize::from(u16_at(catalog, offset + 2).unwrap_or_default());
+
+        for entry in 0..entries {
+            if let Some(&boot_indicator) = catalog.get(offset + 32 * (entry + 1)) {
+                add(platform_id, boot_indicator);
+            }
+        }
+
+        offset += 32 * (entries + 1);
+        if header == 0x91 {
+            break;
+        }
+    }
+
+    platforms
+}
+
+/// Lists the primary partitions in the MBR along with their types.
+fn mbr_partitions(data: &[u8]) -> Option<Vec<(u8, Partition)>> {
+    if !has_at(data, 510, b"\x55\xAA") || filesystem(data).is_some_and(|x| x != "ISO 9660") {
+        return None;
+    }
+
+    let partitions = (0..4)
+        .filter_map(|index| {
+            let entry = data.get(446 + index * 16..446 + (index + 1) * 16)?;
+            let partition_type = entry[4];
+            let start = u64::from(u32_at(entry, 8)?);
+            let sectors = u64::from(u32_at(entry, 12)?);
+
+            (partition_type != 0 && sectors > 0).then(|| {
+                (
+                    partition_type,
+                    Partition {
+                        number: index + 1,
+                        kind: mbr_type_name(partition_type),
+                        label: String::new(),
+                        offset: start * MBR_SECTOR_SIZE,
+                        size: sectors * MBR_SECTOR_SIZE,
+                        esp: partition_type == MBR_TYPE_ESP,
+                        filesystem: None,
+                    },
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+
+    (!partitions.is_empty()).then_some(partitions)
+}
+
+fn mbr_type_name(partition_type: u8) -> String {
+    match partition_type {
+        0x01 | 0x04 | 0x06 | 0x0E => "FAT".to_owned(),
+        0x07 => "NTFS/exFAT".to_owned(),
+        0x0B | 0x0C => "FAT32".to_owned(),
+        0x17 => "Hidden NTFS".to_owned(),
+        0x82 => "Linux Swap".to_owned(),
+        0x83 => "Linux".to_owned(),
+        0x8E => "Linux LVM".to_owned(),
+        0xEE => "GPT Protective".to_owned(),
+        0xEF => "EFI System".to_owned(),
+        _ => format!("Type {partition_type:#04X}"),
+    }
+}
+
+/// Lists the partitions in the GUID partition table, which is found after
+/// the first sector for either common sector size.
+fn gpt_partitions(data: &[u8]) -> Option<Vec<Partition>> {
+    let sector_size = [512_usize, 4096]
+        .into_iter()
+        .find(|&sector_size| has_at(data, sector_size, b"EFI PART"))?;
+    let header = &data[sector_size..];
+
+    let entries_offset = usize::try_from(u64_at(header, 72)?)
+        .ok()?
+        .checked_mul(sector_size)?;
+    let entry_count = usize::try_from(u32_at(header, 80)?).ok()?.min(256);
+    let entry_size = usize::try_from(u32_at(header, 84)?).ok()?;
+    if entry_size < 128 {
+        return None;
+    }
+
+    let partitions = (0..entry_count)
+        .filter_map(|index| {
+            let offset = index.checked_mul(entry_size)?.checked_add(entries_offset)?;
+            let entry = data.get(offset..offset.checked_add(128)?)?;
+            if entry[..16].iter().all(|&x| x == 0) {
+                return None;
+            }
+
+            let type_guid = guid(&entry[..16]);
+            let first = u64_at(entry, 32)?;
+            let last = u64_at(entry, 40)?;
+            let label = String::from_utf16_lossy(
+                &entry[56..128]
+                    .chunks_exact(2)
+                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                    .take_while(|&x| x != 0)
+                    .collect::<Vec<_>>(),
+            );
+
+            // Entries that point past anything addressable are left out
+            let offset = first.checked_mul(sector_size as u64)?;
+            let size = last
+                .checked_add(1)?
+                .saturating_sub(first)
+                .checked_mul(sector_size as u64)?;
+
+            Some(Partition {
+                number: index + 1,
+                kind: gpt_type_name(&type_guid),
+                label,
+                offset,
+                size,
+                esp: type_guid == ESP_GUID,
+                filesystem: None,
+            })
+        })
+        .collect();
+
+    Some(partitions)
+}
+
+/// Formats a GUID as it is stored on disk, with the first three fields in
+/// little endian.
+fn guid(bytes: &[u8]) -> String {
+    format!(
+        "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{}-{}",
+        bytes[3],
+        bytes[2],
+        bytes[1],
+        bytes[0],
+        bytes[5],
+        bytes[4],
+        bytes[7],
+        bytes[6],
+        hex::encode_upper(&bytes[8..10]),
+        hex::encode_upper(&bytes[10..16]),
+    )
+}
+
+fn gpt_type_name(type_guid: &str) -> String {
+    match type_guid {
+        ESP_GUID => "EFI System",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS Boot",
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft Reserved",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Basic Data",
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows Recovery",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux Filesystem",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux Root (x86-64)",
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE" => "Linux Root (ARM64)",
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172" => "Linux Extended Boot",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux Swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "FE3A2A5D-4F32-41A7-B725-ACCC3285A309" => "ChromeOS Kernel",
+        "3CB8E202-3B7E-47DD-8A3C-7FF2A13CFCEC" => "ChromeOS Root",
+        "48465300-0000-11AA-AA11-00306543ECAC" => "Apple HFS+",
+        other => return other.to_owned(),
+    }
+    .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINUX_GUID: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+    fn put(data: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Stores a GUID the way [`guid`] reads it back.
+    fn guid_bytes(guid: &str) -> Vec<u8> {
+        let mut bytes = hex::decode(guid.replace('-', "")).expect("Invalid GUID");
+        bytes[..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+    }
+
+    fn mbr(partitions: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut data = vec![0; 512];
+        for (index, (partition_type, start, sectors)) in partitions.iter().enumerate() {
+            let entry = 446 + index * 16;
+            data[entry + 4] = *partition_type;
+            put(&mut data, entry + 8, &start.to_le_bytes());
+            put(&mut data, entry + 12, &sectors.to_le_bytes());
+        }
+        put(&mut data, 510, b"\x55\xAA");
+        data
+    }
+
+    /// Adds a GUID partition table with `partitions` of a type, first and last sector.
+    fn gpt(data: &mut Vec<u8>, sector_size: usize, partitions: &[(&str, u64, u64, &str)]) {
+        put(data, sector_size, b"EFI PART");
+        put(data, sector_size + 72, &2_u64.to_le_bytes());
+        put(data, sector_size + 80, &128_u32.to_le_bytes());
+        put(data, sector_size + 84, &128_u32.to_le_bytes());
+
+        for (index, (type_guid, first, last, label)) in partitions.iter().enumerate() {
+            let entry = 2 * sector_size + index * 128;
+            put(data, entry, &guid_bytes(type_guid));
+            put(data, entry + 32, &first.to_le_bytes());
+            put(data, entry + 40, &last.to_le_bytes());
+            let label = label
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>();
+            put(data, entry + 56, &label);
+        }
+        data.resize(data.len().max(2 * sector_size + 128 * 128), 0);
+    }
+
+    #[test]
+    fn reads_gpt_partitions() {
+        let mut data = mbr(&[(MBR_TYPE_PROTECTIVE, 1, u32::MAX)]);
+        gpt(
+            &mut data,
+            512,
+            &[
+                (ESP_GUID, 2048, 206_847, "EFI System Partition"),
+                (LINUX_GUID, 206_848, 4_194_303, "root"),
+            ],
+        );
+
+        let partitions = gpt_partitions(&data).expect("No partition table");
+
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].number, 1);
+        assert_eq!(partitions[0].kind, "EFI System");
+        assert_eq!(partitions[0].label, "EFI System Partition");
+        assert_eq!(partitions[0].offset, 2048 * 512);
+        assert_eq!(partitions[0].size, 204_800 * 512);
+        assert!(partitions[0].esp);
+        assert_eq!(partitions[1].number, 2);
+        assert_eq!(partitions[1].kind, "Linux Filesystem");
+        assert!(!partitions[1].esp);
+    }
+
+    #[test]
+    fn reads_gpt_partitions_of_4k_sectors() {
+        let mut data = Vec::new();
+        gpt(&mut data, 4096, &[(LINUX_GUID, 256, 511, "")]);
+
+        let partitions = gpt_partitions(&data).expect("No partition table");
+
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].offset, 256 * 4096);
+        assert_eq!(partitions[0].size, 256 * 4096);
+    }
+
+    #[test]
+    fn leaves_out_gpt_partitions_past_the_end() {
+        let mut data = Vec::new();
+        gpt(
+            &mut data,
+            512,
+            &[
+                (LINUX_GUID, u64::MAX / 2, u64::MAX / 2 + 10, "offset"),
+                (LINUX_GUID, 2048, u64::MAX, "size"),
+                (LINUX_GUID, 2048, 4095, "fine"),
+            ],
+        );
+
+        let partitions = gpt_partitions(&data).expect("No partition table");
+
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].label, "fine");
+
+        // Neither is a table that starts past anything addressable
+        put(&mut data, 512 + 72, &u64::MAX.to_le_bytes());
+        assert!(gpt_partitions(&data).is_none());
+    }
+
+    /// Builds the start of an ISO 9660 image whose boot catalog has a
+    /// default entry for `default` and a section for `section`.
+    fn iso(default: (u8, u8), section: Option<(u8, u8)>) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        let primary = 16 * ISO_SECTOR_SIZE;
+        put(&mut data, primary, b"\x01CD001");
+        put(&mut data, primary + 40, b"LIVE_IMAGE                      ");
+
+        let boot_record = 17 * ISO_SECTOR_SIZE;
+        put(&mut data, boot_record, b"\x00CD001");
+        put(&mut data, boot_record + 7, b"EL TORITO SPECIFICATION");
+        put(&mut data, boot_record + 0x47, &20_u32.to_le_bytes());
+
+        put(&mut data, 18 * ISO_SECTOR_SIZE, b"\xFFCD001");
+
+        let catalog = 20 * ISO_SECTOR_SIZE;
+        put(&mut data, catalog, &[0x01, default.0]);
+        put(&mut data, catalog + 30, b"\x55\xAA");
+        put(&mut data, catalog + 32, &[default.1]);
+
+        if let Some((platform_id, boot_indicator)) = section {
+            put(&mut data, catalog + 64, &[0x91, platform_id, 1, 0]);
+            put(&mut data, catalog + 96, &[boot_indicator]);
+        }
+
+        data.resize(catalog + ISO_SECTOR_SIZE, 0);
+        data
+    }
+
+    #[test]
+    fn reads_el_torito_platforms() {
+        assert_eq!(
+            el_torito_platforms(&iso((0x00, 0x88), Some((0xEF, 0x88)))),
+            [Platform::Bios, Platform::Uefi]
+        );
+        assert_eq!(
+            el_torito_platforms(&iso((0x00, 0x00), Some((0xEF, 0x88)))),
+            [Platform::Uefi]
+        );
+        assert_eq!(
+            el_torito_platforms(&iso((0x00, 0x88), None)),
+            [Platform::Bios]
+        );
+        // Other platforms, like Macs, aren't told apart
+        assert!(el_torito_platforms(&iso((0x02, 0x88), None)).is_empty());
+
+        let mut no_catalog = iso((0x00, 0x88), None);
+        put(
+            &mut no_catalog,
+            17 * ISO_SECTOR_SIZE + 0x47,
+            &u32::MAX.to_le_bytes(),
+        );
+        assert!(el_torito_platforms(&no_catalog).is_empty());
+    }
+
+    #[test]
+    fn inspects_hybrid_isos() {
+        let mut data = iso((0x00, 0x88), Some((0xEF, 0x88)));
+        put(&mut data, 0, &[0xEB; 440]);
+        put(
+            &mut data,
+            446,
+            &mbr(&[(0x00, 0, 0), (MBR_TYPE_ESP, 64, 8192)])[446..],
+        );
+
+        let inspection = parse(&data);
+
+        assert_eq!(inspection.iso9660.as_deref(), Some("LIVE_IMAGE"));
+        assert_eq!(inspection.el_torito, [Platform::Bios, Platform::Uefi]);
+        assert_eq!(inspection.partition_table, Some(PartitionTable::Mbr));
+        assert_eq!(inspection.partitions.len(), 1);
+        assert_eq!(inspection.partitions[0].number, 2);
+        assert!(inspection.bios_bootable);
+        assert!(inspection.uefi_bootable);
+    }
+
+    #[test]
+    fn inspects_gpt_images() {
+        let mut data = mbr(&[(MBR_TYPE_PROTECTIVE, 1, u32::MAX)]);
+        gpt(&mut data, 512, &[(ESP_GUID, 34, 33 + 8192, "")]);
+        put(&mut data, 34 * 512 + 0x52, b"FAT32   ");
+
+        let inspection = parse(&data);
+
+        assert_eq!(
+            inspection.partition_table,
+            Some(PartitionTable::Gpt { hybrid: false })
+        );
+        assert_eq!(inspection.partitions[0].filesystem, Some("FAT32"));
+        assert!(!inspection.bios_bootable);
+        assert!(inspection.uefi_bootable);
+
+        put(&mut data, 446 + 16, &mbr(&[(0x0C, 34, 8192)])[446..462]);
+        assert_eq!(
+            parse(&data).partition_table,
+            Some(PartitionTable::Gpt { hybrid: true })
+        );
+    }
+
+    #[test]
+    fn inspects_images_booted_by_firmware() {
+        let mut data = mbr(&[(0x0C, 8192, 524_288), (0x83, 532_480, 4_194_304)]);
+        data.resize(8192 * 512 + 512, 0);
+        put(&mut data, 8192 * 512 + 0x36, b"FAT16   ");
+
+        let inspection = parse(&data);
+
+        assert_eq!(inspection.partition_table, Some(PartitionTable::Mbr));
+        assert_eq!(inspection.partitions[0].kind, "FAT32");
+        assert_eq!(inspection.partitions[0].filesystem, Some("FAT"));
+        assert_eq!(inspection.partitions[1].kind, "Linux");
+        assert!(!inspection.is_bootable());
+        assert!(inspection.has_fat_boot_partition());
+    }
+
+    #[test]
+    fn inspects_plain_file_systems() {
+        let mut data = vec![0; 2048];
+        put(&mut data, 1080, b"\x53\xEF");
+
+        let inspection = parse(&data);
+
+        assert_eq!(inspection.filesystem, Some("ext4"));
+        assert_eq!(inspection.partition_table, None);
+        assert!(!inspection.is_bootable());
+        assert!(!inspection.has_fat_boot_partition());
+    }
+}
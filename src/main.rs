@@ -1,11 +1,26 @@
 mod application;
+mod backup;
+mod bmap;
+mod capacity;
+mod checksum;
 #[rustfmt::skip]
 mod config;
+mod download;
 mod drag_overlay;
+mod duplicator;
 mod flash;
+mod format;
+mod inspect;
 mod online;
+mod probe;
+mod report;
+mod source;
+mod sparse;
+mod vdisk;
 mod widgets;
 mod window;
+mod wipe;
+mod writer;
 
 use gettextrs::{LocaleCategory, gettext};
 use glib::ExitCode;
@@ -0,0 +1,351 @@
+use std::io::Read;
+use std::path::Path;
+
+use crate::sparse;
+use crate::vdisk;
+use crate::window::Compression;
+
+/// Extensions of the archive entries that are offered as disk images.
+const IMAGE_EXTENSIONS: [&str; 5] = ["iso", "img", "raw", "bin", "wic"];
+
+/// Enough of the header to reach the ISO9660 primary volume descriptor.
+const HEADER_SIZE: u64 = 0x8006;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Xz,
+    Gzip,
+    Bzip2,
+    Zstd,
+    Zip,
+    /// Android sparse image, expanded while writing.
+    AndroidSparse,
+    VirtualDisk(vdisk::Format),
+    Iso9660,
+    Gpt,
+    Mbr,
+    Unknown,
+}
+
+impl ImageFormat {
+    /// The compression to undo while writing, if it doesn't depend on more
+    /// than the format, as is the case for archives.
+    pub const fn compression(self) -> Option<Compression> {
+        match self {
+            Self::Xz => Some(Compression::Xz),
+            Self::Gzip => Some(Compression::Gzip),
+            Self::Bzip2 => Some(Compression::Bzip2),
+            Self::Zstd => Some(Compression::Zstd),
+            Self::VirtualDisk(format) => Some(Compression::VirtualDisk(format)),
+            Self::AndroidSparse | Self::Iso9660 | Self::Gpt | Self::Mbr | Self::Unknown => {
+                Some(Compression::Raw)
+            }
+            Self::Zip => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Sniffs the format of the image at `path` from its first bytes.
+pub fn probe(path: &Path) -> std::io::Result<ImageFormat> {
+    let file = std::fs::File::open(path)?;
+    let mut header = Vec::new();
+    (&file).take(HEADER_SIZE).read_to_end(&mut header)?;
+
+    let format = probe_header(&header);
+    if matches!(
+        format,
+        ImageFormat::Iso9660 | ImageFormat::Gpt | ImageFormat::Mbr | ImageFormat::Unknown
+    ) && vdisk::has_vhd_footer(&file)?
+    {
+        // Fixed size VHDs are raw images with a footer
+        return Ok(ImageFormat::VirtualDisk(vdisk::Format::Vhd));
+    }
+
+    Ok(format)
+}
+
+/// Works out how to read the image at `path`, picking the disk image out of
+/// ZIP archives that hold only one.
+pub fn compression(path: &Path) -> std::io::Result<Compression> {
+    if let Some(compression) = probe(path)?.compression() {
+        return Ok(compression);
+    }
+
+    let entries = zip_image_entries(path).map_err(std::io::Error::other)?;
+    match entries.as_slice() {
+        [entry] => Ok(Compression::Zip {
+            entry: entry.name.clone(),
+        }),
+        [] => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "The ZIP archive does not contain a disk image",
+        )),
+        entries => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "The ZIP archive holds {} disk images, open it to choose one",
+                entries.len()
+            ),
+        )),
+    }
+}
+
+pub fn probe_header(header: &[u8]) -> ImageFormat {
+    let has_at = |offset: usize, magic: &[u8]| {
+        header
+            .get(offset..offset + magic.len())
+            .is_some_and(|bytes| bytes == magic)
+    };
+
+    if has_at(0, b"\xFD7zXZ\x00") {
+        ImageFormat::Xz
+    } else if has_at(0, b"\x1F\x8B") {
+        ImageFormat::Gzip
+    } else if has_at(0, b"BZh")
+        && header
+            .get(3)
+            .is_some_and(|level| (b'1'..=b'9').contains(level))
+    {
+        ImageFormat::Bzip2
+    } else if has_at(0, b"\x28\xB5\x2F\xFD") {
+        ImageFormat::Zstd
+    } else if has_at(0, b"PK\x03\x04") {
+        ImageFormat::Zip
+    } else if has_at(0, b"QFI\xFB") {
+        ImageFormat::VirtualDisk(vdisk::Format::Qcow2)
+    } else if has_at(0, b"KDMV") {
+        ImageFormat::VirtualDisk(vdisk::Format::Vmdk)
+    } else if has_at(0, b"vhdxfile") {
+        ImageFormat::VirtualDisk(vdisk::Format::Vhdx)
+    } else if has_at(0, b"conectix") {
+        ImageFormat::VirtualDisk(vdisk::Format::Vhd)
+    } else if has_at(0x40, b"\x7F\x10\xDA\xBE") {
+        ImageFormat::VirtualDisk(vdisk::Format::Vdi)
+    } else if sparse::is_sparse(header) {
+        ImageFormat::AndroidSparse
+    } else if has_at(0x8001, b"CD001") {
+        // Checked before the partition tables, as hybrid ISOs carry an MBR too
+        ImageFormat::Iso9660
+    } else if has_at(512, b"EFI PART") || has_at(4096, b"EFI PART") {
+        ImageFormat::Gpt
+    } else if has_at(510, b"\x55\xAA") {
+        ImageFormat::Mbr
+    } else {
+        ImageFormat::Unknown
+    }
+}
+
+/// Lists the files in the ZIP archive at `path` that look like disk images,
+/// or every file if none of them has a disk image extension.
+pub fn zip_image_entries(path: &Path) -> zip::result::ZipResult<Vec<ZipEntry>> {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index)?;
+        if file.is_file() {
+            files.push(ZipEntry {
+                name: file.name().to_owned(),
+                size: file.size(),
+            });
+        }
+    }
+
+    let images = files
+        .iter()
+        .filter(|entry| {
+            Path::new(&entry.name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+                })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    Ok(if images.is_empty() { files } else { images })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Copies `bytes` into `data` at `offset`, growing it as needed.
+    fn put(data: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// A header holding `magic` at `offset` and zeroes everywhere else.
+    fn header(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 1024];
+        put(&mut data, offset, magic);
+        data
+    }
+
+    /// Calls `f` with the path of a temporary file holding `data`.
+    fn with_file<T>(name: &str, data: &[u8], f: impl FnOnce(&Path) -> T) -> T {
+        let path = std::env::temp_dir().join(format!("impression-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).expect("failed to write the test file");
+
+        let result = f(&path);
+        std::fs::remove_file(&path).expect("failed to remove the test file");
+
+        result
+    }
+
+    /// Builds a ZIP archive holding an empty file for each of `names`.
+    fn zip(names: &[&str]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        writer
+            .add_directory("images/", options)
+            .expect("failed to add the directory");
+        for name in names {
+            writer
+                .start_file(*name, options)
+                .expect("failed to add the file");
+            writer
+                .write_all(name.as_bytes())
+                .expect("failed to write the file");
+        }
+        writer
+            .finish()
+            .expect("failed to finish the archive")
+            .into_inner()
+    }
+
+    fn entry_names(names: &[&str]) -> Vec<String> {
+        with_file(&format!("zip-{}", names.len()), &zip(names), |path| {
+            zip_image_entries(path)
+                .expect("failed to list the entries")
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn probes_compressed_images() {
+        assert_eq!(probe_header(b"\xFD7zXZ\x00\x00\x04"), ImageFormat::Xz);
+        assert_eq!(probe_header(b"\x1F\x8B\x08\x00"), ImageFormat::Gzip);
+        assert_eq!(probe_header(b"BZh91AY&SY"), ImageFormat::Bzip2);
+        assert_eq!(probe_header(b"\x28\xB5\x2F\xFD\x24\x00"), ImageFormat::Zstd);
+        assert_eq!(probe_header(b"PK\x03\x04\x14\x00"), ImageFormat::Zip);
+
+        // Without a block size, it's just text that starts with "BZh"
+        assert_eq!(probe_header(b"BZhello"), ImageFormat::Unknown);
+    }
+
+    #[test]
+    fn probes_virtual_disks() {
+        assert_eq!(
+            probe_header(&header(0, b"QFI\xFB")),
+            ImageFormat::VirtualDisk(vdisk::Format::Qcow2)
+        );
+        assert_eq!(
+            probe_header(&header(0, b"KDMV")),
+            ImageFormat::VirtualDisk(vdisk::Format::Vmdk)
+        );
+        assert_eq!(
+            probe_header(&header(0, b"vhdxfile")),
+            ImageFormat::VirtualDisk(vdisk::Format::Vhdx)
+        );
+        assert_eq!(
+            probe_header(&header(0, b"conectix")),
+            ImageFormat::VirtualDisk(vdisk::Format::Vhd)
+        );
+        assert_eq!(
+            probe_header(&header(0x40, b"\x7F\x10\xDA\xBE")),
+            ImageFormat::VirtualDisk(vdisk::Format::Vdi)
+        );
+        assert_eq!(
+            probe_header(&header(0, &0xED26_FF3A_u32.to_le_bytes())),
+            ImageFormat::AndroidSparse
+        );
+    }
+
+    #[test]
+    fn probes_raw_images() {
+        let mut iso = vec![0; 0x8006];
+        put(&mut iso, 0x8001, b"CD001");
+        assert_eq!(probe_header(&iso), ImageFormat::Iso9660);
+
+        // Hybrid ISOs boot from USB sticks through their MBR
+        put(&mut iso, 510, b"\x55\xAA");
+        assert_eq!(probe_header(&iso), ImageFormat::Iso9660);
+
+        let mut gpt = header(510, b"\x55\xAA");
+        put(&mut gpt, 512, b"EFI PART");
+        assert_eq!(probe_header(&gpt), ImageFormat::Gpt);
+
+        // Drives with 4K sectors keep the GPT header further in
+        let mut gpt = header(510, b"\x55\xAA");
+        put(&mut gpt, 4096, b"EFI PART");
+        assert_eq!(probe_header(&gpt), ImageFormat::Gpt);
+
+        assert_eq!(probe_header(&header(510, b"\x55\xAA")), ImageFormat::Mbr);
+        assert_eq!(probe_header(&header(0, b"")), ImageFormat::Unknown);
+        assert_eq!(probe_header(&[]), ImageFormat::Unknown);
+    }
+
+    #[test]
+    fn probes_vhd_footers() {
+        let mut image = header(510, b"\x55\xAA");
+        put(&mut image, 1024, &[0; 512]);
+        put(&mut image, 1024, b"conectix");
+        let format = with_file("fixed.vhd", &image, probe);
+        assert_eq!(
+            format.expect("failed to probe the image"),
+            ImageFormat::VirtualDisk(vdisk::Format::Vhd)
+        );
+
+        // The cookie only counts in the last sector
+        put(&mut image, 1536, &[0; 512]);
+        let format = with_file("footless.img", &image, probe);
+        assert_eq!(format.expect("failed to probe the image"), ImageFormat::Mbr);
+    }
+
+    #[test]
+    fn lists_zip_images() {
+        assert_eq!(entry_names(&[]), Vec::<String>::new());
+        assert_eq!(
+            entry_names(&["README.txt", "images/disk.img"]),
+            ["images/disk.img"]
+        );
+        assert_eq!(
+            entry_names(&["a.ISO", "README.txt", "b.wic"]),
+            ["a.ISO", "b.wic"]
+        );
+
+        // Without anything that looks like an image, every file is offered
+        assert_eq!(entry_names(&["README.txt", "disk"]), ["README.txt", "disk"]);
+    }
+
+    #[test]
+    fn picks_the_only_zip_image() {
+        let picked = with_file("single.zip", &zip(&["disk.img"]), compression);
+        assert!(matches!(
+            picked.expect("failed to pick the image"),
+            Compression::Zip { entry } if entry == "disk.img"
+        ));
+
+        let picked = with_file("empty.zip", &zip(&[]), compression);
+        assert!(picked.is_err());
+
+        let picked = with_file("several.zip", &zip(&["a.img", "b.img"]), compression);
+        assert!(picked.is_err());
+    }
+}
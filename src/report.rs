@@ -0,0 +1,255 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+use log::{info, warn};
+
+use crate::checksum::Digest;
+use crate::window::DiskImage;
+
+const CSV_HEADER: &str = "started,finished,serial,vendor,model,capacity,image,expected_digest,\
+                          computed_digest,bytes_written,throughput,verification,error";
+
+/// How reading a drive back after writing went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Skipped,
+    Passed,
+    Failed,
+}
+
+impl Verification {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Skipped => "skipped",
+            Self::Passed => "passed",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// What happened when an image was written to a single drive.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub serial: String,
+    pub vendor: String,
+    pub model: String,
+    pub capacity: u64,
+    pub image: String,
+    /// The digest that the image was checked against before writing, if any.
+    pub expected_digest: Option<String>,
+    /// The digest of the image as computed from its file, if it was.
+    pub computed_digest: Option<String>,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub bytes_written: u64,
+    /// Average speed of writing in bytes per second.
+    pub throughput: u64,
+    pub verification: Verification,
+    pub error: Option<String>,
+    write_started: Instant,
+}
+
+impl Entry {
+    /// Starts an entry for writing `source`, with the `computed_digest` of
+    /// its file, to `drive`, which holds `capacity` bytes.
+    pub async fn start(
+        source: &DiskImage,
+        computed_digest: Option<&Digest>,
+        drive: &udisks::drive::DriveProxy<'_>,
+        capacity: u64,
+    ) -> Self {
+        let now = Local::now();
+
+        Self {
+            serial: drive.serial().await.unwrap_or_default(),
+            vendor: drive.vendor().await.unwrap_or_default().trim().to_owned(),
+            model: drive.model().await.unwrap_or_default().trim().to_owned(),
+            capacity,
+            image: image_name(source),
+            expected_digest: match source {
+                DiskImage::Local {
+                    digest: Some(digest),
+                    ..
+                } => Some(digest.to_string()),
+                _ => None,
+            },
+            computed_digest: computed_digest.map(ToString::to_string),
+            started: now,
+            finished: now,
+            bytes_written: 0,
+            throughput: 0,
+            verification: Verification::Skipped,
+            error: None,
+            write_started: Instant::now(),
+        }
+    }
+
+    /// Notes that the drive is ready and writing to it starts now.
+    pub fn begin_writing(&mut self) {
+        self.write_started = Instant::now();
+    }
+
+    /// Notes that writing `bytes` to the drive is done.
+    pub fn written(&mut self, bytes: u64) {
+        let seconds = self.write_started.elapsed().as_secs_f64();
+
+        self.bytes_written = bytes;
+        if seconds > 0.0 {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let throughput = (bytes as f64 / seconds) as u64;
+            self.throughput = throughput;
+        }
+    }
+
+    /// Adds the entry to the report of this session, as finished now.
+    pub fn record(&self, verification: Verification, error: Option<String>) {
+        let entry = Self {
+            finished: Local::now(),
+            verification,
+            error,
+            ..self.clone()
+        };
+
+        if let Err(e) = append(&entry) {
+            warn!(
+                "Failed to add to the report at {}: {e}",
+                session_path().display()
+            );
+        }
+
+        if let Ok(mut entries) = session().lock() {
+            entries.push(entry);
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        [
+            self.started.to_rfc3339(),
+            self.finished.to_rfc3339(),
+            self.serial.clone(),
+            self.vendor.clone(),
+            self.model.clone(),
+            self.capacity.to_string(),
+            self.image.clone(),
+            self.expected_digest.clone().unwrap_or_default(),
+            self.computed_digest.clone().unwrap_or_default(),
+            self.bytes_written.to_string(),
+            self.throughput.to_string(),
+            self.verification.as_str().to_owned(),
+            self.error.clone().unwrap_or_default(),
+        ]
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "started": self.started.to_rfc3339(),
+            "finished": self.finished.to_rfc3339(),
+            "serial": self.serial,
+            "vendor": self.vendor,
+            "model": self.model,
+            "capacity": self.capacity,
+            "image": self.image,
+            "expected_digest": self.expected_digest,
+            "computed_digest": self.computed_digest,
+            "bytes_written": self.bytes_written,
+            "throughput": self.throughput,
+            "verification": self.verification.as_str(),
+            "error": self.error,
+        })
+        .to_string()
+    }
+}
+
+fn session() -> &'static Mutex<Vec<Entry>> {
+    static SESSION: OnceLock<Mutex<Vec<Entry>>> = OnceLock::new();
+    SESSION.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Where the entries of this session are appended to as they are recorded,
+/// so that they are kept even if the report is never exported.
+fn session_path() -> &'static Path {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+    PATH.get_or_init(|| {
+        glib::user_data_dir()
+            .join("impression")
+            .join("reports")
+            .join(format!("{}.jsonl", Local::now().format("%Y-%m-%d-%H%M%S")))
+    })
+}
+
+fn append(entry: &Entry) -> std::io::Result<()> {
+    let path = session_path();
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        info!("Keeping the report of this session at {}", path.display());
+    }
+
+    writeln!(file, "{}", entry.to_json())
+}
+
+/// Whether no drive was written to since the app was started.
+pub fn is_empty() -> bool {
+    session().lock().map_or(true, |entries| entries.is_empty())
+}
+
+/// Saves the report of this session to `path`, as JSON lines if its
+/// extension asks for that and as CSV otherwise.
+pub fn export(path: &Path) -> std::io::Result<()> {
+    let entries = session()
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?
+        .clone();
+
+    let json = path
+        .extension()
+        .is_some_and(|extension| extension == "jsonl" || extension == "json");
+
+    let mut contents = String::new();
+    if !json {
+        contents.push_str(CSV_HEADER);
+        contents.push('\n');
+    }
+
+    for entry in &entries {
+        contents.push_str(&if json {
+            entry.to_json()
+        } else {
+            entry.to_csv()
+        });
+        contents.push('\n');
+    }
+
+    std::fs::write(path, contents)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn image_name(source: &DiskImage) -> String {
+    match source {
+        DiskImage::Local { path, .. } => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        DiskImage::Online { name, .. } | DiskImage::Drive { name, .. } => name.clone(),
+    }
+}
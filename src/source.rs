@@ -0,0 +1,300 @@
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::mpsc;
+
+use crate::flash::{FlashPhase, Progress};
+use crate::probe;
+use crate::vdisk::VirtualDiskReader;
+use crate::window::Compression;
+
+/// Amount of decoded image data handed to the writer at once.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of chunks the reading thread may get ahead of the writer.
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+type ChunkSender = mpsc::Sender<std::io::Result<Vec<u8>>>;
+
+/// Wraps a reader and counts how many bytes were read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let x = self.inner.read(buf)?;
+        self.count.fetch_add(x as u64, Ordering::Relaxed);
+        Ok(x)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Reads the bytes pushed through a [`DownloadSink`], blocking until they arrive.
+struct ChannelReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+    count: Arc<AtomicU64>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            let Some(chunk) = self.receiver.blocking_recv() else {
+                return Ok(0);
+            };
+            self.chunk = chunk;
+            self.position = 0;
+        }
+
+        let x = std::cmp::min(buf.len(), self.chunk.len() - self.position);
+        buf[..x].copy_from_slice(&self.chunk[self.position..self.position + x]);
+        self.position += x;
+        self.count.fetch_add(x as u64, Ordering::Relaxed);
+        Ok(x)
+    }
+}
+
+/// Feeds the bytes of an image that is still being downloaded into an [`ImageStream`].
+pub struct DownloadSink {
+    sender: mpsc::Sender<Vec<u8>>,
+    downloaded: Arc<AtomicU64>,
+}
+
+impl DownloadSink {
+    /// Hands the next downloaded bytes over, returning `false` if nobody reads them anymore.
+    pub async fn send(&self, bytes: Vec<u8>) -> bool {
+        self.downloaded
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        self.sender.send(bytes).await.is_ok()
+    }
+}
+
+/// Image data decoded on a blocking thread and received chunk by chunk.
+///
+/// Progress is measured on the source side, so for compressed images it
+/// follows the compressed bytes consumed rather than the bytes produced.
+/// Virtual disks are read out of order and follow the raw bytes produced.
+pub struct ImageStream {
+    receiver: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    consumed: Arc<AtomicU64>,
+    /// Bytes downloaded so far, for images written while downloading.
+    downloaded: Option<Arc<AtomicU64>>,
+    /// Size of the source in bytes, or 0 if it isn't known.
+    size: u64,
+}
+
+impl ImageStream {
+    /// Opens the image at `path`, decompressing it on the fly if needed.
+    pub fn open(path: &Path, compression: &Compression) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let consumed = Arc::new(AtomicU64::new(0));
+
+        if let Compression::VirtualDisk(format) = compression {
+            let disk = VirtualDiskReader::open(file, *format)?;
+            let size = disk.size();
+            let reader = CountingReader {
+                inner: disk,
+                count: consumed.clone(),
+            };
+            return Ok(Self::spawn(reader, consumed, size));
+        }
+
+        let size = file.metadata()?.len();
+        let reader = CountingReader {
+            inner: file,
+            count: consumed.clone(),
+        };
+
+        if let Compression::Zip { entry } = compression {
+            let mut archive = zip::ZipArchive::new(reader)?;
+            let index = archive.index_for_name(entry).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{entry} not found in archive"),
+                )
+            })?;
+
+            return Ok(Self::spawn_with(
+                consumed,
+                size,
+                move |sender| match archive.by_index(index) {
+                    Ok(file) => send_chunks(file, sender),
+                    Err(e) => {
+                        sender.blocking_send(Err(e.into())).ok();
+                    }
+                },
+            ));
+        }
+
+        Ok(Self::spawn(
+            decompress(reader, compression)?,
+            consumed,
+            size,
+        ))
+    }
+
+    /// Reads the first `size` bytes of a drive as they are.
+    pub fn from_drive(file: std::fs::File, size: u64) -> Self {
+        Self::from_reader(file, size)
+    }
+
+    /// Reads the first `size` bytes of `reader` as they are.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, size: u64) -> Self {
+        let consumed = Arc::new(AtomicU64::new(0));
+        let reader = CountingReader {
+            inner: reader.take(size),
+            count: consumed.clone(),
+        };
+
+        Self::spawn(reader, consumed, size)
+    }
+
+    /// Creates a stream of an image of `size` bytes that is being downloaded,
+    /// or of unknown size if the server didn't tell.
+    ///
+    /// The compression is sniffed from the first bytes that arrive.
+    pub fn from_download(size: Option<u64>) -> (Self, DownloadSink) {
+        let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+
+        let consumed = Arc::new(AtomicU64::new(0));
+        let downloaded = Arc::new(AtomicU64::new(0));
+
+        let reader = ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+            count: consumed.clone(),
+        };
+
+        let mut stream = Self::spawn_with(consumed, size.unwrap_or_default(), move |sender| {
+            let mut reader = std::io::BufReader::with_capacity(CHUNK_SIZE, reader);
+
+            // Archives list their entries at the end, which hasn't arrived yet
+            let compression = std::io::BufRead::fill_buf(&mut reader).and_then(|header| {
+                probe::probe_header(header).compression().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "ZIP archives can only be written once they are downloaded, \
+                         turn off writing while downloading to write this one",
+                    )
+                })
+            });
+            let compression = match compression {
+                Ok(compression) => compression,
+                Err(e) => {
+                    sender.blocking_send(Err(e)).ok();
+                    return;
+                }
+            };
+
+            match decompress(reader, &compression) {
+                Ok(reader) => send_chunks(reader, sender),
+                Err(e) => {
+                    sender.blocking_send(Err(e)).ok();
+                }
+            }
+        });
+        stream.downloaded = Some(downloaded.clone());
+
+        (stream, DownloadSink { sender, downloaded })
+    }
+
+    fn spawn<R: Read + Send + 'static>(reader: R, consumed: Arc<AtomicU64>, size: u64) -> Self {
+        Self::spawn_with(consumed, size, move |sender| send_chunks(reader, sender))
+    }
+
+    fn spawn_with<F: FnOnce(&ChunkSender) + Send + 'static>(
+        consumed: Arc<AtomicU64>,
+        size: u64,
+        produce: F,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+
+        tokio::task::spawn_blocking(move || produce(&sender));
+
+        Self {
+            receiver,
+            consumed,
+            downloaded: None,
+            size,
+        }
+    }
+
+    /// Returns the next chunk of image data, or `None` once the image is exhausted.
+    pub async fn next_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        match self.receiver.recv().await? {
+            Ok(chunk) if chunk.is_empty() => None,
+            result => Some(result),
+        }
+    }
+
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress::from((self.consumed.load(Ordering::Relaxed), self.size))
+    }
+
+    pub fn phase(&self) -> FlashPhase {
+        self.downloaded
+            .as_ref()
+            .map_or(FlashPhase::Copy, |downloaded| FlashPhase::DownloadAndCopy {
+                downloaded: downloaded.load(Ordering::Relaxed),
+                total: (self.size > 0).then_some(self.size),
+            })
+    }
+}
+
+/// Reads `reader` to the end on the current thread, sending it chunk by chunk.
+fn send_chunks<R: Read>(mut reader: R, sender: &ChunkSender) {
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        let result = (&mut reader)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)
+            .map(|_| chunk);
+
+        let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+
+        // Sending only fails once the writer is gone, so there is no one left to read for
+        if sender.blocking_send(result).is_err() || done {
+            break;
+        }
+    }
+}
+
+fn decompress<R: Read + Send + 'static>(
+    reader: R,
+    compression: &Compression,
+) -> std::io::Result<Box<dyn Read + Send>> {
+    Ok(match compression {
+        Compression::Raw => Box::new(reader),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Zip { .. } => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "ZIP archives can only be read from a file",
+            ));
+        }
+        Compression::VirtualDisk(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Virtual disks can only be read from a file",
+            ));
+        }
+    })
+}
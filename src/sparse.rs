@@ -0,0 +1,517 @@
+use log::info;
+use terrors::OneOf;
+
+use crate::writer::FanOut;
+
+/// Magic number at the start of Android sparse images, in little endian.
+const MAGIC: [u8; 4] = 0xED26_FF3A_u32.to_le_bytes();
+
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xCAC1;
+const CHUNK_TYPE_FILL: u16 = 0xCAC2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_TYPE_CRC32: u16 = 0xCAC4;
+
+/// Size of the buffers that fill chunks are expanded into.
+const FILL_BUFFER_SIZE: usize = 1024 * 1024;
+
+static ZEROES: [u8; 64 * 1024] = [0; 64 * 1024];
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid Android sparse image: {0}")]
+pub struct InvalidSparseImage(String);
+
+pub fn is_sparse(header: &[u8]) -> bool {
+    header.starts_with(&MAGIC)
+}
+
+/// Size of the image that the sparse image with `header` expands to.
+pub fn expanded_size(header: &[u8]) -> Option<u64> {
+    (is_sparse(header) && header.len() >= FILE_HEADER_SIZE)
+        .then(|| u64::from(u32_at(header, 12)) * u64::from(u32_at(header, 16)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    FileHeader,
+    ChunkHeader,
+    /// Skipping the part of a header that this version doesn't know about.
+    Skip {
+        left: usize,
+    },
+    Raw {
+        left: u64,
+    },
+    FillValue {
+        len: u64,
+    },
+    CrcValue,
+    Done,
+}
+
+/// Expands an Android sparse image onto a drive as it is read, chunk by chunk.
+pub struct SparseExpander {
+    state: State,
+    /// Bytes of a header or value that is split between two reads.
+    pending: Vec<u8>,
+    block_size: u64,
+    chunk_header_size: usize,
+    chunks_left: u32,
+    total_size: u64,
+    /// Where the next chunk lands in the expanded image.
+    offset: u64,
+    /// CRC32 of the expanded image so far, with skipped blocks as zeroes.
+    crc: crc32fast::Hasher,
+}
+
+impl SparseExpander {
+    pub fn new() -> Self {
+        Self {
+            state: State::FileHeader,
+            pending: Vec::new(),
+            block_size: 0,
+            chunk_header_size: CHUNK_HEADER_SIZE,
+            chunks_left: 0,
+            total_size: 0,
+            offset: 0,
+            crc: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Expands the next `data` read from the sparse image onto `writer`.
+    pub async fn feed(
+        &mut self,
+        mut data: &[u8],
+        writer: &mut FanOut,
+    ) -> Result<(), OneOf<(std::io::Error, InvalidSparseImage)>> {
+        while !data.is_empty() {
+            match self.state {
+                State::FileHeader => {
+                    if let Some(header) = self.take(&mut data, FILE_HEADER_SIZE) {
+                        self.parse_file_header(&header).map_err(OneOf::new)?;
+                    }
+                }
+                State::ChunkHeader => {
+                    if let Some(header) = self.take(&mut data, self.chunk_header_size) {
+                        self.parse_chunk_header(&header).map_err(OneOf::new)?;
+                    }
+                }
+                State::Skip { left } => {
+                    let len = left.min(data.len());
+                    data = &data[len..];
+                    self.state = if len == left {
+                        self.next_chunk()
+                    } else {
+                        State::Skip { left: left - len }
+                    };
+                }
+                State::Raw { left } => {
+                    #[allow(clippy::cast_possible_truncation)]
+                    let len = left.min(data.len() as u64) as usize;
+                    let (raw, rest) = data.split_at(len);
+                    data = rest;
+
+                    writer
+                        .write_at(self.offset, raw)
+                        .await
+                        .map_err(OneOf::new)?;
+                    self.crc.update(raw);
+                    self.offset += len as u64;
+
+                    if len as u64 == left {
+                        self.finish_chunk();
+                    } else {
+                        self.state = State::Raw {
+                            left: left - len as u64,
+                        };
+                    }
+                }
+                State::FillValue { len } => {
+                    if let Some(value) = self.take(&mut data, 4) {
+                        self.fill(&value, len, writer).await.map_err(OneOf::new)?;
+                        self.finish_chunk();
+                    }
+                }
+                State::CrcValue => {
+                    if let Some(value) = self.take(&mut data, 4) {
+                        let expected = u32_at(&value, 0);
+                        let computed = self.crc.clone().finalize();
+                        if expected != computed {
+                            return Err(OneOf::new(InvalidSparseImage(format!(
+                                "checksum mismatch before offset {}",
+                                self.offset
+                            ))));
+                        }
+                        self.finish_chunk();
+                    }
+                }
+                State::Done => {
+                    return Err(OneOf::new(InvalidSparseImage(
+                        "data after the last chunk".to_owned(),
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the whole image was read, returning its expanded size.
+    pub fn finish(&self) -> Result<u64, InvalidSparseImage> {
+        if self.state != State::Done {
+            return Err(InvalidSparseImage("the image is truncated".to_owned()));
+        }
+
+        if self.offset != self.total_size {
+            return Err(InvalidSparseImage(format!(
+                "the chunks describe {} bytes, but the header {} bytes",
+                self.offset, self.total_size
+            )));
+        }
+
+        Ok(self.total_size)
+    }
+
+    /// Moves bytes from `data` until `len` of them are pending and returns them.
+    fn take(&mut self, data: &mut &[u8], len: usize) -> Option<Vec<u8>> {
+        let missing = (len - self.pending.len()).min(data.len());
+        self.pending.extend_from_slice(&data[..missing]);
+        *data = &data[missing..];
+
+        (self.pending.len() == len).then(|| std::mem::take(&mut self.pending))
+    }
+
+    fn parse_file_header(&mut self, header: &[u8]) -> Result<(), InvalidSparseImage> {
+        let major_version = u16_at(header, 4);
+        let file_header_size = usize::from(u16_at(header, 8));
+        let chunk_header_size = usize::from(u16_at(header, 10));
+        let block_size = u32_at(header, 12);
+        let total_blocks = u32_at(header, 16);
+
+        if !is_sparse(header) || major_version != 1 {
+            return Err(InvalidSparseImage(format!(
+                "unsupported version {major_version}"
+            )));
+        }
+
+        if file_header_size < FILE_HEADER_SIZE
+            || chunk_header_size < CHUNK_HEADER_SIZE
+            || block_size == 0
+            || !block_size.is_multiple_of(4)
+        {
+            return Err(InvalidSparseImage("invalid header".to_owned()));
+        }
+
+        self.block_size = u64::from(block_size);
+        self.chunk_header_size = chunk_header_size;
+        self.chunks_left = u32_at(header, 20);
+        self.total_size = u64::from(total_blocks) * self.block_size;
+
+        info!(
+            "Expanding Android sparse image of {} bytes in {} chunks",
+            self.total_size, self.chunks_left
+        );
+
+        self.state = match file_header_size - FILE_HEADER_SIZE {
+            0 => self.next_chunk(),
+            left => State::Skip { left },
+        };
+
+        Ok(())
+    }
+
+    fn parse_chunk_header(&mut self, header: &[u8]) -> Result<(), InvalidSparseImage> {
+        let chunk_type = u16_at(header, 0);
+        let len = u64::from(u32_at(header, 4)) * self.block_size;
+        let body_size = u64::from(u32_at(header, 8)).checked_sub(self.chunk_header_size as u64);
+
+        let expected_body_size = match chunk_type {
+            CHUNK_TYPE_RAW => len,
+            CHUNK_TYPE_FILL | CHUNK_TYPE_CRC32 => 4,
+            CHUNK_TYPE_DONT_CARE => 0,
+            _ => {
+                return Err(InvalidSparseImage(format!(
+                    "unknown chunk type {chunk_type:#X}"
+                )));
+            }
+        };
+
+        if body_size != Some(expected_body_size) || self.offset + len > self.total_size {
+            return Err(InvalidSparseImage(format!(
+                "invalid chunk at offset {}",
+                self.offset
+            )));
+        }
+
+        match chunk_type {
+            CHUNK_TYPE_RAW if len > 0 => self.state = State::Raw { left: len },
+            CHUNK_TYPE_FILL => self.state = State::FillValue { len },
+            CHUNK_TYPE_CRC32 => self.state = State::CrcValue,
+            CHUNK_TYPE_DONT_CARE => {
+                // What is on the drive stays, but checksums count these blocks as zeroes
+                let mut left = len;
+                while left > 0 {
+                    #[allow(clippy::cast_possible_truncation)]
+                    let zeroes = left.min(ZEROES.len() as u64) as usize;
+                    self.crc.update(&ZEROES[..zeroes]);
+                    left -= zeroes as u64;
+                }
+                self.offset += len;
+                self.finish_chunk();
+            }
+            _ => self.finish_chunk(),
+        }
+
+        Ok(())
+    }
+
+    async fn fill(&mut self, value: &[u8], len: u64, writer: &mut FanOut) -> std::io::Result<()> {
+        #[allow(clippy::cast_possible_truncation)]
+        let buffer_size = len.min(FILL_BUFFER_SIZE as u64) as usize;
+        let buffer = value
+            .iter()
+            .cycle()
+            .take(buffer_size)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let end = self.offset + len;
+        while self.offset < end {
+            #[allow(clippy::cast_possible_truncation)]
+            let data = &buffer[..(end - self.offset).min(buffer_size as u64) as usize];
+            writer.write_at(self.offset, data).await?;
+            self.crc.update(data);
+            self.offset += data.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    const fn finish_chunk(&mut self) {
+        self.chunks_left -= 1;
+        self.state = self.next_chunk();
+    }
+
+    const fn next_chunk(&self) -> State {
+        if self.chunks_left == 0 {
+            State::Done
+        } else {
+            State::ChunkHeader
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::writer::Message;
+
+    use super::*;
+
+    const BLOCK_SIZE: u32 = 8;
+
+    fn file_header(total_blocks: u32, chunks: u32) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&1_u16.to_le_bytes());
+        header.extend_from_slice(&0_u16.to_le_bytes());
+        header.extend_from_slice(&28_u16.to_le_bytes());
+        header.extend_from_slice(&12_u16.to_le_bytes());
+        header.extend_from_slice(&BLOCK_SIZE.to_le_bytes());
+        header.extend_from_slice(&total_blocks.to_le_bytes());
+        header.extend_from_slice(&chunks.to_le_bytes());
+        header.extend_from_slice(&0_u32.to_le_bytes());
+        header
+    }
+
+    fn chunk(chunk_type: u16, blocks: u32, body: &[u8]) -> Vec<u8> {
+        let mut chunk = chunk_type.to_le_bytes().to_vec();
+        chunk.extend_from_slice(&0_u16.to_le_bytes());
+        chunk.extend_from_slice(&blocks.to_le_bytes());
+        #[allow(clippy::cast_possible_truncation)]
+        chunk.extend_from_slice(&(CHUNK_HEADER_SIZE as u32 + body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    /// Feeds `parts` of a sparse image to an expander writing onto `drive`,
+    /// returning the expanded size and what ended up on the drive.
+    fn expand(parts: &[&[u8]], mut drive: Vec<u8>) -> (Result<u64, String>, Vec<u8>) {
+        let (mut fan_out, mut inboxes) = FanOut::new(1);
+        let mut inbox = inboxes.pop().expect("No inbox");
+
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build runtime")
+            .block_on(async {
+                let (result, ()) = futures::join!(
+                    async {
+                        let mut expander = SparseExpander::new();
+                        let mut result = Ok(());
+                        for part in parts {
+                            result = expander.feed(part, &mut fan_out).await;
+                            if result.is_err() {
+                                break;
+                            }
+                        }
+                        drop(fan_out);
+
+                        result
+                            .map_err(|e| e.to_string())
+                            .and_then(|()| expander.finish().map_err(|e| e.to_string()))
+                    },
+                    async {
+                        while let Some(message) = inbox.recv().await {
+                            if let Message::Write(offset, data) = message {
+                                #[allow(clippy::cast_possible_truncation)]
+                                let offset = offset as usize;
+                                drive[offset..offset + data.len()].copy_from_slice(&data);
+                            }
+                        }
+                    }
+                );
+                result
+            });
+
+        (result, drive)
+    }
+
+    fn checksum(data: &[u8]) -> Vec<u8> {
+        crc32fast::hash(data).to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn expands_every_chunk_type() {
+        let raw = b"rawdata!";
+        let mut expanded = raw.to_vec();
+        expanded.extend_from_slice(&[0xAB, 0xCD, 0xEF, 0x01].repeat(4));
+        expanded.extend_from_slice(&[0; 8]);
+
+        let image = [
+            file_header(4, 4),
+            chunk(CHUNK_TYPE_RAW, 1, raw),
+            chunk(CHUNK_TYPE_FILL, 2, &[0xAB, 0xCD, 0xEF, 0x01]),
+            chunk(CHUNK_TYPE_DONT_CARE, 1, &[]),
+            chunk(CHUNK_TYPE_CRC32, 0, &checksum(&expanded)),
+        ]
+        .concat();
+
+        let (result, drive) = expand(&[&image], vec![0xEE; 32]);
+
+        assert_eq!(result, Ok(32));
+        assert_eq!(drive[..24], expanded[..24]);
+        // Blocks that don't matter keep what was on the drive
+        assert_eq!(drive[24..], [0xEE; 8]);
+    }
+
+    #[test]
+    fn expands_chunks_split_across_reads() {
+        let raw = b"0123456789abcdef";
+        let image = [
+            file_header(3, 2),
+            chunk(CHUNK_TYPE_RAW, 2, raw),
+            chunk(CHUNK_TYPE_FILL, 1, &[1, 2, 3, 4]),
+        ]
+        .concat();
+
+        // Split inside the file header, a chunk header, raw data and the fill value
+        let parts = [
+            &image[..5],
+            &image[5..34],
+            &image[34..50],
+            &image[50..70],
+            &image[70..],
+        ];
+        let (result, drive) = expand(&parts, vec![0; 24]);
+
+        assert_eq!(result, Ok(24));
+        assert_eq!(drive[..16], raw[..]);
+        assert_eq!(drive[16..], [1, 2, 3, 4, 1, 2, 3, 4]);
+
+        let bytes = image.iter().map(std::slice::from_ref).collect::<Vec<_>>();
+        assert_eq!(expand(&bytes, vec![0; 24]), (Ok(24), drive));
+    }
+
+    #[test]
+    fn rejects_checksum_mismatches() {
+        let image = [
+            file_header(1, 2),
+            chunk(CHUNK_TYPE_RAW, 1, b"rawdata!"),
+            chunk(CHUNK_TYPE_CRC32, 0, &checksum(b"somethin")),
+        ]
+        .concat();
+
+        let (result, _) = expand(&[&image], vec![0; 8]);
+
+        assert_eq!(
+            result,
+            Err("Invalid Android sparse image: checksum mismatch before offset 8".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut version_2 = file_header(1, 1);
+        version_2[4] = 2;
+        let (result, _) = expand(&[&version_2], vec![0; 8]);
+        assert_eq!(
+            result,
+            Err("Invalid Android sparse image: unsupported version 2".to_owned())
+        );
+
+        let mut odd_block_size = file_header(1, 1);
+        odd_block_size[12] = 7;
+        let (result, _) = expand(&[&odd_block_size], vec![0; 8]);
+        assert_eq!(
+            result,
+            Err("Invalid Android sparse image: invalid header".to_owned())
+        );
+
+        let unknown_chunk = [file_header(1, 1), chunk(0xCAC5, 1, &[])].concat();
+        let (result, _) = expand(&[&unknown_chunk], vec![0; 8]);
+        assert_eq!(
+            result,
+            Err("Invalid Android sparse image: unknown chunk type 0xCAC5".to_owned())
+        );
+
+        let too_large = [file_header(1, 1), chunk(CHUNK_TYPE_DONT_CARE, 2, &[])].concat();
+        let (result, _) = expand(&[&too_large], vec![0; 8]);
+        assert_eq!(
+            result,
+            Err("Invalid Android sparse image: invalid chunk at offset 0".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        let image = [file_header(2, 2), chunk(CHUNK_TYPE_FILL, 1, &[0; 4])].concat();
+
+        let (result, _) = expand(&[&image], vec![0; 16]);
+
+        assert_eq!(
+            result,
+            Err("Invalid Android sparse image: the image is truncated".to_owned())
+        );
+    }
+
+    #[test]
+    fn tells_the_expanded_size() {
+        assert_eq!(expanded_size(&file_header(3, 1)), Some(24));
+        assert_eq!(expanded_size(&file_header(3, 1)[..20]), None);
+        assert_eq!(expanded_size(&[0; 28]), None);
+    }
+}
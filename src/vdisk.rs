@@ -0,0 +1,1074 @@
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+
+use log::info;
+
+/// Tables larger than this are taken as a sign of a corrupted image.
+const MAX_TABLE_SIZE: u64 = 256 * 1024 * 1024;
+
+const VHD_COOKIE: &[u8] = b"conectix";
+
+const VHDX_BAT_REGION: [u8; 16] = [
+    0x66, 0x77, 0xC2, 0x2D, 0x23, 0xF6, 0x00, 0x42, 0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08,
+];
+const VHDX_METADATA_REGION: [u8; 16] = [
+    0x06, 0xA2, 0x7C, 0x8B, 0x90, 0x47, 0x9A, 0x4B, 0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E,
+];
+const VHDX_FILE_PARAMETERS: [u8; 16] = [
+    0x37, 0x67, 0xA1, 0xCA, 0x36, 0xFA, 0x43, 0x4D, 0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B,
+];
+const VHDX_VIRTUAL_DISK_SIZE: [u8; 16] = [
+    0x24, 0x42, 0xA5, 0x2F, 0x1B, 0xCD, 0x76, 0x48, 0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8,
+];
+const VHDX_LOGICAL_SECTOR_SIZE: [u8; 16] = [
+    0x1D, 0xBF, 0x41, 0x81, 0x6F, 0xA9, 0x09, 0x47, 0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F,
+];
+
+/// Virtual machine disk formats that are converted to a raw image while writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Qcow2,
+    Vmdk,
+    Vhd,
+    Vhdx,
+    Vdi,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid virtual disk: {0}")]
+pub struct InvalidVirtualDisk(String);
+
+fn invalid(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        InvalidVirtualDisk(message.into()),
+    )
+}
+
+fn unsupported(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{what} are not supported"),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Codec {
+    Deflate,
+    Zlib,
+    Zstd,
+}
+
+/// Where the data of a cluster of the virtual disk is found in its file.
+enum Cluster {
+    /// Not allocated, so it reads as zeroes.
+    Zero,
+    /// Stored as is from this offset on.
+    Data(u64),
+    /// Compressed into `len` bytes at `offset`.
+    Compressed { offset: u64, len: u64, codec: Codec },
+}
+
+/// How a format maps the clusters of the virtual disk to its file.
+trait Layout: Send {
+    /// Size of the virtual disk in bytes.
+    fn size(&self) -> u64;
+
+    /// Granularity at which the virtual disk is allocated.
+    fn cluster_size(&self) -> u64;
+
+    fn locate(&mut self, file: &File, cluster: u64) -> std::io::Result<Cluster>;
+}
+
+/// Reads the disk as seen by the virtual machine, as a raw image.
+pub struct VirtualDiskReader {
+    file: File,
+    layout: Box<dyn Layout>,
+    position: u64,
+    /// The cluster decompressed last, as reads rarely cover a whole one.
+    decompressed: Option<(u64, Vec<u8>)>,
+}
+
+impl VirtualDiskReader {
+    pub fn open(file: File, format: Format) -> std::io::Result<Self> {
+        let layout: Box<dyn Layout> = match format {
+            Format::Qcow2 => Box::new(Qcow2::open(&file)?),
+            Format::Vmdk => Box::new(Vmdk::open(&file)?),
+            Format::Vhd => Box::new(Vhd::open(&file)?),
+            Format::Vhdx => Box::new(Vhdx::open(&file)?),
+            Format::Vdi => Box::new(Vdi::open(&file)?),
+        };
+
+        info!(
+            "Converting {format:?} image of {} bytes in clusters of {} bytes",
+            layout.size(),
+            layout.cluster_size()
+        );
+
+        Ok(Self {
+            file,
+            layout,
+            position: 0,
+            decompressed: None,
+        })
+    }
+
+    /// Size of the raw image.
+    pub fn size(&self) -> u64 {
+        self.layout.size()
+    }
+}
+
+impl Read for VirtualDiskReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.layout.size();
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let cluster_size = self.layout.cluster_size();
+        let cluster = self.position / cluster_size;
+        let within = self.position % cluster_size;
+
+        #[allow(clippy::cast_possible_truncation)]
+        let len = (cluster_size - within)
+            .min(size - self.position)
+            .min(buf.len() as u64) as usize;
+        let buf = &mut buf[..len];
+
+        match self.layout.locate(&self.file, cluster)? {
+            Cluster::Zero => buf.fill(0),
+            Cluster::Data(offset) => self.file.read_exact_at(buf, offset + within)?,
+            Cluster::Compressed { offset, len, codec } => {
+                let data = match self.decompressed.take() {
+                    Some((index, data)) if index == cluster => data,
+                    _ => decompress(&self.file, offset, len, codec, cluster_size)?,
+                };
+
+                #[allow(clippy::cast_possible_truncation)]
+                buf.copy_from_slice(&data[within as usize..within as usize + buf.len()]);
+                self.decompressed = Some((cluster, data));
+            }
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+/// Checks for the footer that fixed size VHDs, which have no header, end with.
+pub fn has_vhd_footer(file: &File) -> std::io::Result<bool> {
+    let len = file.metadata()?.len();
+    if len < 512 {
+        return Ok(false);
+    }
+
+    let mut cookie = [0; VHD_COOKIE.len()];
+    file.read_exact_at(&mut cookie, len - 512)?;
+    Ok(cookie == VHD_COOKIE)
+}
+
+fn decompress(
+    file: &File,
+    offset: u64,
+    len: u64,
+    codec: Codec,
+    cluster_size: u64,
+) -> std::io::Result<Vec<u8>> {
+    if len > MAX_TABLE_SIZE {
+        return Err(invalid(format!(
+            "compressed cluster at {offset} is too big"
+        )));
+    }
+
+    // The length can be rounded up past the end of the file
+    let mut compressed = Vec::new();
+    ReadAt { file, offset }
+        .take(len)
+        .read_to_end(&mut compressed)?;
+
+    let decoder: Box<dyn Read + '_> = match codec {
+        Codec::Deflate => Box::new(flate2::read::DeflateDecoder::new(compressed.as_slice())),
+        Codec::Zlib => Box::new(flate2::read::ZlibDecoder::new(compressed.as_slice())),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(compressed.as_slice())?.single_frame()),
+    };
+
+    #[allow(clippy::cast_possible_truncation)]
+    let mut data = Vec::with_capacity(cluster_size as usize);
+    decoder.take(cluster_size).read_to_end(&mut data)?;
+    #[allow(clippy::cast_possible_truncation)]
+    data.resize(cluster_size as usize, 0);
+
+    Ok(data)
+}
+
+/// Reads a file sequentially from `offset` without moving its cursor.
+struct ReadAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let x = self.file.read_at(buf, self.offset)?;
+        self.offset += x as u64;
+        Ok(x)
+    }
+}
+
+fn read_table(file: &File, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    if len > MAX_TABLE_SIZE {
+        return Err(invalid(format!("table at {offset} is too big")));
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    let mut table = vec![0; len as usize];
+    file.read_exact_at(&mut table, offset)?;
+    Ok(table)
+}
+
+struct Qcow2 {
+    size: u64,
+    cluster_bits: u32,
+    codec: Codec,
+    l1: Vec<u64>,
+    /// The L2 table read last, with its offset.
+    l2: Option<(u64, Vec<u64>)>,
+}
+
+impl Qcow2 {
+    /// Offset bits of L1 and uncompressed L2 entries.
+    const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+
+    fn open(file: &File) -> std::io::Result<Self> {
+        let header = read_table(file, 0, 112)?;
+
+        let version = be_u32(&header, 4);
+        if !(2..=3).contains(&version) {
+            return Err(unsupported(&format!("qcow2 images of version {version}")));
+        }
+        if be_u64(&header, 8) != 0 {
+            return Err(unsupported("qcow2 images with a backing file"));
+        }
+        if be_u32(&header, 32) != 0 {
+            return Err(unsupported("Encrypted qcow2 images"));
+        }
+
+        let cluster_bits = be_u32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(format!("cluster size of 2^{cluster_bits} bytes")));
+        }
+
+        let mut codec = Codec::Deflate;
+        if version == 3 {
+            // Only the dirty bit and the compression type are understood
+            let incompatible_features = be_u64(&header, 72);
+            if incompatible_features & !0b1001 != 0 {
+                return Err(unsupported(&format!(
+                    "qcow2 images with features {incompatible_features:#x}"
+                )));
+            }
+
+            if incompatible_features & 0b1000 != 0 && be_u32(&header, 100) > 104 {
+                codec = match header[104] {
+                    0 => Codec::Deflate,
+                    1 => Codec::Zstd,
+                    other => {
+                        return Err(unsupported(&format!(
+                            "qcow2 images with compression type {other}"
+                        )));
+                    }
+                };
+            }
+        }
+
+        let size = be_u64(&header, 24);
+        let l1_size = u64::from(be_u32(&header, 36));
+        let l1_offset = be_u64(&header, 40);
+
+        let l2_coverage = 1_u64 << (2 * cluster_bits - 3);
+        if size.div_ceil(l2_coverage) > l1_size {
+            return Err(invalid("L1 table is too small"));
+        }
+
+        let l1 = read_table(file, l1_offset, l1_size * 8)?;
+
+        Ok(Self {
+            size,
+            cluster_bits,
+            codec,
+            l1: l1.chunks_exact(8).map(|entry| be_u64(entry, 0)).collect(),
+            l2: None,
+        })
+    }
+}
+
+impl Layout for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn locate(&mut self, file: &File, cluster: u64) -> std::io::Result<Cluster> {
+        let l2_bits = self.cluster_bits - 3;
+        #[allow(clippy::cast_possible_truncation)]
+        let l1_index = (cluster >> l2_bits) as usize;
+        #[allow(clippy::cast_possible_truncation)]
+        let l2_index = (cluster & ((1 << l2_bits) - 1)) as usize;
+
+        let l2_offset = self.l1.get(l1_index).copied().unwrap_or_default() & Self::OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Cluster::Zero);
+        }
+
+        if self
+            .l2
+            .as_ref()
+            .is_none_or(|(offset, _)| *offset != l2_offset)
+        {
+            let table = read_table(file, l2_offset, self.cluster_size())?;
+            let entries = table.chunks_exact(8).map(|entry| be_u64(entry, 0));
+            self.l2 = Some((l2_offset, entries.collect()));
+        }
+        let entry = self.l2.as_ref().map_or(0, |(_, table)| table[l2_index]);
+
+        if entry & (1 << 62) != 0 {
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry & ((1 << 62) - 1)) >> offset_bits) + 1;
+
+            return Ok(Cluster::Compressed {
+                offset,
+                len: sectors * 512 - (offset % 512),
+                codec: self.codec,
+            });
+        }
+
+        // Clusters can be marked as reading as zeroes while keeping their offset
+        let offset = entry & Self::OFFSET_MASK;
+        Ok(if offset == 0 || entry & 1 != 0 {
+            Cluster::Zero
+        } else {
+            Cluster::Data(offset)
+        })
+    }
+}
+
+struct Vmdk {
+    size: u64,
+    grain_size: u64,
+    entries_per_table: u64,
+    compressed: bool,
+    directory: Vec<u32>,
+    /// The grain table read last, with its offset.
+    table: Option<(u64, Vec<u32>)>,
+}
+
+impl Vmdk {
+    fn open(file: &File) -> std::io::Result<Self> {
+        let mut header = read_table(file, 0, 512)?;
+
+        // Stream optimized images only know where the directory is at the end
+        if le_u64(&header, 56) == u64::MAX {
+            let len = file.metadata()?.len();
+            header = read_table(file, len.saturating_sub(1024), 512)?;
+            if !header.starts_with(b"KDMV") {
+                return Err(invalid("missing footer"));
+            }
+        }
+
+        let version = le_u32(&header, 4);
+        if !(1..=3).contains(&version) {
+            return Err(unsupported(&format!("VMDK images of version {version}")));
+        }
+
+        let flags = le_u32(&header, 8);
+        let capacity = le_u64(&header, 12);
+        let grain_sectors = le_u64(&header, 20);
+        let entries_per_table = u64::from(le_u32(&header, 44));
+        let directory_offset = le_u64(&header, 56);
+
+        if !(1..=32768).contains(&grain_sectors) || entries_per_table == 0 {
+            return Err(invalid("invalid grain size"));
+        }
+
+        let compressed = flags & (1 << 16) != 0;
+        if compressed && le_u16(&header, 77) != 1 {
+            return Err(unsupported("VMDK images with this compression"));
+        }
+
+        let tables = capacity.div_ceil(grain_sectors).div_ceil(entries_per_table);
+        let directory = read_table(file, directory_offset * 512, tables * 4)?;
+
+        Ok(Self {
+            size: capacity * 512,
+            grain_size: grain_sectors * 512,
+            entries_per_table,
+            compressed,
+            directory: directory
+                .chunks_exact(4)
+                .map(|entry| le_u32(entry, 0))
+                .collect(),
+            table: None,
+        })
+    }
+}
+
+impl Layout for Vmdk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.grain_size
+    }
+
+    fn locate(&mut self, file: &File, cluster: u64) -> std::io::Result<Cluster> {
+        #[allow(clippy::cast_possible_truncation)]
+        let table_offset = self
+            .directory
+            .get((cluster / self.entries_per_table) as usize)
+            .map(|sector| u64::from(*sector) * 512)
+            .ok_or_else(|| invalid("grain directory is too small"))?;
+        if table_offset == 0 {
+            return Ok(Cluster::Zero);
+        }
+
+        if self
+            .table
+            .as_ref()
+            .is_none_or(|(offset, _)| *offset != table_offset)
+        {
+            let table = read_table(file, table_offset, self.entries_per_table * 4)?;
+            let entries = table.chunks_exact(4).map(|entry| le_u32(entry, 0));
+            self.table = Some((table_offset, entries.collect()));
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let sector = self.table.as_ref().map_or(0, |(_, table)| {
+            table[(cluster % self.entries_per_table) as usize]
+        });
+
+        // Sector 1 marks grains that were explicitly zeroed
+        if sector <= 1 {
+            return Ok(Cluster::Zero);
+        }
+        let offset = u64::from(sector) * 512;
+
+        if !self.compressed {
+            return Ok(Cluster::Data(offset));
+        }
+
+        // Compressed grains start with their position and compressed size
+        let marker = read_table(file, offset, 12)?;
+        Ok(Cluster::Compressed {
+            offset: offset + 12,
+            len: u64::from(le_u32(&marker, 8)),
+            codec: Codec::Zlib,
+        })
+    }
+}
+
+struct Vhd {
+    size: u64,
+    block_size: u64,
+    /// Sector offsets of the blocks of dynamic images, `None` for fixed ones.
+    blocks: Option<Vec<u32>>,
+    /// Size of the sector bitmap in front of each block.
+    bitmap_size: u64,
+}
+
+impl Vhd {
+    /// Granularity at which fixed images are read.
+    const FIXED_BLOCK_SIZE: u64 = 2 * 1024 * 1024;
+
+    fn open(file: &File) -> std::io::Result<Self> {
+        let len = file.metadata()?.len();
+        let mut footer = read_table(file, len.saturating_sub(512), 512)?;
+
+        // Dynamic images keep a copy of the footer at the start
+        if !footer.starts_with(VHD_COOKIE) {
+            footer = read_table(file, 0, 512)?;
+            if !footer.starts_with(VHD_COOKIE) {
+                return Err(invalid("missing footer"));
+            }
+        }
+
+        let size = be_u64(&footer, 48);
+
+        match be_u32(&footer, 60) {
+            2 => Ok(Self {
+                size,
+                block_size: Self::FIXED_BLOCK_SIZE,
+                blocks: None,
+                bitmap_size: 0,
+            }),
+            3 => {
+                let header = read_table(file, be_u64(&footer, 16), 1024)?;
+                if !header.starts_with(b"cxsparse") {
+                    return Err(invalid("missing dynamic disk header"));
+                }
+
+                let table_offset = be_u64(&header, 16);
+                let max_entries = u64::from(be_u32(&header, 28));
+                let block_size = u64::from(be_u32(&header, 32));
+
+                if block_size == 0 || block_size % 512 != 0 {
+                    return Err(invalid(format!("block size of {block_size} bytes")));
+                }
+                if size.div_ceil(block_size) > max_entries {
+                    return Err(invalid("block allocation table is too small"));
+                }
+
+                let blocks = read_table(file, table_offset, max_entries * 4)?;
+
+                Ok(Self {
+                    size,
+                    block_size,
+                    blocks: Some(
+                        blocks
+                            .chunks_exact(4)
+                            .map(|entry| be_u32(entry, 0))
+                            .collect(),
+                    ),
+                    bitmap_size: (block_size / 512).div_ceil(8).div_ceil(512) * 512,
+                })
+            }
+            4 => Err(unsupported("Differencing VHD images")),
+            other => Err(invalid(format!("disk type {other}"))),
+        }
+    }
+}
+
+impl Layout for Vhd {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn locate(&mut self, _file: &File, cluster: u64) -> std::io::Result<Cluster> {
+        let Some(blocks) = &self.blocks else {
+            return Ok(Cluster::Data(cluster * self.block_size));
+        };
+
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(match blocks.get(cluster as usize) {
+            Some(&u32::MAX) | None => Cluster::Zero,
+            Some(sector) => Cluster::Data(u64::from(*sector) * 512 + self.bitmap_size),
+        })
+    }
+}
+
+struct Vhdx {
+    size: u64,
+    block_size: u64,
+    /// Number of payload blocks between two sector bitmap entries.
+    chunk_ratio: u64,
+    blocks: Vec<u64>,
+}
+
+impl Vhdx {
+    fn open(file: &File) -> std::io::Result<Self> {
+        if !read_table(file, 0, 8)?.starts_with(b"vhdxfile") {
+            return Err(invalid("missing file identifier"));
+        }
+
+        // Of the two headers, the one with the higher sequence number is current
+        let mut header = None;
+        for offset in [64 * 1024, 128 * 1024] {
+            let candidate = read_table(file, offset, 80)?;
+            if candidate.starts_with(b"head")
+                && header
+                    .as_ref()
+                    .is_none_or(|current: &Vec<u8>| le_u64(&candidate, 8) > le_u64(current, 8))
+            {
+                header = Some(candidate);
+            }
+        }
+        let header = header.ok_or_else(|| invalid("missing header"))?;
+        if header[48..64].iter().any(|byte| *byte != 0) {
+            return Err(unsupported("VHDX images with a log to replay"));
+        }
+
+        let regions = read_table(file, 192 * 1024, 64 * 1024)?;
+        if !regions.starts_with(b"regi") {
+            return Err(invalid("missing region table"));
+        }
+        let region = |id: &[u8; 16]| {
+            regions[16..]
+                .chunks_exact(32)
+                .take(le_u32(&regions, 8) as usize)
+                .find(|entry| entry[..16] == *id)
+                .map(|entry| (le_u64(entry, 16), u64::from(le_u32(entry, 24))))
+        };
+
+        let (bat_offset, bat_len) =
+            region(&VHDX_BAT_REGION).ok_or_else(|| invalid("missing block allocation table"))?;
+        let (metadata_offset, metadata_len) =
+            region(&VHDX_METADATA_REGION).ok_or_else(|| invalid("missing metadata"))?;
+
+        let metadata = read_table(file, metadata_offset, metadata_len)?;
+        if !metadata.starts_with(b"metadata") {
+            return Err(invalid("missing metadata"));
+        }
+        let item = |id: &[u8; 16], len: usize| {
+            metadata[32..]
+                .chunks_exact(32)
+                .take(usize::from(le_u16(&metadata, 10)))
+                .find(|entry| entry[..16] == *id)
+                .and_then(|entry| metadata.get(le_u32(entry, 16) as usize..)?.get(..len))
+                .ok_or_else(|| invalid("missing metadata item"))
+        };
+
+        let parameters = item(&VHDX_FILE_PARAMETERS, 8)?;
+        let block_size = u64::from(le_u32(parameters, 0));
+        if le_u32(parameters, 4) & 0b10 != 0 {
+            return Err(unsupported("Differencing VHDX images"));
+        }
+        let size = le_u64(item(&VHDX_VIRTUAL_DISK_SIZE, 8)?, 0);
+        let sector_size = u64::from(le_u32(item(&VHDX_LOGICAL_SECTOR_SIZE, 4)?, 0));
+
+        if !block_size.is_power_of_two() || !(1 << 20..=256 << 20).contains(&block_size) {
+            return Err(invalid(format!("block size of {block_size} bytes")));
+        }
+        if sector_size != 512 && sector_size != 4096 {
+            return Err(invalid(format!("sector size of {sector_size} bytes")));
+        }
+
+        let blocks = read_table(file, bat_offset, bat_len)?;
+
+        Ok(Self {
+            size,
+            block_size,
+            chunk_ratio: (1 << 23) * sector_size / block_size,
+            blocks: blocks
+                .chunks_exact(8)
+                .map(|entry| le_u64(entry, 0))
+                .collect(),
+        })
+    }
+}
+
+impl Layout for Vhdx {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn locate(&mut self, _file: &File, cluster: u64) -> std::io::Result<Cluster> {
+        // Every few payload blocks, the table has an entry for a sector bitmap
+        #[allow(clippy::cast_possible_truncation)]
+        let entry = self
+            .blocks
+            .get((cluster + cluster / self.chunk_ratio) as usize)
+            .ok_or_else(|| invalid("block allocation table is too small"))?;
+
+        match entry & 0b111 {
+            6 => Ok(Cluster::Data((entry >> 20) * 1024 * 1024)),
+            7 => Err(invalid("partially present block")),
+            _ => Ok(Cluster::Zero),
+        }
+    }
+}
+
+struct Vdi {
+    size: u64,
+    block_size: u64,
+    /// Bytes in front of the data of each block.
+    block_extra: u64,
+    data_offset: u64,
+    blocks: Vec<u32>,
+}
+
+impl Vdi {
+    fn open(file: &File) -> std::io::Result<Self> {
+        let header = read_table(file, 0, 0x190)?;
+        if le_u32(&header, 0x40) != 0xBEDA_107F {
+            return Err(invalid("missing signature"));
+        }
+
+        let version = le_u32(&header, 0x44) >> 16;
+        if version != 1 {
+            return Err(unsupported(&format!("VDI images of version {version}")));
+        }
+
+        match le_u32(&header, 0x4C) {
+            1 | 2 => {}
+            _ => return Err(unsupported("Differencing VDI images")),
+        }
+
+        let blocks_offset = u64::from(le_u32(&header, 0x154));
+        let data_offset = u64::from(le_u32(&header, 0x158));
+        let size = le_u64(&header, 0x170);
+        let block_size = u64::from(le_u32(&header, 0x178));
+        let block_extra = u64::from(le_u32(&header, 0x17C));
+        let block_count = u64::from(le_u32(&header, 0x180));
+
+        if block_size == 0 || size.div_ceil(block_size) > block_count {
+            return Err(invalid("block map is too small"));
+        }
+
+        let blocks = read_table(file, blocks_offset, block_count * 4)?;
+
+        Ok(Self {
+            size,
+            block_size,
+            block_extra,
+            data_offset,
+            blocks: blocks
+                .chunks_exact(4)
+                .map(|entry| le_u32(entry, 0))
+                .collect(),
+        })
+    }
+}
+
+impl Layout for Vdi {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn locate(&mut self, _file: &File, cluster: u64) -> std::io::Result<Cluster> {
+        // The two highest values mark free and zeroed blocks
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(match self.blocks.get(cluster as usize) {
+            Some(&index) if index < 0xFFFF_FFFE => Cluster::Data(
+                self.data_offset
+                    + u64::from(index) * (self.block_size + self.block_extra)
+                    + self.block_extra,
+            ),
+            _ => Cluster::Zero,
+        })
+    }
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Reads the image in `data` as `format`, returning its size and content.
+    fn convert(name: &str, format: Format, data: &[u8]) -> std::io::Result<(u64, Vec<u8>)> {
+        with_file(name, data, |file| {
+            let mut reader = VirtualDiskReader::open(file, format)?;
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content)?;
+            Ok((reader.size(), content))
+        })
+    }
+
+    /// Calls `f` with a temporary file holding `data`.
+    fn with_file<T>(
+        name: &str,
+        data: &[u8],
+        f: impl FnOnce(File) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let path = std::env::temp_dir().join(format!("impression-{}-{name}", std::process::id()));
+        std::fs::write(&path, data)?;
+
+        let result = File::open(&path).and_then(f);
+        std::fs::remove_file(&path)?;
+
+        result
+    }
+
+    fn put(image: &mut Vec<u8>, offset: usize, data: &[u8]) {
+        if image.len() < offset + data.len() {
+            image.resize(offset + data.len(), 0);
+        }
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn compress(data: &[u8], mut encoder: impl Write) {
+        encoder.write_all(data).expect("Failed to compress");
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        compress(data, &mut encoder);
+        encoder.finish().expect("Failed to compress")
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        compress(data, &mut encoder);
+        encoder.finish().expect("Failed to compress")
+    }
+
+    #[test]
+    fn converts_qcow2_images() {
+        let mut image = Vec::new();
+        put(&mut image, 0, b"QFI\xfb");
+        put(&mut image, 4, &3_u32.to_be_bytes());
+        put(&mut image, 20, &9_u32.to_be_bytes());
+        put(&mut image, 24, &2000_u64.to_be_bytes());
+        put(&mut image, 36, &1_u32.to_be_bytes());
+        put(&mut image, 40, &0x200_u64.to_be_bytes());
+        put(&mut image, 100, &104_u32.to_be_bytes());
+
+        // L1 and L2 entries of clusters in use have the copied flag set
+        let copied = 1 << 63;
+        put(&mut image, 0x200, &(0x400_u64 | copied).to_be_bytes());
+        // Allocated, unallocated, zero flagged and compressed clusters
+        put(&mut image, 0x400, &(0x600_u64 | copied).to_be_bytes());
+        put(&mut image, 0x410, &(0x800_u64 | 1).to_be_bytes());
+        put(&mut image, 0x418, &(0xA00_u64 | 1 << 62).to_be_bytes());
+
+        put(&mut image, 0x600, &[0xAA; 512]);
+        put(&mut image, 0x800, &[0xBB; 512]);
+        put(&mut image, 0xA00, &deflate(&[0xCC; 512]));
+        image.resize(0xC00, 0);
+
+        let (size, content) =
+            convert("image.qcow2", Format::Qcow2, &image).expect("Failed to convert");
+
+        assert_eq!(size, 2000);
+        assert_eq!(content.len(), 2000);
+        assert_eq!(content[..512], [0xAA; 512]);
+        assert_eq!(content[512..1536], [0; 1024]);
+        assert_eq!(content[1536..], [0xCC; 464]);
+    }
+
+    #[test]
+    fn rejects_qcow2_images_with_a_backing_file() {
+        let mut image = Vec::new();
+        put(&mut image, 0, b"QFI\xfb");
+        put(&mut image, 4, &2_u32.to_be_bytes());
+        put(&mut image, 8, &512_u64.to_be_bytes());
+        image.resize(1024, 0);
+
+        let error = convert("backing.qcow2", Format::Qcow2, &image).expect_err("Converted anyway");
+
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    fn vmdk_header(capacity: u64, directory: u64, compressed: bool) -> Vec<u8> {
+        let mut header = Vec::new();
+        put(&mut header, 0, b"KDMV");
+        put(&mut header, 4, &3_u32.to_le_bytes());
+        let flags: u32 = if compressed { 0b11 << 16 | 1 } else { 1 };
+        put(&mut header, 8, &flags.to_le_bytes());
+        put(&mut header, 12, &capacity.to_le_bytes());
+        put(&mut header, 20, &1_u64.to_le_bytes());
+        put(&mut header, 44, &2_u32.to_le_bytes());
+        put(&mut header, 56, &directory.to_le_bytes());
+        put(&mut header, 77, &u16::from(compressed).to_le_bytes());
+        header.resize(512, 0);
+        header
+    }
+
+    #[test]
+    fn converts_vmdk_images() {
+        let mut image = vmdk_header(4, 1, false);
+        // The second table of the directory isn't allocated
+        put(&mut image, 512, &2_u32.to_le_bytes());
+        // An allocated grain and one that was zeroed
+        put(&mut image, 1024, &3_u32.to_le_bytes());
+        put(&mut image, 1028, &1_u32.to_le_bytes());
+        put(&mut image, 1536, &[0xAA; 512]);
+
+        let (size, content) =
+            convert("image.vmdk", Format::Vmdk, &image).expect("Failed to convert");
+
+        assert_eq!(size, 2048);
+        assert_eq!(content[..512], [0xAA; 512]);
+        assert_eq!(content[512..], [0; 1536]);
+    }
+
+    #[test]
+    fn converts_stream_optimized_vmdk_images() {
+        let mut image = vmdk_header(2, u64::MAX, true);
+
+        // Grain marker with the sector and size of the compressed grain
+        let grain = zlib(&[0xAA; 512]);
+        put(&mut image, 2 * 512, &0_u64.to_le_bytes());
+        #[allow(clippy::cast_possible_truncation)]
+        put(&mut image, 2 * 512 + 8, &(grain.len() as u32).to_le_bytes());
+        put(&mut image, 2 * 512 + 12, &grain);
+
+        put(&mut image, 3 * 512, &2_u32.to_le_bytes());
+        put(&mut image, 4 * 512, &3_u32.to_le_bytes());
+
+        // The footer comes right before the end of stream marker
+        let footer = vmdk_header(2, 4, true);
+        put(&mut image, 5 * 512, &footer);
+        image.resize(7 * 512, 0);
+
+        let (size, content) =
+            convert("stream.vmdk", Format::Vmdk, &image).expect("Failed to convert");
+
+        assert_eq!(size, 1024);
+        assert_eq!(content[..512], [0xAA; 512]);
+        assert_eq!(content[512..], [0; 512]);
+    }
+
+    fn vhd_footer(size: u64, disk_type: u32, header: u64) -> Vec<u8> {
+        let mut footer = Vec::new();
+        put(&mut footer, 0, VHD_COOKIE);
+        put(&mut footer, 16, &header.to_be_bytes());
+        put(&mut footer, 48, &size.to_be_bytes());
+        put(&mut footer, 60, &disk_type.to_be_bytes());
+        footer.resize(512, 0);
+        footer
+    }
+
+    #[test]
+    fn converts_fixed_vhd_images() {
+        let mut image = [0xAA; 1024].to_vec();
+        image.extend_from_slice(&vhd_footer(1024, 2, u64::MAX));
+
+        assert!(
+            with_file("footer.vhd", &image, |file| has_vhd_footer(&file))
+                .expect("Failed to read footer")
+        );
+
+        let (size, content) = convert("fixed.vhd", Format::Vhd, &image).expect("Failed to convert");
+
+        assert_eq!(size, 1024);
+        assert_eq!(content, [0xAA; 1024]);
+    }
+
+    #[test]
+    fn converts_dynamic_vhd_images() {
+        let footer = vhd_footer(2048, 3, 512);
+        let mut image = footer.clone();
+
+        put(&mut image, 512, b"cxsparse");
+        put(&mut image, 528, &1536_u64.to_be_bytes());
+        put(&mut image, 540, &2_u32.to_be_bytes());
+        put(&mut image, 544, &1024_u32.to_be_bytes());
+
+        // The first block is allocated after a sector of bitmap, the second isn't
+        put(&mut image, 1536, &4_u32.to_be_bytes());
+        put(&mut image, 1540, &u32::MAX.to_be_bytes());
+        put(&mut image, 2048, &[0xFF; 512]);
+        put(&mut image, 2560, &[0xAA; 1024]);
+        image.extend_from_slice(&footer);
+
+        let (size, content) =
+            convert("dynamic.vhd", Format::Vhd, &image).expect("Failed to convert");
+
+        assert_eq!(size, 2048);
+        assert_eq!(content[..1024], [0xAA; 1024]);
+        assert_eq!(content[1024..], [0; 1024]);
+    }
+
+    #[test]
+    fn converts_vhdx_images() {
+        const MIB: usize = 1024 * 1024;
+
+        let mut image = Vec::new();
+        put(&mut image, 0, b"vhdxfile");
+        put(&mut image, 64 * 1024, b"head");
+        put(&mut image, 64 * 1024 + 8, &1_u64.to_le_bytes());
+        // Only the newer second header counts, along with its empty log
+        put(&mut image, 128 * 1024, b"head");
+        put(&mut image, 128 * 1024 + 8, &2_u64.to_le_bytes());
+        put(&mut image, 64 * 1024 + 48, &[1; 16]);
+
+        let regions = 192 * 1024;
+        put(&mut image, regions, b"regi");
+        put(&mut image, regions + 8, &2_u32.to_le_bytes());
+        put(&mut image, regions + 16, &VHDX_BAT_REGION);
+        put(&mut image, regions + 32, &(320_u64 * 1024).to_le_bytes());
+        put(&mut image, regions + 40, &4096_u32.to_le_bytes());
+        put(&mut image, regions + 48, &VHDX_METADATA_REGION);
+        put(&mut image, regions + 64, &(256_u64 * 1024).to_le_bytes());
+        put(&mut image, regions + 72, &(64_u32 * 1024).to_le_bytes());
+
+        let metadata = 256 * 1024;
+        put(&mut image, metadata, b"metadata");
+        put(&mut image, metadata + 10, &3_u16.to_le_bytes());
+        for (index, (id, value)) in [
+            (VHDX_FILE_PARAMETERS, (1_u64 << 20).to_le_bytes()),
+            (VHDX_VIRTUAL_DISK_SIZE, (3_u64 << 20).to_le_bytes()),
+            (VHDX_LOGICAL_SECTOR_SIZE, 512_u64.to_le_bytes()),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let entry = metadata + 32 + index * 32;
+            let offset = 32 * 1024 + index * 8;
+            put(&mut image, entry, id);
+            #[allow(clippy::cast_possible_truncation)]
+            put(&mut image, entry + 16, &(offset as u32).to_le_bytes());
+            put(&mut image, metadata + offset, value);
+        }
+
+        // A present, a missing and a zero block
+        let bat = 320 * 1024;
+        put(&mut image, bat, &(4_u64 << 20 | 6).to_le_bytes());
+        put(&mut image, bat + 16, &2_u64.to_le_bytes());
+        put(&mut image, 4 * MIB, &vec![0xAA; MIB]);
+
+        let (size, content) =
+            convert("image.vhdx", Format::Vhdx, &image).expect("Failed to convert");
+
+        assert_eq!(size, 3 << 20);
+        assert!(content[..MIB].iter().all(|byte| *byte == 0xAA));
+        assert!(content[MIB..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn converts_vdi_images() {
+        let mut image = Vec::new();
+        put(&mut image, 0x40, &0xBEDA_107F_u32.to_le_bytes());
+        put(&mut image, 0x44, &0x0001_0001_u32.to_le_bytes());
+        put(&mut image, 0x4C, &1_u32.to_le_bytes());
+        put(&mut image, 0x154, &512_u32.to_le_bytes());
+        put(&mut image, 0x158, &1024_u32.to_le_bytes());
+        put(&mut image, 0x170, &1536_u64.to_le_bytes());
+        put(&mut image, 0x178, &512_u32.to_le_bytes());
+        put(&mut image, 0x180, &3_u32.to_le_bytes());
+
+        // The second block of data is stored first, then a free and a zeroed block
+        put(&mut image, 512, &1_u32.to_le_bytes());
+        put(&mut image, 516, &0xFFFF_FFFF_u32.to_le_bytes());
+        put(&mut image, 520, &0xFFFF_FFFE_u32.to_le_bytes());
+        put(&mut image, 1024, &[0xBB; 512]);
+        put(&mut image, 1536, &[0xAA; 512]);
+
+        let (size, content) = convert("image.vdi", Format::Vdi, &image).expect("Failed to convert");
+
+        assert_eq!(size, 1536);
+        assert_eq!(content[..512], [0xAA; 512]);
+        assert_eq!(content[512..], [0; 1024]);
+    }
+}
@@ -0,0 +1,88 @@
+use adw::prelude::*;
+use gettextrs::gettext;
+
+use crate::flash::{Destination, FlashPhase, FlashStatus, Progress};
+
+/// A row that follows how writing to one of several drives goes.
+#[derive(Debug)]
+pub struct DestinationRow {
+    pub row: adw::ActionRow,
+    destination: Destination,
+    /// Whether the drive is read back after writing.
+    verify: bool,
+    progress_bar: gtk::ProgressBar,
+    result_icon: gtk::Image,
+}
+
+impl DestinationRow {
+    pub fn new(title: &str, destination: Destination, verify: bool) -> Self {
+        let progress_bar = gtk::ProgressBar::builder()
+            .valign(gtk::Align::Center)
+            .width_request(120)
+            .build();
+        let result_icon = gtk::Image::builder().visible(false).build();
+
+        let row = adw::ActionRow::builder().title(title).build();
+        row.add_suffix(&progress_bar);
+        row.add_suffix(&result_icon);
+
+        let this = Self {
+            row,
+            destination,
+            verify,
+            progress_bar,
+            result_icon,
+        };
+        this.update();
+        this
+    }
+
+    /// Shows the current status of the drive.
+    pub fn update(&self) {
+        let Some(status) = self.destination.status() else {
+            return;
+        };
+
+        match status {
+            FlashStatus::Active(phase, progress) => {
+                self.row.set_subtitle(&match phase {
+                    FlashPhase::Copy | FlashPhase::DownloadAndCopy { .. } => gettext("Writing"),
+                    FlashPhase::Verify => gettext("Verifying"),
+                    _ => gettext("Waiting"),
+                });
+
+                match progress {
+                    Progress::Fraction(x) => self.progress_bar.set_fraction(x),
+                    Progress::Pulse => self.progress_bar.pulse(),
+                }
+            }
+            FlashStatus::Done(error) => {
+                self.progress_bar.set_visible(false);
+                self.result_icon.set_visible(true);
+
+                if let Some(error) = error {
+                    self.row.set_subtitle(&error);
+                    self.result_icon.set_icon_name(Some("error-symbolic"));
+                    self.result_icon.add_css_class("error");
+                } else {
+                    self.row.set_subtitle(&if self.verify {
+                        gettext("Written and verified")
+                    } else {
+                        gettext("Written")
+                    });
+                    self.result_icon
+                        .set_icon_name(Some("check-round-outline-symbolic"));
+                    self.result_icon.add_css_class("success");
+                }
+            }
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        matches!(self.destination.status(), Some(FlashStatus::Done(None)))
+    }
+
+    pub fn finished(&self) -> bool {
+        matches!(self.destination.status(), Some(FlashStatus::Done(_)))
+    }
+}
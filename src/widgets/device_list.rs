@@ -1,7 +1,11 @@
 use std::ffi::CString;
 
 use adw::prelude::*;
+use gettextrs::gettext;
 
+use crate::capacity;
+use crate::duplicator::DriveModel;
+use crate::get_size_string;
 use crate::window::ImpressionAppWindow;
 
 async fn refresh_devices(client: &udisks::Client) -> udisks::Result<Vec<udisks::Object>> {
@@ -10,8 +14,8 @@ async fn refresh_devices(client: &udisks::Client) -> udisks::Result<Vec<udisks::
         .object_manager()
         .get_managed_objects()
         .await?
-        .into_iter()
-        .filter_map(|(object_path, _)| client.object(object_path).ok())
+        .into_keys()
+        .filter_map(|object_path| client.object(object_path).ok())
     {
         let Ok(drive): udisks::Result<udisks::drive::DriveProxy> = object.drive().await else {
             continue;
@@ -37,6 +41,9 @@ pub struct DeviceMetadata {
     pub display_string: Option<String>,
     pub info: Option<String>,
     pub label: udisks::Result<String>,
+    pub model: Option<DriveModel>,
+    /// Capacity in bytes.
+    pub size: u64,
 }
 
 async fn device_metadata(client: &udisks::Client, object: &udisks::Object) -> DeviceMetadata {
@@ -45,6 +52,8 @@ async fn device_metadata(client: &udisks::Client, object: &udisks::Object) -> De
         display_string: preferred_device_display_string(object).await,
         info: device_info(client, object).await,
         label: device_label(client, object).await,
+        model: DriveModel::of(client, object).await.ok().flatten(),
+        size: device_size(object).await.unwrap_or_default(),
     }
 }
 
@@ -66,59 +75,46 @@ pub async fn fetch_devices_metadata() -> udisks::Result<Vec<DeviceMetadata>> {
     Ok(get_devices_metadata(&client, &devices).await)
 }
 
+/// Rows of drives to write an image of `needed` bytes to, with the drives
+/// that are too small for it greyed out.
 pub fn new(
     app: &ImpressionAppWindow,
     devices: &[DeviceMetadata],
-    selected_device: Option<&str>,
+    selected_devices: &[String],
+    needed: Option<u64>,
 ) -> Vec<adw::ActionRow> {
-    let mut check_buttons = Vec::new();
+    let fits = |device: &DeviceMetadata| capacity::check(needed, device.size).is_ok();
 
-    for device in devices {
-        let check_button_builder = check_buttons
-            .first()
-            .map_or_else(gtk::CheckButton::builder, |first_check_button| {
-                gtk::CheckButton::builder().group(first_check_button)
-            });
-        let check_button = check_button_builder
+    let keep_selection = devices.iter().any(|device| {
+        fits(device) && selected_devices.contains(&device.object.object_path().to_string())
+    });
+    let first_fitting = devices.iter().position(fits);
+
+    let mut res = Vec::new();
+
+    for (i, device) in devices.iter().enumerate() {
+        let check_button = gtk::CheckButton::builder()
             .valign(gtk::Align::Center)
             .css_classes(["selection_mode"])
             .build();
 
         let object_path = device.object.object_path().to_string();
-        if devices.len() == 1 {
-            check_button.connect_toggled(glib::clone!(
-                #[weak(rename_to=this)]
-                app,
-                move |x| {
-                    x.set_active(true);
-                    this.set_selected_device_object_path_for_writing(Some(object_path.clone()));
-                }
-            ));
-        } else {
-            check_button.connect_toggled(glib::clone!(
-                #[weak(rename_to=this)]
-                app,
-                move |x| {
-                    if x.is_active() {
-                        this.set_selected_device_object_path_for_writing(Some(object_path.clone()));
-                    }
-                }
-            ));
-        }
-        check_buttons.push(check_button);
-    }
-
-    let mut res = Vec::new();
-
-    for (i, (device, check_button)) in devices.iter().zip(check_buttons.into_iter()).enumerate() {
-        if device.display_string.as_ref().is_some_and(|device_name| {
-            selected_device.is_some_and(|selected_device_name| device_name == selected_device_name)
-        }) || selected_device.is_none() && i == 0
+        check_button.connect_toggled(glib::clone!(
+            #[weak(rename_to=this)]
+            app,
+            #[strong]
+            object_path,
+            move |x| {
+                this.set_device_selected_for_writing(&object_path, x.is_active());
+            }
+        ));
+
+        // Drives stay selected when others come and go, otherwise the first one is
+        if fits(device)
+            && (keep_selection && selected_devices.contains(&object_path)
+                || !keep_selection && Some(i) == first_fitting)
         {
             check_button.set_active(true);
-            app.set_selected_device_object_path_for_writing(Some(
-                device.object.object_path().to_string(),
-            ));
         }
 
         let row = adw::ActionRow::builder()
@@ -128,12 +124,55 @@ pub fn new(
             .build();
 
         row.add_prefix(&check_button);
+
+        if let (Some(needed), false) = (needed, fits(device)) {
+            row.set_sensitive(false);
+            row.set_subtitle(
+                &gettext("Too small, holds {} but the image needs {}")
+                    .replacen("{}", &get_size_string(device.size), 1)
+                    .replacen("{}", &get_size_string(needed), 1),
+            );
+        }
+
         res.push(row);
     }
 
     res
 }
 
+/// Rows of drives that call `on_activated` with the drive when clicked.
+pub fn action_rows<F: Fn(&ImpressionAppWindow, DeviceMetadata) + Clone + 'static>(
+    app: &ImpressionAppWindow,
+    devices: &[DeviceMetadata],
+    on_activated: F,
+) -> Vec<adw::ActionRow> {
+    devices
+        .iter()
+        .map(|device| {
+            let next_image = gtk::Image::from_icon_name("go-next-symbolic");
+
+            let row = adw::ActionRow::builder()
+                .title(device.label.clone().unwrap_or_default())
+                .subtitle(device.info.clone().unwrap_or_default())
+                .activatable(true)
+                .build();
+            row.add_suffix(&next_image);
+
+            let device = device.clone();
+            let on_activated = on_activated.clone();
+            row.connect_activated(glib::clone!(
+                #[weak(rename_to=this)]
+                app,
+                move |_| {
+                    on_activated(&this, device.clone());
+                }
+            ));
+
+            row
+        })
+        .collect()
+}
+
 pub async fn device_label(
     client: &udisks::Client,
     object: &udisks::Object,
@@ -165,6 +204,10 @@ pub async fn device_label(
     ))
 }
 
+async fn device_size(object: &udisks::Object) -> udisks::Result<u64> {
+    object.block().await?.size().await
+}
+
 async fn device_info(client: &udisks::Client, device: &udisks::Object) -> Option<String> {
     let info = client.object_info(device).await;
     info.one_liner
@@ -1 +1,2 @@
+pub mod destination_list;
 pub mod device_list;
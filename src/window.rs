@@ -1,4 +1,8 @@
+use std::cell::RefCell;
+use std::ops::ControlFlow;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use adw::prelude::*;
 use gettextrs::gettext;
@@ -10,16 +14,35 @@ use log::{error, info, warn};
 use crate::config::APP_ID;
 use crate::runtime;
 use crate::{
-    flash::{FlashPhase, FlashRequest, FlashStatus, Progress},
+    backup::{BackupCompression, BackupOptions, BackupRequest},
+    bmap::{self, Bmap},
+    capacity,
+    checksum::{Digest, FileDigest},
+    duplicator::{DriveModel, Duplicator, NewDrive},
+    flash::{Destination, FlashOptions, FlashPhase, FlashRequest, FlashStatus, Progress},
+    format::{Filesystem, FormatOptions, FormatRequest, PartitionScheme},
     get_size_string,
+    inspect::{self, Inspection, PartitionTable, Platform},
     online::{DistroRelease, collect_online_distros, get_osinfo_db_url},
-    widgets::device_list,
+    probe::{self, ImageFormat, ZipEntry},
+    report, vdisk,
+    widgets::{destination_list::DestinationRow, device_list},
+    wipe::{ErasureReport, WipeMethod, WipeRequest},
 };
 
 #[derive(Debug, Clone)]
 pub enum Compression {
     Raw,
     Xz,
+    Gzip,
+    Bzip2,
+    Zstd,
+    /// A single entry of a ZIP archive, named by its path inside the archive.
+    Zip {
+        entry: String,
+    },
+    /// A virtual machine disk, converted to a raw image.
+    VirtualDisk(vdisk::Format),
 }
 
 #[derive(Debug, Clone)]
@@ -27,17 +50,40 @@ pub enum DiskImage {
     Local {
         path: PathBuf,
         compression: Compression,
+        digest: Option<Digest>,
+        /// Digest of the image computed while it was selected, to check it against.
+        computed_digest: Option<FileDigest>,
+        /// Block map listing the parts of the image that hold data.
+        bmap: Option<PathBuf>,
     },
     Online {
         url: url::Url,
-        download_path: PathBuf,
+        /// Where to keep the downloaded image, if it should be kept at all.
+        download_path: Option<PathBuf>,
+        name: String,
+    },
+    /// Another drive, copied as it is.
+    Drive {
+        object: udisks::Object,
         name: String,
     },
 }
 
+/// The kind of job that is running.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Task {
+    #[default]
+    Write,
+    Backup,
+    /// Writing to every allowed drive that is plugged in.
+    Duplicate,
+    Format,
+    Wipe,
+}
+
 mod imp {
 
-    use std::{cell::RefCell, sync::atomic::AtomicBool};
+    use std::cell::Cell;
 
     use crate::{
         config::{APP_ID, PROFILE},
@@ -67,16 +113,64 @@ mod imp {
         #[template_child]
         pub available_devices_list: TemplateChild<gtk::ListBox>,
         #[template_child]
+        pub backup_devices_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub clone_devices_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub format_devices_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub wipe_devices_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub duplicator_page: TemplateChild<adw::NavigationPage>,
+        #[template_child]
+        pub duplicator_allowlist: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub duplicator_candidates_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub duplicator_candidates_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub duplicator_drives_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub duplicator_drives_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub duplicator_start_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub boot_warning_banner: TemplateChild<adw::Banner>,
+        #[template_child]
+        pub contents_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub contents_row: TemplateChild<adw::ExpanderRow>,
+        #[template_child]
+        pub checksum_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub checksum_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub checksum_result_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub checksum_spinner: TemplateChild<adw::Spinner>,
+        #[template_child]
+        pub checksum_status_icon: TemplateChild<gtk::Image>,
+        #[template_child]
+        pub bmap_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub bmap_clear_button: TemplateChild<gtk::Button>,
+        #[template_child]
         pub name_value_label: TemplateChild<gtk::Label>,
         #[template_child]
         pub size_label: TemplateChild<gtk::Label>,
         #[template_child]
         pub flash_button: TemplateChild<gtk::Button>,
         #[template_child]
+        pub duplicator_button: TemplateChild<gtk::Button>,
+        #[template_child]
         pub try_again_button: TemplateChild<gtk::Button>,
         #[template_child]
         pub done_button: TemplateChild<gtk::Button>,
         #[template_child]
+        pub success_report_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub failure_report_button: TemplateChild<gtk::Button>,
+        #[template_child]
         pub loading_spinner: TemplateChild<gtk::Spinner>,
         #[template_child]
         pub progress_bar: TemplateChild<gtk::ProgressBar>,
@@ -85,6 +179,14 @@ mod imp {
         #[template_child]
         pub flashing_page: TemplateChild<adw::StatusPage>,
         #[template_child]
+        pub destinations_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub success_page: TemplateChild<adw::StatusPage>,
+        #[template_child]
+        pub results_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub failure_page: TemplateChild<adw::StatusPage>,
+        #[template_child]
         pub download_spinner: TemplateChild<gtk::Box>,
         #[template_child]
         pub offline_screen: TemplateChild<gtk::Box>,
@@ -103,9 +205,20 @@ mod imp {
         #[template_child]
         pub error_message_label: TemplateChild<gtk::Label>,
 
-        pub selected_device_object_path_for_writing: RefCell<Option<String>>,
+        pub selected_device_object_paths_for_writing: RefCell<Vec<String>>,
         pub selected_image_file_for_reading: RefCell<Option<DiskImage>>,
         pub available_devices: RefCell<Vec<device_list::DeviceMetadata>>,
+        pub task: Cell<Task>,
+        /// Room that the selected image takes up on a drive, once it is known.
+        pub image_size: Cell<Option<u64>>,
+        /// Whether the size being worked out for the selected image is still wanted.
+        pub image_size_job: RefCell<Option<Arc<AtomicBool>>>,
+        pub contents_rows: RefCell<Vec<adw::ActionRow>>,
+        pub duplicator_rows: RefCell<Vec<DestinationRow>>,
+        /// How the last wipe went, once it is done.
+        pub erasure_report: std::sync::Arc<std::sync::Mutex<Option<ErasureReport>>>,
+        /// Whether the checksum being computed for the selected image is still wanted.
+        pub checksum_job: RefCell<Option<Arc<AtomicBool>>>,
 
         pub is_running: std::sync::Arc<AtomicBool>,
 
@@ -226,7 +339,24 @@ impl ImpressionAppWindow {
                     }
                 ))
                 .build(),
+            gio::ActionEntry::builder("export-report")
+                .activate(clone!(
+                    #[weak(rename_to=window)]
+                    self,
+                    move |_, _, _| {
+                        window.export_report_dialog();
+                    }
+                ))
+                .build(),
         ]);
+
+        self.add_action(&self.imp().settings.create_action("verify-after-writing"));
+        self.add_action(&self.imp().settings.create_action("write-while-downloading"));
+        self.add_action(&self.imp().settings.create_action("skip-zero-blocks"));
+        self.add_action(&self.imp().settings.create_action("test-compressed-images"));
+        self.add_action(&self.imp().settings.create_action("backup-compression"));
+        self.add_action(&self.imp().settings.create_action("backup-used-space-only"));
+        self.add_action(&self.imp().settings.create_action("backup-bmap"));
     }
 
     fn setup_drop_target(&self) {
@@ -256,15 +386,37 @@ impl ImpressionAppWindow {
     }
 
     fn cancel_request(&self, close_after: bool) {
-        let dialog = adw::AlertDialog::new(
-            Some(&gettext("Stop Writing?")),
-            Some(&gettext("This might leave the drive in a faulty state")),
-        );
+        let (heading, body, stop) = match self.imp().task.get() {
+            Task::Write => (
+                gettext("Stop Writing?"),
+                gettext("This might leave the drive in a faulty state"),
+                gettext("_Stop Writing"),
+            ),
+            Task::Backup => (
+                gettext("Stop Backing Up?"),
+                gettext("The incomplete image will be deleted"),
+                gettext("_Stop Backing Up"),
+            ),
+            Task::Format => (
+                gettext("Stop Formatting?"),
+                gettext("This might leave the drive without a partition table"),
+                gettext("_Stop Formatting"),
+            ),
+            Task::Wipe => (
+                gettext("Stop Wiping?"),
+                gettext("Data that was not overwritten yet can still be recovered"),
+                gettext("_Stop Wiping"),
+            ),
+            Task::Duplicate => (
+                gettext("Stop Duplicating?"),
+                gettext("Drives that are still being written might be left in a faulty state"),
+                gettext("_Stop Duplicating"),
+            ),
+        };
 
-        dialog.add_responses(&[
-            ("cancel", &gettext("_Cancel")),
-            ("stop", &gettext("_Stop Writing")),
-        ]);
+        let dialog = adw::AlertDialog::new(Some(&heading), Some(&body));
+
+        dialog.add_responses(&[("cancel", &gettext("_Cancel")), ("stop", &stop)]);
         dialog.set_response_appearance("stop", adw::ResponseAppearance::Destructive);
 
         dialog.connect_response(
@@ -293,24 +445,62 @@ impl ImpressionAppWindow {
 
     #[template_callback]
     fn flash_dialog(&self) {
-        let Some(selected_device) = self.selected_device_for_writing() else {
+        let selected_devices = self.selected_devices_for_writing();
+        if selected_devices.is_empty() {
             warn!("No device selected");
             return;
-        };
+        }
 
         let Some(selected_disk_image) = self.selected_image_file_for_reading() else {
             warn!("No disk image selected");
             return;
         };
 
-        let selected_device_display_string = selected_device.display_string.unwrap_or_default();
+        let image_size = self.imp().image_size.get();
+        if let Some(device) = selected_devices
+            .iter()
+            .find(|device| capacity::check(image_size, device.size).is_err())
+        {
+            let dialog = adw::AlertDialog::new(
+                Some(&gettext("Drive Too Small")),
+                Some(
+                    &gettext("The image needs {} but {} only holds {}")
+                        .replacen("{}", &get_size_string(image_size.unwrap_or_default()), 1)
+                        .replacen(
+                            "{}",
+                            device.display_string.as_deref().unwrap_or_default(),
+                            1,
+                        )
+                        .replacen("{}", &get_size_string(device.size), 1),
+                ),
+            );
+            dialog.add_response("close", &gettext("_Close"));
+            dialog.present(Some(self));
+            return;
+        }
+
+        let selected_devices_display_string = selected_devices
+            .iter()
+            .filter_map(|device| device.display_string.as_deref())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut body = gettext("You will lose all data stored on {}")
+            .replace("{}", &selected_devices_display_string);
+        if image_size.is_none() && matches!(selected_disk_image, DiskImage::Local { .. }) {
+            body.push_str("\n\n");
+            body.push_str(&gettext(
+                "The size of the image is not known yet, writing fails if it does not fit",
+            ));
+        }
 
         let flash_dialog = adw::AlertDialog::new(
-            Some(&gettext("Erase Drive?")),
-            Some(
-                &gettext("You will lose all data stored on {}")
-                    .replace("{}", &selected_device_display_string),
-            ),
+            Some(&if selected_devices.len() == 1 {
+                gettext("Erase Drive?")
+            } else {
+                gettext("Erase Drives?")
+            }),
+            Some(&body),
         );
 
         flash_dialog.add_response("cancel", &gettext("_Cancel"));
@@ -324,7 +514,7 @@ impl ImpressionAppWindow {
                 self,
                 move |_, response_id| {
                     if response_id == "erase" {
-                        this.flash(&selected_device.object, &selected_disk_image);
+                        this.flash(&selected_devices, &selected_disk_image);
                     }
                 }
             ),
@@ -333,359 +523,1662 @@ impl ImpressionAppWindow {
         flash_dialog.present(Some(self));
     }
 
-    fn flash(&self, device_for_writing: &udisks::Object, disk_image_for_reading: &DiskImage) {
-        self.imp().main_stack.set_visible_child_name("status");
-        self.imp().stack.set_visible_child_name("flashing");
-        self.imp().progress_bar.set_fraction(0.);
-        glib::MainContext::default().iteration(true);
-        self.set_is_running(true);
+    fn flash(
+        &self,
+        devices_for_writing: &[device_list::DeviceMetadata],
+        disk_image_for_reading: &DiskImage,
+    ) {
+        self.start_task(Task::Write);
+
+        let options = self.flash_options();
+
+        let initial_phase = match disk_image_for_reading {
+            DiskImage::Online { download_path, .. }
+                if options.write_while_downloading || download_path.is_none() =>
+            {
+                FlashPhase::DownloadAndCopy {
+                    downloaded: 0,
+                    total: None,
+                }
+            }
+            DiskImage::Online { .. } => FlashPhase::Download {
+                downloaded: 0,
+                total: None,
+            },
+            DiskImage::Local { .. } | DiskImage::Drive { .. } => FlashPhase::Copy,
+        };
+
+        if matches!(initial_phase, FlashPhase::Copy) {
+            let flashing_page = &self.imp().flashing_page;
+            flashing_page.set_description(Some(&gettext("Do not remove the drive")));
+            flashing_page.set_title(&gettext("Writing"));
+            flashing_page.set_icon_name(Some("flash-symbolic"));
+        } else {
+            self.update_flashing_page(&initial_phase);
+        }
+
+        let destinations = devices_for_writing
+            .iter()
+            .map(|device| {
+                Destination::new(
+                    device.object.clone(),
+                    FlashStatus::Active(initial_phase.clone(), Progress::Fraction(0.0)),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // A single drive is followed by the page itself
+        let rows = if destinations.len() > 1 {
+            devices_for_writing
+                .iter()
+                .zip(&destinations)
+                .map(|(device, destination)| {
+                    DestinationRow::new(
+                        device.label.as_deref().unwrap_or_default(),
+                        destination.clone(),
+                        options.verify,
+                    )
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        for row in &rows {
+            self.imp().destinations_list.append(&row.row);
+        }
+        self.imp().destinations_list.set_visible(!rows.is_empty());
 
         let current_status = std::sync::Arc::<std::sync::Mutex<FlashStatus>>::new(
-            std::sync::Mutex::new(FlashStatus::Active(
-                match disk_image_for_reading {
-                    DiskImage::Online { .. } => FlashPhase::Download,
-                    DiskImage::Local { .. } => FlashPhase::Copy,
-                },
-                Progress::Fraction(0.0),
-            )),
+            std::sync::Mutex::new(FlashStatus::Active(initial_phase, Progress::Fraction(0.0))),
         );
 
         let flash_job = FlashRequest::new(
             disk_image_for_reading.clone(),
-            device_for_writing.clone(),
+            destinations,
             current_status.clone(),
             self.imp().is_running.clone(),
+            options,
         );
 
-        let flashing_page = &self.imp().flashing_page;
-        if matches!(disk_image_for_reading, DiskImage::Online { .. }) {
-            flashing_page.set_description(Some(&gettext(
-                "Writing will begin once the download is completed",
-            )));
-            flashing_page.set_title(&gettext("Downloading Image"));
-            flashing_page.set_icon_name(Some("folder-download-symbolic"));
-        } else {
-            flashing_page.set_description(Some(&gettext("Do not remove the drive")));
-            flashing_page.set_title(&gettext("Writing"));
-            flashing_page.set_icon_name(Some("flash-symbolic"));
-        }
-        glib::timeout_add_seconds_local(
-            1,
-            clone!(
-                #[weak(rename_to=this)]
-                self,
-                #[upgrade_or]
-                glib::ControlFlow::Break,
-                move || {
-                    if !this.is_running() {
-                        return glib::ControlFlow::Break;
-                    }
-                    let state = {
-                        if let Ok(lock) = current_status.lock() {
-                            lock.clone()
-                        } else {
-                            return glib::ControlFlow::Break;
-                        }
-                    };
-                    match state {
-                        FlashStatus::Active(phase, progress) => {
-                            this.update_flashing_page(&phase);
-                            match progress {
-                                Progress::Fraction(x) => {
-                                    this.imp().progress_bar.set_fraction(x);
-                                }
-                                Progress::Pulse => {
-                                    this.imp().progress_bar.pulse();
-                                }
-                            }
-                            glib::MainContext::default().iteration(true);
-                        }
-                        FlashStatus::Done(Some(error_message)) => {
-                            this.imp().stack.set_visible_child_name("failure");
-                            this.imp().error_message_label.set_label(&error_message);
-                            this.imp()
-                                .error_message_label
-                                .set_visible(!error_message.is_empty());
-                            this.set_is_running(false);
-                            this.send_notification(gettext("Failed to write image"));
-                            glib::MainContext::default().iteration(true);
-                            return glib::ControlFlow::Break;
-                        }
-                        FlashStatus::Done(None) => {
-                            this.imp().stack.set_visible_child_name("success");
-                            this.set_is_running(false);
-                            this.send_notification(gettext("Image Written"));
-                            glib::MainContext::default().iteration(true);
-                            return glib::ControlFlow::Break;
-                        }
-                    }
-                    glib::ControlFlow::Continue
-                }
-            ),
-        );
+        self.watch_status(current_status, rows);
 
         runtime().spawn(flash_job.perform());
     }
 
-    fn update_flashing_page(&self, phase: &FlashPhase) {
-        let flashing_page = &self.imp().flashing_page;
-        match phase {
-            FlashPhase::Download => {
-                flashing_page.set_description(Some(&gettext(
-                    "Writing will begin once the download is completed",
-                )));
-                flashing_page.set_title(&gettext("Downloading Image"));
-                flashing_page.set_icon_name(Some("folder-download-symbolic"));
-            }
-            FlashPhase::Copy => {
-                flashing_page.set_description(Some(&gettext("This could take a while")));
-                flashing_page.set_title(&gettext("Writing"));
-                flashing_page.set_icon_name(Some("flash-symbolic"));
-            }
+    fn flash_options(&self) -> FlashOptions {
+        let settings = &self.imp().settings;
+        FlashOptions {
+            verify: settings.boolean("verify-after-writing"),
+            write_while_downloading: settings.boolean("write-while-downloading"),
+            download_attempts: settings.uint("download-attempts"),
+            skip_zero_blocks: settings.boolean("skip-zero-blocks"),
+            test_image: settings.boolean("test-compressed-images"),
         }
     }
 
-    fn send_notification(&self, message: String) {
-        if !self.is_active() {
-            runtime().spawn(async move {
-                send_notification(Some(&message)).await;
-            });
-        }
+    #[template_callback]
+    fn show_clone_page(&self) {
+        self.imp().navigation.push_by_tag("clone");
     }
 
-    fn selected_image_file_for_reading(&self) -> Option<DiskImage> {
+    /// Uses `device` as the source, to be copied onto another drive.
+    pub fn select_source_drive(&self, device: device_list::DeviceMetadata) {
         self.imp()
             .selected_image_file_for_reading
-            .borrow()
-            .to_owned()
-    }
+            .replace(Some(DiskImage::Drive {
+                name: device.label.unwrap_or_default(),
+                object: device.object,
+            }));
 
-    fn selected_device_object_path_for_writing(&self) -> Option<String> {
-        self.imp()
-            .selected_device_object_path_for_writing
-            .borrow()
-            .clone()
+        self.load_stored();
     }
 
-    pub fn set_selected_device_object_path_for_writing(
-        &self,
-        selected_device_object_path: Option<String>,
-    ) {
-        self.imp()
-            .flash_button
-            .set_sensitive(selected_device_object_path.is_some());
-        self.imp()
-            .selected_device_object_path_for_writing
-            .replace(selected_device_object_path);
+    #[template_callback]
+    fn show_backup_page(&self) {
+        self.imp().navigation.push_by_tag("backup");
     }
 
-    fn set_is_running(&self, is_running: bool) {
-        self.imp()
-            .is_running
-            .store(is_running, std::sync::atomic::Ordering::SeqCst);
-    }
+    pub fn backup_dialog(&self, device: device_list::DeviceMetadata) {
+        let settings = &self.imp().settings;
+        let options = BackupOptions {
+            compression: BackupCompression::from_setting(&settings.string("backup-compression")),
+            used_space_only: settings.boolean("backup-used-space-only"),
+            bmap: settings.boolean("backup-bmap"),
+        };
 
-    fn is_running(&self) -> bool {
-        self.imp()
-            .is_running
-            .load(std::sync::atomic::Ordering::SeqCst)
-    }
+        let name = device
+            .label
+            .as_deref()
+            .ok()
+            .filter(|label| !label.is_empty())
+            .unwrap_or("drive")
+            .replace('/', "-");
 
-    fn selected_device_for_writing(&self) -> Option<device_list::DeviceMetadata> {
-        let object_path = self.selected_device_object_path_for_writing();
-        object_path.and_then(|object_path| {
-            self.imp()
-                .available_devices
-                .borrow()
-                .iter()
-                .find(|x| x.object.object_path().to_string() == object_path)
-                .cloned()
-        })
-    }
+        let filter = gtk::FileFilter::new();
+        filter.add_pattern("*.img");
+        filter.add_pattern("*.img.xz");
+        filter.add_pattern("*.img.zst");
+        filter.set_name(Some(&gettext("Disk Images")));
 
-    #[template_callback]
-    fn cancel_clicked(&self) {
-        if self.is_running() {
-            self.cancel_request(false);
-        } else {
-            warn!("Cancel button clicked while not running, how did we get here?");
-            self.imp().main_stack.set_visible_child_name("choose");
-            self.refresh_devices();
-        }
-    }
+        let model = gio::ListStore::new::<gtk::FileFilter>();
+        model.append(&filter);
 
-    #[template_callback]
-    fn done_clicked(&self) {
-        let window = self.imp();
-        window.available_devices.replace(vec![]);
-        window.main_stack.set_visible_child_name("status");
-        window.stack.set_visible_child_name("no_devices");
-        window.open_image_button.grab_focus();
-    }
+        gtk::FileDialog::builder()
+            .modal(true)
+            .filters(&model)
+            .initial_name(format!("{name}.{}", options.compression.extension()))
+            .default_filter(&filter)
+            .build()
+            .save(
+                Some(self),
+                gio::Cancellable::NONE,
+                clone!(
+                    #[weak(rename_to=window)]
+                    self,
+                    move |file| match file {
+                        Ok(file) => {
+                            let Some(path) = file.path() else {
+                                error!("Failed to get file path for {file:?}");
+                                return;
+                            };
 
-    #[template_callback]
-    fn try_again_clicked(&self) {
-        self.refresh_devices();
-        self.imp().main_stack.set_visible_child_name("choose");
+                            info!("Backing up to: {}", path.display());
+                            window.backup(&device.object, path, options);
+                        }
+                        Err(e) => {
+                            error!("Failed to open file dialog: {e}");
+                        }
+                    }
+                ),
+            );
     }
 
-    #[template_callback]
-    fn on_architecture_changed(&self) {
-        let imp = self.imp();
+    fn backup(&self, device: &udisks::Object, path: PathBuf, options: BackupOptions) {
+        self.start_task(Task::Backup);
+        self.update_flashing_page(&FlashPhase::Backup);
 
-        match imp.architecture.selected() {
-            0 => {
-                imp.amd_distros.set_visible(true);
-                imp.arm_distros.set_visible(false);
-            }
-            1 => {
-                imp.amd_distros.set_visible(false);
-                imp.arm_distros.set_visible(true);
-            }
-            _ => {}
+        let current_status = std::sync::Arc::<std::sync::Mutex<FlashStatus>>::new(
+            std::sync::Mutex::new(FlashStatus::Active(FlashPhase::Backup, Progress::Pulse)),
+        );
+
+        let backup_job = BackupRequest::new(
+            device.clone(),
+            path,
+            options,
+            current_status.clone(),
+            self.imp().is_running.clone(),
+        );
+
+        self.watch_status(current_status, Vec::new());
+
+        runtime().spawn(backup_job.perform());
+    }
+
+    /// Saves what was written to which drive in this session as CSV or JSON lines.
+    fn export_report_dialog(&self) {
+        if report::is_empty() {
+            self.imp()
+                .toast_overlay
+                .add_toast(adw::Toast::new(&gettext("No drives were written yet")));
+            return;
         }
+
+        let csv_filter = gtk::FileFilter::new();
+        csv_filter.add_pattern("*.csv");
+        csv_filter.set_name(Some(&gettext("CSV Files")));
+
+        let json_filter = gtk::FileFilter::new();
+        json_filter.add_pattern("*.jsonl");
+        json_filter.set_name(Some(&gettext("JSON Lines Files")));
+
+        let model = gio::ListStore::new::<gtk::FileFilter>();
+        model.append(&csv_filter);
+        model.append(&json_filter);
+
+        gtk::FileDialog::builder()
+            .modal(true)
+            .filters(&model)
+            .initial_name(format!(
+                "impression-report-{}.csv",
+                chrono::Local::now().format("%Y-%m-%d-%H%M%S")
+            ))
+            .default_filter(&csv_filter)
+            .build()
+            .save(
+                Some(self),
+                gio::Cancellable::NONE,
+                clone!(
+                    #[weak(rename_to=window)]
+                    self,
+                    move |file| match file {
+                        Ok(file) => {
+                            let Some(path) = file.path() else {
+                                error!("Failed to get file path for {file:?}");
+                                return;
+                            };
+
+                            if let Err(e) = report::export(&path) {
+                                error!("Failed to export the report: {e}");
+                                window
+                                    .imp()
+                                    .toast_overlay
+                                    .add_toast(adw::Toast::new(&gettext(
+                                        "Could not save the report",
+                                    )));
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to open file dialog: {e}");
+                        }
+                    }
+                ),
+            );
     }
 
-    fn setup_callbacks(&self) {
+    #[template_callback]
+    fn show_duplicator_page(&self) {
+        self.load_duplicator_allowlist();
+        self.update_duplicator_page();
+        self.imp().navigation.push_by_tag("duplicator");
+    }
+
+    fn duplicator_allowlist(&self) -> Vec<DriveModel> {
+        DriveModel::from_setting(
+            self.imp()
+                .settings
+                .value("duplicator-allowlist")
+                .get::<Vec<(String, String, u64)>>()
+                .unwrap_or_default(),
+        )
+    }
+
+    fn set_duplicator_allowlist(&self, allowlist: &[DriveModel]) {
+        if let Err(e) = self.imp().settings.set_value(
+            "duplicator-allowlist",
+            &DriveModel::to_setting(allowlist).to_variant(),
+        ) {
+            error!("Failed to save the duplicator allowlist: {e}");
+        }
+
+        self.load_duplicator_allowlist();
+    }
+
+    /// Lets the duplicator erase drives of the same model as `device`.
+    pub fn allow_for_duplicator(&self, device: device_list::DeviceMetadata) {
+        let Some(model) = device.model else {
+            return;
+        };
+
+        let mut allowlist = self.duplicator_allowlist();
+        if !allowlist.contains(&model) {
+            allowlist.push(model);
+            self.set_duplicator_allowlist(&allowlist);
+        }
+    }
+
+    fn load_duplicator_allowlist(&self) {
+        let imp = self.imp();
+        let allowlist = self.duplicator_allowlist();
+
+        imp.duplicator_allowlist.remove_all();
+        for model in &allowlist {
+            let remove_button = gtk::Button::builder()
+                .icon_name("user-trash-symbolic")
+                .tooltip_text(gettext("Remove"))
+                .valign(gtk::Align::Center)
+                .css_classes(["flat"])
+                .build();
+
+            let row = adw::ActionRow::builder()
+                .title(model.name())
+                .subtitle(model.size_string())
+                .build();
+            row.add_suffix(&remove_button);
+
+            let model = model.clone();
+            remove_button.connect_clicked(clone!(
+                #[weak(rename_to=this)]
+                self,
+                move |_| {
+                    let mut allowlist = this.duplicator_allowlist();
+                    allowlist.retain(|x| *x != model);
+                    this.set_duplicator_allowlist(&allowlist);
+                }
+            ));
+
+            imp.duplicator_allowlist.append(&row);
+        }
+
+        imp.duplicator_allowlist.set_visible(!allowlist.is_empty());
+        imp.duplicator_start_button
+            .set_sensitive(!allowlist.is_empty() || self.is_duplicating());
+    }
+
+    fn is_duplicating(&self) -> bool {
+        self.is_running() && self.imp().task.get() == Task::Duplicate
+    }
+
+    #[template_callback]
+    fn duplicator_start_clicked(&self) {
+        if !self.is_duplicating() {
+            self.start_duplicator();
+        } else if self
+            .imp()
+            .duplicator_rows
+            .borrow()
+            .iter()
+            .any(|row| !row.finished())
+        {
+            self.cancel_request(false);
+        } else {
+            self.set_is_running(false);
+            self.update_duplicator_page();
+        }
+    }
+
+    /// Writes the selected image to every allowed drive that is plugged in from now on.
+    fn start_duplicator(&self) {
+        let Some(source) = self.selected_image_file_for_reading() else {
+            warn!("No disk image selected");
+            return;
+        };
+
+        let imp = self.imp();
+        let options = self.flash_options();
+
+        imp.task.set(Task::Duplicate);
+        self.set_is_running(true);
+        imp.duplicator_rows.borrow_mut().clear();
+        imp.duplicator_drives_list.remove_all();
+        self.update_duplicator_page();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        runtime().spawn(
+            Duplicator::new(
+                source,
+                self.duplicator_allowlist(),
+                options,
+                imp.is_running.clone(),
+                sender,
+            )
+            .perform(),
+        );
+
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to=this)]
+            self,
+            async move {
+                while let Some(new_drive) = receiver.recv().await {
+                    this.add_duplicator_drive(new_drive, options.verify);
+                }
+            }
+        ));
+
         timeout_add_seconds_local(
-            2,
+            1,
             clone!(
                 #[weak(rename_to=this)]
                 self,
                 #[upgrade_or]
                 glib::ControlFlow::Break,
                 move || {
-                    let main_stack = this.imp().main_stack.visible_child_name();
-                    let current_stack = this.imp().stack.visible_child_name();
-                    let current_page = this
-                        .imp()
-                        .navigation
-                        .visible_page()
-                        .and_then(|x| x.tag())
-                        .map(|x| x.as_str().to_owned());
-                    if matches!(main_stack.as_deref(), Some("status"))
-                        && matches!(current_stack.as_deref(), Some("no_devices"))
-                        || matches!(main_stack.as_deref(), Some("choose"))
-                            && matches!(current_page.as_deref(), Some("device_list" | "welcome"))
-                    {
-                        this.refresh_devices();
+                    this.update_duplicator_page();
+                    if this.is_duplicating() {
+                        glib::ControlFlow::Continue
+                    } else {
+                        glib::ControlFlow::Break
                     }
-                    glib::ControlFlow::Continue
                 }
             ),
         );
+    }
 
-        self.refresh_devices();
+    fn add_duplicator_drive(&self, new_drive: NewDrive, verify: bool) {
+        let imp = self.imp();
+        let title = format!("{} ({})", new_drive.model.name(), new_drive.device);
 
-        timeout_add_seconds_local(
-            10,
+        if let Some(destination) = new_drive.destination {
+            let row = DestinationRow::new(&title, destination, verify);
+            imp.duplicator_drives_list.prepend(&row.row);
+            imp.duplicator_rows.borrow_mut().push(row);
+        } else {
+            let row = adw::ActionRow::builder()
+                .title(title)
+                .subtitle(gettext("Not on the allowlist, left untouched"))
+                .build();
+            imp.duplicator_drives_list.prepend(&row);
+        }
+
+        imp.duplicator_drives_group.set_visible(true);
+        self.update_duplicator_page();
+    }
+
+    /// Follows the drives that the duplicator writes to, along with how many of them are done.
+    fn update_duplicator_page(&self) {
+        let imp = self.imp();
+        let duplicating = self.is_duplicating();
+        let rows = imp.duplicator_rows.borrow();
+
+        for row in rows.iter() {
+            row.update();
+        }
+
+        let written = rows.iter().filter(|row| row.succeeded()).count();
+        let failed = rows.iter().filter(|row| row.finished()).count() - written;
+        imp.duplicator_drives_group.set_description(Some(
+            &gettext("{} written, {} failed, {} in progress")
+                .replacen("{}", &written.to_string(), 1)
+                .replacen("{}", &failed.to_string(), 1)
+                .replacen("{}", &(rows.len() - written - failed).to_string(), 1),
+        ));
+
+        imp.duplicator_start_button.set_label(&if duplicating {
+            gettext("Stop")
+        } else {
+            gettext("Start")
+        });
+        imp.duplicator_page.set_can_pop(!duplicating);
+        imp.duplicator_candidates_group.set_visible(!duplicating);
+        imp.duplicator_allowlist.set_sensitive(!duplicating);
+    }
+
+    #[template_callback]
+    fn show_format_page(&self) {
+        self.imp().navigation.push_by_tag("format");
+    }
+
+    pub fn format_dialog(&self, device: device_list::DeviceMetadata) {
+        let label_row = adw::EntryRow::builder().title(gettext("Name")).build();
+
+        let filesystem_row = adw::ComboRow::builder()
+            .title(gettext("File System"))
+            .model(&gtk::StringList::new(
+                &Filesystem::ALL.map(Filesystem::name),
+            ))
+            .build();
+
+        let scheme_row = adw::ComboRow::builder()
+            .title(gettext("Partition Table"))
+            .model(&gtk::StringList::new(
+                &PartitionScheme::ALL.map(PartitionScheme::name),
+            ))
+            .build();
+
+        let list = gtk::ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .css_classes(["boxed-list"])
+            .build();
+        list.append(&label_row);
+        list.append(&filesystem_row);
+        list.append(&scheme_row);
+
+        let format_dialog = adw::AlertDialog::new(
+            Some(&gettext("Format Drive?")),
+            Some(
+                &gettext("You will lose all data stored on {}")
+                    .replace("{}", &device.display_string.clone().unwrap_or_default()),
+            ),
+        );
+        format_dialog.set_extra_child(Some(&list));
+
+        format_dialog.add_responses(&[
+            ("cancel", &gettext("_Cancel")),
+            ("format", &gettext("_Format")),
+        ]);
+        format_dialog.set_response_appearance("format", adw::ResponseAppearance::Destructive);
+
+        format_dialog.connect_response(
+            None,
             clone!(
                 #[weak(rename_to=this)]
                 self,
-                #[upgrade_or]
-                glib::ControlFlow::Break,
-                move || {
-                    let main_stack = this.imp().stack.visible_child_name();
-                    let current_page = this
-                        .imp()
-                        .navigation
-                        .visible_page()
-                        .and_then(|x| x.tag())
-                        .map(|x| x.as_str().to_owned());
-                    if matches!(main_stack.as_deref(), Some("choose"))
-                        && matches!(current_page.as_deref(), Some("welcome"))
-                        && this.imp().offline_screen.is_visible()
-                    {
-                        this.get_distros();
+                move |_, response_id| {
+                    if response_id != "format" {
+                        return;
                     }
-                    glib::ControlFlow::Continue
+
+                    let options = FormatOptions {
+                        scheme: PartitionScheme::ALL
+                            .get(scheme_row.selected() as usize)
+                            .copied()
+                            .unwrap_or(PartitionScheme::Gpt),
+                        filesystem: Filesystem::ALL
+                            .get(filesystem_row.selected() as usize)
+                            .copied()
+                            .unwrap_or(Filesystem::Fat32),
+                        label: label_row.text().trim().to_owned(),
+                    };
+
+                    this.format(&device.object, options);
                 }
             ),
         );
 
-        self.get_distros();
+        format_dialog.present(Some(self));
     }
 
-    fn get_distros(&self) {
-        let Some(downloadable_distros) = self
-            .imp()
-            .settings
-            .value("downloadable-distros")
-            .get::<Vec<(String, Option<String>, bool)>>()
-        else {
-            self.load_distros(&self.imp().amd_distros, vec![]);
-            self.load_distros(&self.imp().arm_distros, vec![]);
-            self.imp().download_spinner.set_visible(false);
-            self.imp().offline_screen.set_visible(false);
-            return;
-        };
+    fn format(&self, device: &udisks::Object, options: FormatOptions) {
+        self.start_task(Task::Format);
+        self.update_flashing_page(&FlashPhase::Format);
 
-        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let current_status = std::sync::Arc::<std::sync::Mutex<FlashStatus>>::new(
+            std::sync::Mutex::new(FlashStatus::Active(FlashPhase::Format, Progress::Pulse)),
+        );
 
-        runtime().spawn(async move {
-            if let Some(osinfo_db_url) = get_osinfo_db_url().await {
-                let distros = collect_online_distros(&osinfo_db_url, &downloadable_distros).await;
-                sender.send(distros).expect("Concurrency Issues");
-            } else {
-                sender.send(None).expect("Concurrency Issues");
-            }
+        let format_job = FormatRequest::new(
+            device.clone(),
+            options,
+            current_status.clone(),
+            self.imp().is_running.clone(),
+        );
+
+        self.watch_status(current_status, Vec::new());
+
+        runtime().spawn(format_job.perform());
+    }
+
+    #[template_callback]
+    fn show_wipe_page(&self) {
+        self.imp().navigation.push_by_tag("wipe");
+    }
+
+    pub fn wipe_dialog(&self, device: device_list::DeviceMetadata) {
+        let methods = WipeMethod::ALL.map(|method| match method {
+            WipeMethod::Zero => gettext("Zeroes"),
+            WipeMethod::Random => gettext("Random Data"),
+            WipeMethod::RandomVerified => gettext("Random Data, Verified"),
         });
 
-        glib::spawn_future_local(clone!(
-            #[weak(rename_to=this)]
-            self,
-            async move {
-                if let Ok(online_distros) = receiver.await {
-                    if let Some((amd_distros, arm_distros)) = online_distros {
-                        this.load_distros(&this.imp().amd_distros, amd_distros);
-                        this.load_distros(&this.imp().arm_distros, arm_distros);
-                        this.imp().download_spinner.set_visible(false);
-                        this.imp().offline_screen.set_visible(false);
-                        this.imp().distros.set_visible(true);
-                        this.imp().architecture.set_sensitive(true);
-                    } else {
-                        this.imp().download_spinner.set_visible(false);
-                        this.imp().offline_screen.set_visible(true);
+        let method_row = adw::ComboRow::builder()
+            .title(gettext("Overwrite With"))
+            .model(&gtk::StringList::new(
+                &methods.each_ref().map(String::as_str),
+            ))
+            .build();
+
+        let list = gtk::ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .css_classes(["boxed-list"])
+            .build();
+        list.append(&method_row);
+
+        let wipe_dialog = adw::AlertDialog::new(
+            Some(&gettext("Wipe Drive?")),
+            Some(
+                &gettext("All data stored on {} will be overwritten and can't be recovered")
+                    .replace("{}", &device.display_string.clone().unwrap_or_default()),
+            ),
+        );
+        wipe_dialog.set_extra_child(Some(&list));
+
+        wipe_dialog.add_responses(&[("cancel", &gettext("_Cancel")), ("wipe", &gettext("_Wipe"))]);
+        wipe_dialog.set_response_appearance("wipe", adw::ResponseAppearance::Destructive);
+
+        wipe_dialog.connect_response(
+            None,
+            clone!(
+                #[weak(rename_to=this)]
+                self,
+                move |_, response_id| {
+                    if response_id != "wipe" {
+                        return;
                     }
+
+                    let method = WipeMethod::ALL
+                        .get(method_row.selected() as usize)
+                        .copied()
+                        .unwrap_or(WipeMethod::Zero);
+
+                    this.wipe(&device.object, method);
                 }
-            }
-        ));
+            ),
+        );
+
+        wipe_dialog.present(Some(self));
+    }
+
+    fn wipe(&self, device: &udisks::Object, method: WipeMethod) {
+        let imp = self.imp();
+
+        self.start_task(Task::Wipe);
+        self.update_flashing_page(&FlashPhase::Wipe);
+
+        if let Ok(mut report) = imp.erasure_report.lock() {
+            *report = None;
+        }
+
+        let current_status = std::sync::Arc::<std::sync::Mutex<FlashStatus>>::new(
+            std::sync::Mutex::new(FlashStatus::Active(FlashPhase::Wipe, Progress::Pulse)),
+        );
+
+        let wipe_job = WipeRequest::new(
+            device.clone(),
+            method,
+            current_status.clone(),
+            imp.is_running.clone(),
+            imp.erasure_report.clone(),
+        );
+
+        self.watch_status(current_status, Vec::new());
+
+        runtime().spawn(wipe_job.perform());
+    }
+
+    /// Saves the report of the last wipe as JSON or plain text.
+    #[template_callback]
+    fn save_erasure_report(&self) {
+        let Some(report) = self
+            .imp()
+            .erasure_report
+            .lock()
+            .ok()
+            .and_then(|report| report.clone())
+        else {
+            warn!("No erasure report to save");
+            return;
+        };
+
+        let text_filter = gtk::FileFilter::new();
+        text_filter.add_pattern("*.txt");
+        text_filter.set_name(Some(&gettext("Text Files")));
+
+        let json_filter = gtk::FileFilter::new();
+        json_filter.add_pattern("*.json");
+        json_filter.set_name(Some(&gettext("JSON Files")));
+
+        let model = gio::ListStore::new::<gtk::FileFilter>();
+        model.append(&text_filter);
+        model.append(&json_filter);
+
+        gtk::FileDialog::builder()
+            .modal(true)
+            .filters(&model)
+            .initial_name(format!(
+                "erasure-report-{}.txt",
+                report.finished.format("%Y-%m-%d-%H%M%S")
+            ))
+            .default_filter(&text_filter)
+            .build()
+            .save(
+                Some(self),
+                gio::Cancellable::NONE,
+                clone!(
+                    #[weak(rename_to=window)]
+                    self,
+                    move |file| match file {
+                        Ok(file) => {
+                            let Some(path) = file.path() else {
+                                error!("Failed to get file path for {file:?}");
+                                return;
+                            };
+
+                            if let Err(e) = report.save(&path) {
+                                error!("Failed to save the erasure report: {e}");
+                                window
+                                    .imp()
+                                    .toast_overlay
+                                    .add_toast(adw::Toast::new(&gettext(
+                                        "Could not save the report",
+                                    )));
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to open file dialog: {e}");
+                        }
+                    }
+                ),
+            );
+    }
+
+    /// Shows the flashing page and sets up the pages that follow it for `task`.
+    fn start_task(&self, task: Task) {
+        let imp = self.imp();
+
+        imp.task.set(task);
+        imp.destinations_list.remove_all();
+        imp.destinations_list.set_visible(false);
+        imp.results_list.remove_all();
+        imp.results_list.set_visible(false);
+        imp.success_page
+            .set_description(Some(&gettext("The drive can be safely removed")));
+
+        match task {
+            Task::Write | Task::Duplicate => {
+                imp.success_page.set_title(&gettext("Writing Completed"));
+                imp.failure_page.set_title(&gettext("Writing Unsuccessful"));
+            }
+            Task::Backup => {
+                imp.success_page.set_title(&gettext("Backup Completed"));
+                imp.failure_page.set_title(&gettext("Backup Unsuccessful"));
+            }
+            Task::Format => {
+                imp.success_page.set_title(&gettext("Formatting Completed"));
+                imp.failure_page
+                    .set_title(&gettext("Formatting Unsuccessful"));
+            }
+            Task::Wipe => {
+                imp.success_page.set_title(&gettext("Wiping Completed"));
+                imp.failure_page.set_title(&gettext("Wiping Unsuccessful"));
+            }
+        }
+
+        imp.success_report_button.set_visible(task == Task::Wipe);
+        imp.failure_report_button.set_visible(task == Task::Wipe);
+
+        imp.main_stack.set_visible_child_name("status");
+        imp.stack.set_visible_child_name("flashing");
+        imp.progress_bar.set_fraction(0.);
+        glib::MainContext::default().iteration(true);
+        self.set_is_running(true);
+    }
+
+    /// Follows `current_status` on the flashing page until the job is done,
+    /// along with the drives in `rows` when writing to several of them.
+    fn watch_status(
+        &self,
+        current_status: std::sync::Arc<std::sync::Mutex<FlashStatus>>,
+        rows: Vec<DestinationRow>,
+    ) {
+        glib::timeout_add_seconds_local(
+            1,
+            clone!(
+                #[weak(rename_to=this)]
+                self,
+                #[upgrade_or]
+                glib::ControlFlow::Break,
+                move || {
+                    if !this.is_running() {
+                        return glib::ControlFlow::Break;
+                    }
+                    let state = {
+                        if let Ok(lock) = current_status.lock() {
+                            lock.clone()
+                        } else {
+                            return glib::ControlFlow::Break;
+                        }
+                    };
+                    for row in &rows {
+                        row.update();
+                    }
+                    match state {
+                        FlashStatus::Active(phase, progress) => {
+                            this.update_flashing_page(&phase);
+                            match progress {
+                                Progress::Fraction(x) => {
+                                    this.imp().progress_bar.set_fraction(x);
+                                }
+                                Progress::Pulse => {
+                                    this.imp().progress_bar.pulse();
+                                }
+                            }
+                            glib::MainContext::default().iteration(true);
+                        }
+                        FlashStatus::Done(Some(error_message)) => {
+                            this.imp().stack.set_visible_child_name("failure");
+                            this.imp().error_message_label.set_label(&error_message);
+                            this.imp()
+                                .error_message_label
+                                .set_visible(!error_message.is_empty());
+                            this.set_is_running(false);
+                            this.send_notification(match this.imp().task.get() {
+                                Task::Write | Task::Duplicate => gettext("Failed to write image"),
+                                Task::Backup => gettext("Failed to back up drive"),
+                                Task::Format => gettext("Failed to format drive"),
+                                Task::Wipe => gettext("Failed to wipe drive"),
+                            });
+                            glib::MainContext::default().iteration(true);
+                            return glib::ControlFlow::Break;
+                        }
+                        FlashStatus::Done(None) => {
+                            this.show_results(&rows);
+                            this.imp().stack.set_visible_child_name("success");
+                            this.set_is_running(false);
+                            this.send_notification(match this.imp().task.get() {
+                                Task::Write | Task::Duplicate => gettext("Image Written"),
+                                Task::Backup => gettext("Drive Backed Up"),
+                                Task::Format => gettext("Drive Formatted"),
+                                Task::Wipe => gettext("Drive Wiped"),
+                            });
+                            glib::MainContext::default().iteration(true);
+                            return glib::ControlFlow::Break;
+                        }
+                    }
+                    glib::ControlFlow::Continue
+                }
+            ),
+        );
+    }
+
+    /// Lists how writing to each of several drives ended on the success page.
+    fn show_results(&self, rows: &[DestinationRow]) {
+        let imp = self.imp();
+
+        if rows.is_empty() {
+            return;
+        }
+
+        for row in rows {
+            imp.destinations_list.remove(&row.row);
+            imp.results_list.append(&row.row);
+        }
+        imp.results_list.set_visible(true);
+
+        let written = rows.iter().filter(|row| row.succeeded()).count();
+        imp.success_page
+            .set_description(Some(&if written == rows.len() {
+                gettext("The drives can be safely removed")
+            } else {
+                gettext("{} of {} drives were written")
+                    .replacen("{}", &written.to_string(), 1)
+                    .replacen("{}", &rows.len().to_string(), 1)
+            }));
+    }
+
+    fn update_flashing_page(&self, phase: &FlashPhase) {
+        let flashing_page = &self.imp().flashing_page;
+        match phase {
+            FlashPhase::Checksum => {
+                flashing_page.set_description(Some(&gettext(
+                    "The drive will not be changed until the checksum matches",
+                )));
+                flashing_page.set_title(&gettext("Checking Image"));
+                flashing_page.set_icon_name(Some("paper-symbolic"));
+            }
+            FlashPhase::Download { downloaded, total } => {
+                flashing_page.set_description(Some(&if total.is_none() && *downloaded > 0 {
+                    gettext("{} downloaded").replace("{}", &get_size_string(*downloaded))
+                } else {
+                    gettext("Writing will begin once the download is completed")
+                }));
+                flashing_page.set_title(&gettext("Downloading Image"));
+                flashing_page.set_icon_name(Some("folder-download-symbolic"));
+            }
+            FlashPhase::DownloadAndCopy { downloaded, total } => {
+                flashing_page.set_description(Some(&match total {
+                    Some(total) if *total > 0 => gettext("{}% downloaded")
+                        .replace("{}", &(downloaded * 100 / total).to_string()),
+                    _ if *downloaded > 0 => {
+                        gettext("{} downloaded").replace("{}", &get_size_string(*downloaded))
+                    }
+                    _ => gettext("Do not remove the drive"),
+                }));
+                flashing_page.set_title(&gettext("Downloading and Writing"));
+                flashing_page.set_icon_name(Some("folder-download-symbolic"));
+            }
+            FlashPhase::Retry { attempt, attempts } => {
+                flashing_page.set_description(Some(
+                    &gettext("Trying again, attempt {number} of {count}")
+                        .replace("{number}", &attempt.to_string())
+                        .replace("{count}", &attempts.to_string()),
+                ));
+                flashing_page.set_title(&gettext("Download Interrupted"));
+                flashing_page.set_icon_name(Some("network-offline-symbolic"));
+            }
+            FlashPhase::Copy => {
+                flashing_page.set_description(Some(&gettext("This could take a while")));
+                flashing_page.set_title(&gettext("Writing"));
+                flashing_page.set_icon_name(Some("flash-symbolic"));
+            }
+            FlashPhase::Test => {
+                flashing_page.set_description(Some(&gettext(
+                    "The drive will not be changed until the image is known to be intact",
+                )));
+                flashing_page.set_title(&gettext("Testing Image"));
+                flashing_page.set_icon_name(Some("paper-symbolic"));
+            }
+            FlashPhase::Verify => {
+                flashing_page.set_description(Some(&gettext("Checking the written data")));
+                flashing_page.set_title(&gettext("Verifying"));
+                flashing_page.set_icon_name(Some("check-round-outline-symbolic"));
+            }
+            FlashPhase::Backup => {
+                flashing_page.set_description(Some(&gettext("Do not remove the drive")));
+                flashing_page.set_title(&gettext("Backing Up"));
+                flashing_page.set_icon_name(Some("drive-removable-media-symbolic"));
+            }
+            FlashPhase::Format => {
+                flashing_page.set_description(Some(&gettext("Do not remove the drive")));
+                flashing_page.set_title(&gettext("Formatting"));
+                flashing_page.set_icon_name(Some("drive-removable-media-symbolic"));
+            }
+            FlashPhase::Wipe => {
+                flashing_page.set_description(Some(&gettext("This could take a while")));
+                flashing_page.set_title(&gettext("Wiping"));
+                flashing_page.set_icon_name(Some("drive-removable-media-symbolic"));
+            }
+        }
+    }
+
+    fn send_notification(&self, message: String) {
+        if !self.is_active() {
+            runtime().spawn(async move {
+                send_notification(Some(&message)).await;
+            });
+        }
+    }
+
+    fn selected_image_file_for_reading(&self) -> Option<DiskImage> {
+        self.imp()
+            .selected_image_file_for_reading
+            .borrow()
+            .to_owned()
+    }
+
+    pub fn set_device_selected_for_writing(&self, object_path: &str, selected: bool) {
+        let imp = self.imp();
+        let mut object_paths = imp.selected_device_object_paths_for_writing.borrow_mut();
+
+        object_paths.retain(|x| x != object_path);
+        if selected {
+            object_paths.push(object_path.to_owned());
+        }
+
+        imp.flash_button.set_sensitive(!object_paths.is_empty());
+    }
+
+    fn clear_devices_selected_for_writing(&self) {
+        self.imp()
+            .selected_device_object_paths_for_writing
+            .borrow_mut()
+            .clear();
+        self.imp().flash_button.set_sensitive(false);
+    }
+
+    fn set_is_running(&self, is_running: bool) {
+        self.imp()
+            .is_running
+            .store(is_running, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn is_running(&self) -> bool {
+        self.imp()
+            .is_running
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn selected_devices_for_writing(&self) -> Vec<device_list::DeviceMetadata> {
+        let object_paths = self.imp().selected_device_object_paths_for_writing.borrow();
+        self.imp()
+            .available_devices
+            .borrow()
+            .iter()
+            .filter(|x| object_paths.contains(&x.object.object_path().to_string()))
+            .cloned()
+            .collect()
+    }
+
+    #[template_callback]
+    fn cancel_clicked(&self) {
+        if self.is_running() {
+            self.cancel_request(false);
+        } else {
+            warn!("Cancel button clicked while not running, how did we get here?");
+            self.imp().main_stack.set_visible_child_name("choose");
+            self.refresh_devices();
+        }
+    }
+
+    #[template_callback]
+    fn done_clicked(&self) {
+        let window = self.imp();
+        window.available_devices.replace(vec![]);
+        window.main_stack.set_visible_child_name("status");
+        window.stack.set_visible_child_name("no_devices");
+        window.open_image_button.grab_focus();
+    }
+
+    #[template_callback]
+    fn try_again_clicked(&self) {
+        self.refresh_devices();
+        self.imp().main_stack.set_visible_child_name("choose");
+    }
+
+    #[template_callback]
+    fn on_architecture_changed(&self) {
+        let imp = self.imp();
+
+        match imp.architecture.selected() {
+            0 => {
+                imp.amd_distros.set_visible(true);
+                imp.arm_distros.set_visible(false);
+            }
+            1 => {
+                imp.amd_distros.set_visible(false);
+                imp.arm_distros.set_visible(true);
+            }
+            _ => {}
+        }
+    }
+
+    fn setup_callbacks(&self) {
+        timeout_add_seconds_local(
+            2,
+            clone!(
+                #[weak(rename_to=this)]
+                self,
+                #[upgrade_or]
+                glib::ControlFlow::Break,
+                move || {
+                    let main_stack = this.imp().main_stack.visible_child_name();
+                    let current_stack = this.imp().stack.visible_child_name();
+                    let current_page = this
+                        .imp()
+                        .navigation
+                        .visible_page()
+                        .and_then(|x| x.tag())
+                        .map(|x| x.as_str().to_owned());
+                    if matches!(main_stack.as_deref(), Some("status"))
+                        && matches!(current_stack.as_deref(), Some("no_devices"))
+                        || matches!(main_stack.as_deref(), Some("choose"))
+                            && matches!(
+                                current_page.as_deref(),
+                                Some(
+                                    "device_list"
+                                        | "welcome"
+                                        | "backup"
+                                        | "clone"
+                                        | "format"
+                                        | "wipe"
+                                        | "duplicator"
+                                )
+                            )
+                    {
+                        this.refresh_devices();
+                    }
+                    glib::ControlFlow::Continue
+                }
+            ),
+        );
+
+        self.refresh_devices();
+
+        timeout_add_seconds_local(
+            10,
+            clone!(
+                #[weak(rename_to=this)]
+                self,
+                #[upgrade_or]
+                glib::ControlFlow::Break,
+                move || {
+                    let main_stack = this.imp().stack.visible_child_name();
+                    let current_page = this
+                        .imp()
+                        .navigation
+                        .visible_page()
+                        .and_then(|x| x.tag())
+                        .map(|x| x.as_str().to_owned());
+                    if matches!(main_stack.as_deref(), Some("choose"))
+                        && matches!(current_page.as_deref(), Some("welcome"))
+                        && this.imp().offline_screen.is_visible()
+                    {
+                        this.get_distros();
+                    }
+                    glib::ControlFlow::Continue
+                }
+            ),
+        );
+
+        self.get_distros();
+    }
+
+    fn get_distros(&self) {
+        let Some(downloadable_distros) = self
+            .imp()
+            .settings
+            .value("downloadable-distros")
+            .get::<Vec<(String, Option<String>, bool)>>()
+        else {
+            self.load_distros(&self.imp().amd_distros, vec![]);
+            self.load_distros(&self.imp().arm_distros, vec![]);
+            self.imp().download_spinner.set_visible(false);
+            self.imp().offline_screen.set_visible(false);
+            return;
+        };
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        runtime().spawn(async move {
+            if let Some(osinfo_db_url) = get_osinfo_db_url().await {
+                let distros = collect_online_distros(&osinfo_db_url, &downloadable_distros).await;
+                sender.send(distros).expect("Concurrency Issues");
+            } else {
+                sender.send(None).expect("Concurrency Issues");
+            }
+        });
+
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to=this)]
+            self,
+            async move {
+                if let Ok(online_distros) = receiver.await {
+                    if let Some((amd_distros, arm_distros)) = online_distros {
+                        this.load_distros(&this.imp().amd_distros, amd_distros);
+                        this.load_distros(&this.imp().arm_distros, arm_distros);
+                        this.imp().download_spinner.set_visible(false);
+                        this.imp().offline_screen.set_visible(false);
+                        this.imp().distros.set_visible(true);
+                        this.imp().architecture.set_sensitive(true);
+                    } else {
+                        this.imp().download_spinner.set_visible(false);
+                        this.imp().offline_screen.set_visible(true);
+                    }
+                }
+            }
+        ));
+    }
+
+    fn load_distros(&self, target: &TemplateChild<gtk::ListBox>, distros: Vec<DistroRelease>) {
+        target.remove_all();
+        for distro in distros {
+            let action_row = adw::ActionRow::new();
+            action_row.set_title(&distro.name);
+            if let Some(subtitle) = &distro.version {
+                action_row.set_subtitle(subtitle);
+            }
+            let next_image = gtk::Image::new();
+            next_image.set_icon_name(Some("go-next-symbolic"));
+            action_row.add_suffix(&next_image);
+            action_row.set_activatable_widget(Some(&next_image));
+            action_row.connect_activated(clone!(
+                #[weak(rename_to=this)]
+                self,
+                move |_| {
+                    if this.imp().settings.boolean("write-while-downloading") {
+                        this.keep_download_dialog(distro.clone());
+                    } else {
+                        this.save_dialog(distro.clone());
+                    }
+                }
+            ));
+            target.append(&action_row);
+        }
+    }
+
+    fn keep_download_dialog(&self, distro: DistroRelease) {
+        let dialog = adw::AlertDialog::new(
+            Some(&gettext("Keep a Copy?")),
+            Some(&gettext(
+                "The image is written while it downloads. A copy can also be saved to reuse later",
+            )),
+        );
+
+        dialog.add_responses(&[
+            ("discard", &gettext("_Don't Save")),
+            ("save", &gettext("_Save…")),
+        ]);
+        dialog.set_default_response(Some("save"));
+        dialog.set_close_response("discard");
+
+        dialog.connect_response(
+            None,
+            clone!(
+                #[weak(rename_to=this)]
+                self,
+                move |_, id| {
+                    if id == "save" {
+                        this.save_dialog(distro.clone());
+                    } else {
+                        this.imp().selected_image_file_for_reading.replace(Some(
+                            DiskImage::Online {
+                                url: distro.url.clone(),
+                                download_path: None,
+                                name: distro.name.clone(),
+                            },
+                        ));
+
+                        this.load_stored();
+                    }
+                }
+            ),
+        );
+
+        dialog.present(Some(self));
+    }
+
+    fn save_dialog(&self, distro: DistroRelease) {
+        let file_name = distro
+            .url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or("disk-image.img");
+
+        let filter = gtk::FileFilter::new();
+        filter.add_pattern("*.img");
+        filter.add_pattern("*.iso");
+        filter.set_name(Some(&gettext("Disk Images")));
+
+        let model = gio::ListStore::new::<gtk::FileFilter>();
+        model.append(&filter);
+
+        gtk::FileDialog::builder()
+            .modal(true)
+            .filters(&model)
+            .initial_name(file_name)
+            .default_filter(&filter)
+            .build()
+            .save(
+                Some(self),
+                gio::Cancellable::NONE,
+                clone!(
+                    #[weak(rename_to=window)]
+                    self,
+                    move |file| match file {
+                        Ok(file) => {
+                            info!("Selected file: {file:?}");
+
+                            let Some(path) = file.path() else {
+                                error!("Failed to get file path for {file:?}");
+                                return;
+                            };
+
+                            info!("Selected path: {}", path.display());
+                            window.imp().selected_image_file_for_reading.replace(Some(
+                                DiskImage::Online {
+                                    url: distro.url,
+                                    download_path: Some(path),
+                                    name: distro.name,
+                                },
+                            ));
+
+                            window.load_stored();
+                        }
+                        Err(e) => {
+                            error!("Failed to open file dialog: {e}");
+                        }
+                    }
+                ),
+            );
+    }
+
+    #[template_callback]
+    fn open_dialog(&self) {
+        let filter = gtk::FileFilter::new();
+        filter.add_mime_type("application/x-iso9660-image");
+        filter.add_mime_type("application/x-raw-disk-image");
+        filter.add_mime_type("application/x-cd-image");
+        filter.add_pattern("*.iso");
+        filter.add_pattern("*.img");
+        filter.add_pattern("*.raw");
+        filter.add_pattern("*.bin");
+        filter.add_pattern("*.wic");
+        filter.add_mime_type("application/x-qemu-disk");
+        filter.add_mime_type("application/x-virtualbox-vdi");
+        filter.add_mime_type("application/x-virtualbox-vhd");
+        filter.add_mime_type("application/x-virtualbox-vhdx");
+        filter.add_mime_type("application/x-virtualbox-vmdk");
+        for extension in ["iso", "img", "raw", "wic"] {
+            for compression in ["xz", "gz", "bz2", "zst"] {
+                filter.add_pattern(&format!("*.{extension}.{compression}"));
+            }
+        }
+        filter.set_name(Some(&gettext("Disk Images")));
+
+        let all_files_filter = gtk::FileFilter::new();
+        all_files_filter.add_pattern("*");
+        all_files_filter.set_name(Some(&gettext("All Files")));
+
+        let model = gio::ListStore::new::<gtk::FileFilter>();
+        model.append(&filter);
+        model.append(&all_files_filter);
+
+        gtk::FileDialog::builder()
+            .modal(true)
+            .filters(&model)
+            .default_filter(&filter)
+            .build()
+            .open(
+                Some(self),
+                gio::Cancellable::NONE,
+                clone!(
+                    #[weak(rename_to=window)]
+                    self,
+                    move |file| match file {
+                        Ok(file) => {
+                            info!("Selected file: {file:?}");
+                            window.open_file(&file);
+                        }
+                        Err(e) => {
+                            error!("Failed to open file dialog: {e}");
+                        }
+                    }
+                ),
+            );
+    }
+
+    pub fn open_file(&self, file: &gio::File) {
+        let Some(path) = file.path() else {
+            error!("Failed to get file path for {file:?}");
+            return;
+        };
+
+        info!("Selected file: {}", path.display());
+
+        let format = match probe::probe(&path) {
+            Ok(format) => format,
+            Err(e) => {
+                self.imp()
+                    .toast_overlay
+                    .add_toast(adw::Toast::new(&gettext("Could not read the file")));
+                error!("Failed to probe {}: {e}", path.display());
+                return;
+            }
+        };
+
+        info!("Detected image format: {format:?}");
+
+        match format {
+            ImageFormat::Unknown => self.confirm_unknown_image(path),
+            ImageFormat::Zip => self.open_zip_archive(path),
+            format => {
+                self.select_local_image(path, format.compression().unwrap_or(Compression::Raw));
+            }
+        }
+    }
+
+    fn open_zip_archive(&self, path: PathBuf) {
+        let entries = match probe::zip_image_entries(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.imp()
+                    .toast_overlay
+                    .add_toast(adw::Toast::new(&gettext("Could not read the ZIP archive")));
+                error!("Failed to list entries of {}: {e}", path.display());
+                return;
+            }
+        };
+
+        match entries.as_slice() {
+            [] => {
+                self.imp().toast_overlay.add_toast(adw::Toast::new(&gettext(
+                    "The ZIP archive does not contain a disk image",
+                )));
+                error!("No disk image in {}", path.display());
+            }
+            [entry] => self.select_local_image(
+                path,
+                Compression::Zip {
+                    entry: entry.name.clone(),
+                },
+            ),
+            _ => self.choose_zip_entry(path, entries),
+        }
+    }
+
+    fn choose_zip_entry(&self, path: PathBuf, entries: Vec<ZipEntry>) {
+        let list = gtk::ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .css_classes(["boxed-list"])
+            .build();
+
+        let mut check_buttons: Vec<gtk::CheckButton> = Vec::new();
+
+        for entry in &entries {
+            let check_button = gtk::CheckButton::builder()
+                .valign(gtk::Align::Center)
+                .css_classes(["selection_mode"])
+                .build();
+
+            if let Some(first_check_button) = check_buttons.first() {
+                check_button.set_group(Some(first_check_button));
+            } else {
+                check_button.set_active(true);
+            }
+
+            let row = adw::ActionRow::builder()
+                .title(&entry.name)
+                .use_markup(false)
+                .subtitle(get_size_string(entry.size))
+                .activatable_widget(&check_button)
+                .build();
+
+            row.add_prefix(&check_button);
+            list.append(&row);
+            check_buttons.push(check_button);
+        }
+
+        let dialog = adw::AlertDialog::new(
+            Some(&gettext("Choose Image")),
+            Some(&gettext(
+                "The archive contains several files that could be disk images",
+            )),
+        );
+        dialog.set_extra_child(Some(&list));
+
+        dialog.add_responses(&[("cancel", &gettext("_Cancel")), ("open", &gettext("_Open"))]);
+        dialog.set_response_appearance("open", adw::ResponseAppearance::Suggested);
+
+        dialog.connect_response(
+            None,
+            clone!(
+                #[weak(rename_to=this)]
+                self,
+                move |_, id| {
+                    if id != "open" {
+                        return;
+                    }
+
+                    if let Some((entry, _)) = entries
+                        .iter()
+                        .zip(&check_buttons)
+                        .find(|(_, check_button)| check_button.is_active())
+                    {
+                        this.select_local_image(
+                            path.clone(),
+                            Compression::Zip {
+                                entry: entry.name.clone(),
+                            },
+                        );
+                    }
+                }
+            ),
+        );
+
+        dialog.present(Some(self));
+    }
+
+    fn confirm_unknown_image(&self, path: PathBuf) {
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        let dialog = adw::AlertDialog::new(
+            Some(&gettext("Unknown Image Format")),
+            Some(
+                &gettext("{} does not look like a disk image. The drive might not be usable after writing it.")
+                    .replace("{}", file_name),
+            ),
+        );
+
+        dialog.add_responses(&[
+            ("cancel", &gettext("_Cancel")),
+            ("open", &gettext("_Use Anyway")),
+        ]);
+        dialog.set_response_appearance("open", adw::ResponseAppearance::Destructive);
+
+        dialog.connect_response(
+            None,
+            clone!(
+                #[weak(rename_to=this)]
+                self,
+                move |_, id| {
+                    if id == "open" {
+                        this.select_local_image(path.clone(), Compression::Raw);
+                    }
+                }
+            ),
+        );
+
+        dialog.present(Some(self));
+    }
+
+    fn select_local_image(&self, path: PathBuf, compression: Compression) {
+        let bmap = bmap::find_sibling(&path).filter(|bmap_path| {
+            Bmap::load(bmap_path)
+                .inspect_err(|e| warn!("Ignoring block map {}: {e}", bmap_path.display()))
+                .is_ok()
+        });
+
+        self.imp()
+            .selected_image_file_for_reading
+            .replace(Some(DiskImage::Local {
+                path,
+                compression,
+                digest: None,
+                computed_digest: None,
+                bmap,
+            }));
+
+        self.load_stored();
+    }
+
+    fn load_stored(&self) {
+        self.compute_image_size();
+        self.inspect_image();
+
+        match self.selected_image_file_for_reading() {
+            Some(DiskImage::Local { path, digest, .. }) => {
+                self.imp().checksum_group.set_visible(true);
+                self.imp()
+                    .checksum_entry
+                    .set_text(digest.as_ref().map_or("", |digest| digest.value.as_str()));
+                self.compute_checksum();
+                self.update_bmap_row();
+
+                self.imp().name_value_label.set_text(
+                    path.file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or_default(),
+                );
+                self.imp()
+                    .size_label
+                    .set_text(&match std::fs::metadata(path) {
+                        Ok(metadata) => get_size_string(metadata.len()),
+                        Err(e) => {
+                            error!("Failed to get file metadata: {e}");
+                            String::new()
+                        }
+                    });
+            }
+            Some(DiskImage::Online { name, .. } | DiskImage::Drive { name, .. }) => {
+                self.imp().checksum_group.set_visible(false);
+                self.imp().name_value_label.set_text(&name);
+                self.imp().size_label.set_text("");
+            }
+            None => {
+                warn!("No disk image selected");
+                return;
+            }
+        }
+
+        // Online images would be downloaded again for every drive
+        self.imp().duplicator_button.set_visible(!matches!(
+            self.selected_image_file_for_reading(),
+            Some(DiskImage::Online { .. })
+        ));
+
+        // A drive that is cloned is not offered as the destination
+        let devices = self.imp().available_devices.take();
+        self.load_devices_into_ui(&devices);
+
+        self.imp().navigation.push_by_tag("device_list");
+    }
+
+    #[template_callback]
+    fn checksum_entry_applied(&self) {
+        let text = self.imp().checksum_entry.text();
+
+        if text.trim().is_empty() {
+            self.set_expected_digest(None);
+            return;
+        }
+
+        if let Some(digest) = Digest::parse(&text) {
+            self.set_expected_digest(Some(digest));
+        } else {
+            self.imp().toast_overlay.add_toast(adw::Toast::new(&gettext(
+                "Not a valid MD5, SHA-1, SHA-256 or SHA-512 checksum",
+            )));
+        }
+    }
+
+    #[template_callback]
+    fn open_checksum_file(&self) {
+        gtk::FileDialog::builder().modal(true).build().open(
+            Some(self),
+            gio::Cancellable::NONE,
+            clone!(
+                #[weak(rename_to=window)]
+                self,
+                move |file| match file {
+                    Ok(file) => {
+                        info!("Selected checksum file: {file:?}");
+                        window.load_checksum_file(&file);
+                    }
+                    Err(e) => {
+                        error!("Failed to open file dialog: {e}");
+                    }
+                }
+            ),
+        );
     }
 
-    fn load_distros(&self, target: &TemplateChild<gtk::ListBox>, distros: Vec<DistroRelease>) {
-        target.remove_all();
-        for distro in distros {
-            let action_row = adw::ActionRow::new();
-            action_row.set_title(&distro.name);
-            if let Some(subtitle) = &distro.version {
-                action_row.set_subtitle(subtitle);
+    fn load_checksum_file(&self, file: &gio::File) {
+        let Some(DiskImage::Local { path, .. }) = self.selected_image_file_for_reading() else {
+            return;
+        };
+
+        let Some(checksum_path) = file.path() else {
+            error!("Failed to get file path for {file:?}");
+            return;
+        };
+
+        let contents = match std::fs::read_to_string(&checksum_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("Failed to read {}: {e}", checksum_path.display());
+                self.imp().toast_overlay.add_toast(adw::Toast::new(&gettext(
+                    "Could not read the checksum file",
+                )));
+                return;
             }
-            let next_image = gtk::Image::new();
-            next_image.set_icon_name(Some("go-next-symbolic"));
-            action_row.add_suffix(&next_image);
-            action_row.set_activatable_widget(Some(&next_image));
-            action_row.connect_activated(clone!(
-                #[weak(rename_to=this)]
-                self,
-                move |_| {
-                    this.save_dialog(distro.clone());
-                }
+        };
+
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        if let Some(digest) = Digest::from_sums_file(&contents, file_name) {
+            self.imp().checksum_entry.set_text(&digest.value);
+            self.set_expected_digest(Some(digest));
+        } else {
+            self.imp().toast_overlay.add_toast(adw::Toast::new(
+                &gettext("No checksum for {} found in this file").replace("{}", file_name),
             ));
-            target.append(&action_row);
         }
     }
 
-    fn save_dialog(&self, distro: DistroRelease) {
-        let file_name = distro
-            .url
-            .path_segments()
-            .and_then(|mut segments| segments.next_back())
-            .unwrap_or("disk-image.img");
+    fn set_expected_digest(&self, expected: Option<Digest>) {
+        if let Some(DiskImage::Local { digest, .. }) = self
+            .imp()
+            .selected_image_file_for_reading
+            .borrow_mut()
+            .as_mut()
+        {
+            *digest = expected;
+        }
+
+        self.compute_checksum();
+    }
 
+    #[template_callback]
+    fn open_bmap_file(&self) {
         let filter = gtk::FileFilter::new();
-        filter.add_pattern("*.img");
-        filter.add_pattern("*.iso");
-        filter.set_name(Some(&gettext("Disk Images")));
+        filter.add_pattern("*.bmap");
+        filter.set_name(Some(&gettext("Block Maps")));
 
         let model = gio::ListStore::new::<gtk::FileFilter>();
         model.append(&filter);
@@ -693,10 +2186,9 @@ impl ImpressionAppWindow {
         gtk::FileDialog::builder()
             .modal(true)
             .filters(&model)
-            .initial_name(file_name)
             .default_filter(&filter)
             .build()
-            .save(
+            .open(
                 Some(self),
                 gio::Cancellable::NONE,
                 clone!(
@@ -704,23 +2196,23 @@ impl ImpressionAppWindow {
                     self,
                     move |file| match file {
                         Ok(file) => {
-                            info!("Selected file: {file:?}");
+                            info!("Selected block map: {file:?}");
 
                             let Some(path) = file.path() else {
                                 error!("Failed to get file path for {file:?}");
                                 return;
                             };
 
-                            info!("Selected path: {}", path.display());
-                            window.imp().selected_image_file_for_reading.replace(Some(
-                                DiskImage::Online {
-                                    url: distro.url,
-                                    download_path: path,
-                                    name: distro.name,
-                                },
-                            ));
+                            if let Err(e) = Bmap::load(&path) {
+                                error!("Failed to load block map {}: {e}", path.display());
+                                window
+                                    .imp()
+                                    .toast_overlay
+                                    .add_toast(adw::Toast::new(&gettext("Not a valid block map")));
+                                return;
+                            }
 
-                            window.load_stored();
+                            window.set_bmap(Some(path));
                         }
                         Err(e) => {
                             error!("Failed to open file dialog: {e}");
@@ -731,113 +2223,401 @@ impl ImpressionAppWindow {
     }
 
     #[template_callback]
-    fn open_dialog(&self) {
-        let filter = gtk::FileFilter::new();
-        filter.add_mime_type("application/x-iso9660-image");
-        filter.add_mime_type("application/x-raw-disk-image");
-        filter.add_mime_type("application/x-cd-image");
-        filter.add_pattern("*.iso");
-        filter.add_pattern("*.img");
-        filter.add_pattern("*.iso.xz");
-        filter.add_pattern("*.img.xz");
-        filter.add_pattern("*.raw.xz");
-        filter.set_name(Some(&gettext("Disk Images")));
+    fn clear_bmap_file(&self) {
+        self.set_bmap(None);
+    }
 
-        let model = gio::ListStore::new::<gtk::FileFilter>();
-        model.append(&filter);
+    fn set_bmap(&self, path: Option<PathBuf>) {
+        if let Some(DiskImage::Local { bmap, .. }) = self
+            .imp()
+            .selected_image_file_for_reading
+            .borrow_mut()
+            .as_mut()
+        {
+            *bmap = path;
+        }
 
-        gtk::FileDialog::builder()
-            .modal(true)
-            .filters(&model)
-            .default_filter(&filter)
-            .build()
-            .open(
-                Some(self),
-                gio::Cancellable::NONE,
-                clone!(
-                    #[weak(rename_to=window)]
-                    self,
-                    move |file| match file {
-                        Ok(file) => {
-                            info!("Selected file: {file:?}");
-                            window.open_file(&file);
-                        }
-                        Err(e) => {
-                            error!("Failed to open file dialog: {e}");
-                        }
-                    }
-                ),
+        self.update_bmap_row();
+        self.compute_image_size();
+    }
+
+    fn update_bmap_row(&self) {
+        let imp = self.imp();
+
+        if let Some(DiskImage::Local {
+            bmap: Some(path), ..
+        }) = self.selected_image_file_for_reading()
+        {
+            imp.bmap_row.set_subtitle(
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or_default(),
             );
+            imp.bmap_clear_button.set_visible(true);
+        } else {
+            imp.bmap_row
+                .set_subtitle(&gettext("Not used, the whole image is written"));
+            imp.bmap_clear_button.set_visible(false);
+        }
     }
 
-    pub fn open_file(&self, file: &gio::File) {
-        let Some(path) = file.path() else {
-            error!("Failed to get file path for {file:?}");
+    /// Shows what the start of the selected image holds and whether it boots.
+    fn inspect_image(&self) {
+        let imp = self.imp();
+
+        imp.contents_group.set_visible(false);
+        imp.boot_warning_banner.set_revealed(false);
+        for row in imp.contents_rows.take() {
+            imp.contents_row.remove(&row);
+        }
+
+        let Some(DiskImage::Local {
+            path, compression, ..
+        }) = self.selected_image_file_for_reading()
+        else {
             return;
         };
 
-        info!("Selected file: {}", path.display());
+        let (sender, receiver) = tokio::sync::oneshot::channel();
 
-        if !path
-            .extension()
-            .and_then(|extension| extension.to_str())
-            .is_some_and(|extension| ["iso", "img", "xz"].contains(&extension))
-        {
-            self.imp()
-                .toast_overlay
-                .add_toast(adw::Toast::new(&gettext("File is not a Disk Image")));
-            error!("Not a Disk Image: {}", path.display());
-            return;
+        let image_path = path.clone();
+        runtime().spawn(async move {
+            sender
+                .send(inspect::inspect(&image_path, &compression).await)
+                .ok();
+        });
+
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to=this)]
+            self,
+            async move {
+                let Ok(inspection) = receiver.await else {
+                    return;
+                };
+
+                // The image might have changed in the meantime
+                if !matches!(
+                    this.selected_image_file_for_reading(),
+                    Some(DiskImage::Local { path: current_path, .. }) if current_path == path
+                ) {
+                    return;
+                }
+
+                match inspection {
+                    Ok(Some(inspection)) => this.show_inspection(&inspection),
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to inspect the image: {e}"),
+                }
+            }
+        ));
+    }
+
+    fn show_inspection(&self, inspection: &Inspection) {
+        let imp = self.imp();
+
+        let mut rows = Vec::new();
+        let mut add_row = |title: &str, subtitle: &str| {
+            let row = adw::ActionRow::builder()
+                .title(title)
+                .subtitle(subtitle)
+                .subtitle_selectable(true)
+                .css_classes(["property"])
+                .build();
+            imp.contents_row.add_row(&row);
+            rows.push(row);
+        };
+
+        if let Some(volume_id) = &inspection.iso9660 {
+            add_row(&gettext("ISO 9660 Volume"), volume_id);
         }
 
-        self.imp()
-            .selected_image_file_for_reading
-            .replace(Some(DiskImage::Local {
-                path: path.clone(),
-                compression: {
-                    if matches!(path.extension(), Some(x) if x == "xz") {
-                        Compression::Xz
+        if let Some(filesystem) = inspection.filesystem {
+            add_row(&gettext("File System"), filesystem);
+        }
+
+        if !inspection.el_torito.is_empty() {
+            let platforms = inspection
+                .el_torito
+                .iter()
+                .map(|platform| match platform {
+                    Platform::Bios => "BIOS",
+                    Platform::Uefi => "UEFI",
+                })
+                .collect::<Vec<_>>();
+            add_row(&gettext("Boots From Optical Discs"), &platforms.join(", "));
+        }
+
+        add_row(
+            &gettext("Partition Table"),
+            &match inspection.partition_table {
+                None => gettext("None"),
+                Some(PartitionTable::Mbr) => "MBR".to_owned(),
+                Some(PartitionTable::Gpt { hybrid: false }) => "GPT".to_owned(),
+                Some(PartitionTable::Gpt { hybrid: true }) => gettext("GPT with hybrid MBR"),
+            },
+        );
+
+        for partition in &inspection.partitions {
+            let title = if partition.label.is_empty() {
+                gettext("Partition {}").replace("{}", &partition.number.to_string())
+            } else {
+                gettext("Partition {}: {}")
+                    .replacen("{}", &partition.number.to_string(), 1)
+                    .replacen("{}", &partition.label, 1)
+            };
+
+            let mut details = vec![partition.kind.clone()];
+            details.extend(partition.filesystem.map(str::to_owned));
+            details.push(get_size_string(partition.size));
+
+            add_row(&title, &details.join(", "));
+        }
+
+        let boot_modes = [
+            (inspection.bios_bootable, "BIOS"),
+            (inspection.uefi_bootable, "UEFI"),
+        ]
+        .into_iter()
+        .filter_map(|(bootable, mode)| bootable.then_some(mode))
+        .collect::<Vec<_>>();
+
+        imp.contents_row.set_subtitle(&if !boot_modes.is_empty() {
+            gettext("Starts a computer with {}").replace("{}", &boot_modes.join(", "))
+        } else if inspection.has_fat_boot_partition() {
+            gettext("Starts boards that boot from a FAT partition")
+        } else {
+            gettext("Does not start a computer from a drive")
+        });
+
+        imp.contents_rows.replace(rows);
+        imp.contents_group.set_visible(true);
+
+        if !inspection.is_bootable() {
+            imp.boot_warning_banner.set_title(&if !inspection.el_torito.is_empty() {
+                gettext(
+                    "This image only boots from optical discs, a drive it is written to will not start a computer",
+                )
+            } else if inspection.iso9660.is_some() {
+                gettext(
+                    "This is a data-only image without boot code, a drive it is written to will not start a computer",
+                )
+            } else if inspection.has_fat_boot_partition() {
+                // Like Raspberry Pi and Yocto images, which are fine as they are
+                gettext(
+                    "This image boots from a FAT partition, like on a Raspberry Pi, but might not start a PC",
+                )
+            } else if let Some(filesystem) = inspection.filesystem {
+                gettext(
+                    "This image only holds a {} file system, a drive it is written to will not start a computer",
+                )
+                .replace("{}", filesystem)
+            } else {
+                gettext(
+                    "This image has no boot code for BIOS or UEFI, a drive it is written to might not start a computer",
+                )
+            });
+            imp.boot_warning_banner.set_revealed(true);
+        }
+    }
+
+    /// Works out how much room the selected image needs, to grey out the
+    /// drives that are too small for it.
+    fn compute_image_size(&self) {
+        let imp = self.imp();
+        imp.image_size.set(None);
+
+        let Some(image) = self.selected_image_file_for_reading() else {
+            stop_job(&imp.image_size_job);
+            return;
+        };
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        let job = restart_job(&imp.image_size_job);
+        let source = image.clone();
+        runtime().spawn(async move {
+            let size = match (capacity::declared_size(&source).await, &source) {
+                (Some(size), _) => Some(size),
+                (
+                    None,
+                    DiskImage::Local {
+                        path, compression, ..
+                    },
+                ) => capacity::decoded_size(path, compression, |_| {
+                    if job.load(Ordering::SeqCst) {
+                        ControlFlow::Continue(())
                     } else {
-                        Compression::Raw
+                        ControlFlow::Break(())
                     }
-                },
-            }));
+                })
+                .await
+                .inspect_err(|e| warn!("Failed to decompress the image: {e}"))
+                .ok()
+                .flatten(),
+                (None, _) => None,
+            };
+            sender.send(size).ok();
+        });
 
-        self.load_stored();
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to=this)]
+            self,
+            async move {
+                let Ok(Some(size)) = receiver.await else {
+                    return;
+                };
+
+                // The image or its block map might have changed in the meantime
+                let unchanged = match (this.selected_image_file_for_reading(), &image) {
+                    (
+                        Some(DiskImage::Local { path, bmap, .. }),
+                        DiskImage::Local {
+                            path: old_path,
+                            bmap: old_bmap,
+                            ..
+                        },
+                    ) => path == *old_path && bmap == *old_bmap,
+                    (
+                        Some(DiskImage::Drive { object, .. }),
+                        DiskImage::Drive { object: old, .. },
+                    ) => object.object_path() == old.object_path(),
+                    _ => false,
+                };
+                if !unchanged {
+                    return;
+                }
+
+                info!("The image needs {size} bytes");
+                this.imp().image_size.set(Some(size));
+
+                let devices = this.imp().available_devices.take();
+                this.load_devices_into_ui(&devices);
+            }
+        ));
     }
 
-    fn load_stored(&self) {
-        match self.selected_image_file_for_reading() {
-            Some(DiskImage::Local {
-                path,
-                compression: _,
-            }) => {
-                self.imp().name_value_label.set_text(
-                    path.file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or_default(),
-                );
-                self.imp()
-                    .size_label
-                    .set_text(&match std::fs::metadata(path) {
-                        Ok(metadata) => get_size_string(metadata.len()),
-                        Err(e) => {
-                            error!("Failed to get file metadata: {e}");
-                            String::new()
-                        }
-                    });
+    fn compute_checksum(&self) {
+        let imp = self.imp();
+
+        let Some(DiskImage::Local {
+            path,
+            digest: Some(expected),
+            computed_digest,
+            ..
+        }) = self.selected_image_file_for_reading()
+        else {
+            stop_job(&imp.checksum_job);
+            imp.checksum_result_row.set_visible(false);
+            return;
+        };
+
+        imp.checksum_result_row.set_visible(true);
+
+        // Only the expected checksum changed, not the image
+        if let Some(computed) = computed_digest.filter(|computed| {
+            computed.digest.algorithm == expected.algorithm && computed.is_current(&path)
+        }) {
+            stop_job(&imp.checksum_job);
+            self.show_checksum_result(&expected, &Ok(computed.digest));
+            return;
+        }
+
+        imp.checksum_result_row
+            .set_title(&gettext("Computing {} Checksum…").replace("{}", expected.algorithm.name()));
+        imp.checksum_result_row.set_subtitle("");
+        imp.checksum_spinner.set_visible(true);
+        imp.checksum_status_icon.set_visible(false);
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        let job = restart_job(&imp.checksum_job);
+        let algorithm = expected.algorithm;
+        let image_path = path.clone();
+        runtime().spawn(async move {
+            let computed = FileDigest::compute(&image_path, algorithm, |_, _| {
+                if job.load(Ordering::SeqCst) {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            })
+            .await;
+            sender.send(computed).ok();
+        });
+
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to=this)]
+            self,
+            async move {
+                // Nothing to show for computations that were stopped
+                let computed = match receiver.await {
+                    Ok(Ok(Some(computed))) => Ok(computed),
+                    Ok(Err(e)) => Err(e),
+                    Ok(Ok(None)) | Err(_) => return,
+                };
+
+                let mut selected = this.imp().selected_image_file_for_reading.borrow_mut();
+                let Some(DiskImage::Local {
+                    path: current_path,
+                    digest: Some(current),
+                    computed_digest,
+                    ..
+                }) = selected.as_mut()
+                else {
+                    return;
+                };
+
+                // The image or the expected checksum might have changed in the meantime
+                if *current_path != path || current.algorithm != algorithm {
+                    return;
+                }
+                let current = current.clone();
+
+                // Kept for flashing, so that the image isn't hashed a second time
+                if let Ok(computed) = &computed {
+                    *computed_digest = Some(computed.clone());
+                }
+                drop(selected);
+
+                this.show_checksum_result(&current, &computed.map(|computed| computed.digest));
             }
-            Some(DiskImage::Online { name, .. }) => {
-                self.imp().name_value_label.set_text(&name);
-                self.imp().size_label.set_text("");
+        ));
+    }
+
+    fn show_checksum_result(&self, expected: &Digest, computed: &std::io::Result<Digest>) {
+        let imp = self.imp();
+        imp.checksum_spinner.set_visible(false);
+
+        match computed {
+            Ok(computed) => {
+                let algorithm = computed.algorithm.name();
+                imp.checksum_result_row.set_subtitle(&computed.value);
+                imp.checksum_status_icon.set_visible(true);
+
+                if computed == expected {
+                    imp.checksum_result_row
+                        .set_title(&gettext("{} Checksum Matches").replace("{}", algorithm));
+                    imp.checksum_status_icon
+                        .set_icon_name(Some("check-round-outline-symbolic"));
+                    imp.checksum_status_icon.remove_css_class("error");
+                    imp.checksum_status_icon.add_css_class("success");
+                } else {
+                    imp.checksum_result_row
+                        .set_title(&gettext("{} Checksum Does Not Match").replace("{}", algorithm));
+                    imp.checksum_status_icon
+                        .set_icon_name(Some("error-symbolic"));
+                    imp.checksum_status_icon.remove_css_class("success");
+                    imp.checksum_status_icon.add_css_class("error");
+                }
             }
-            None => {
-                warn!("No disk image selected");
-                return;
+            Err(e) => {
+                error!("Failed to compute checksum: {e}");
+                imp.checksum_result_row
+                    .set_title(&gettext("Failed to Compute Checksum"));
+                imp.checksum_result_row.set_subtitle(&e.to_string());
+                imp.checksum_status_icon.set_visible(false);
             }
         }
-
-        self.imp().navigation.push_by_tag("device_list");
     }
 
     fn refresh_devices(&self) {
@@ -877,21 +2657,63 @@ impl ImpressionAppWindow {
             return;
         }
 
-        imp.selected_device_object_path_for_writing.take();
-
-        let selected_device = self
-            .selected_device_for_writing()
-            .and_then(|dev| dev.display_string);
+        let selected_devices = imp
+            .selected_device_object_paths_for_writing
+            .borrow()
+            .clone();
+        self.clear_devices_selected_for_writing();
 
         imp.available_devices_list.remove_all();
+        imp.backup_devices_list.remove_all();
+        imp.clone_devices_list.remove_all();
+        imp.format_devices_list.remove_all();
+        imp.wipe_devices_list.remove_all();
+        imp.duplicator_candidates_list.remove_all();
         imp.available_devices.replace(devices.to_vec());
 
-        if devices.is_empty() {
-            self.set_selected_device_object_path_for_writing(None);
+        let duplicator_page_visible = imp
+            .navigation
+            .visible_page()
+            .is_some_and(|page| page == *imp.duplicator_page);
+
+        // The duplicator waits for drives to be plugged in
+        if devices.is_empty() && !duplicator_page_visible {
             self.imp().stack.set_visible_child_name("no_devices");
             self.imp().main_stack.set_visible_child_name("status");
         } else {
-            let devices = device_list::new(self, devices, selected_device.as_deref());
+            let candidates = devices
+                .iter()
+                .filter(|device| device.model.is_some())
+                .cloned()
+                .collect::<Vec<_>>();
+            for row in device_list::action_rows(self, &candidates, Self::allow_for_duplicator) {
+                imp.duplicator_candidates_list.append(&row);
+            }
+
+            for row in device_list::action_rows(self, devices, Self::backup_dialog) {
+                imp.backup_devices_list.append(&row);
+            }
+            for row in device_list::action_rows(self, devices, Self::select_source_drive) {
+                imp.clone_devices_list.append(&row);
+            }
+            for row in device_list::action_rows(self, devices, Self::format_dialog) {
+                imp.format_devices_list.append(&row);
+            }
+            for row in device_list::action_rows(self, devices, Self::wipe_dialog) {
+                imp.wipe_devices_list.append(&row);
+            }
+
+            let source = match self.selected_image_file_for_reading() {
+                Some(DiskImage::Drive { object, .. }) => Some(object.object_path().to_string()),
+                _ => None,
+            };
+            let targets = devices
+                .iter()
+                .filter(|device| Some(device.object.object_path().to_string()) != source)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let devices = device_list::new(self, &targets, &selected_devices, imp.image_size.get());
             for device in devices {
                 imp.available_devices_list.append(&device);
             }
@@ -992,3 +2814,18 @@ async fn send_notification(message: Option<&str>) {
         error!("Failed to send notification: {e}");
     }
 }
+
+/// Starts a new background job in `slot`, stopping the one that was there,
+/// and returns whether the new one is still wanted.
+fn restart_job(slot: &RefCell<Option<Arc<AtomicBool>>>) -> Arc<AtomicBool> {
+    let job = Arc::new(AtomicBool::new(true));
+    stop_job(slot);
+    slot.replace(Some(job.clone()));
+    job
+}
+
+fn stop_job(slot: &RefCell<Option<Arc<AtomicBool>>>) {
+    if let Some(job) = slot.take() {
+        job.store(false, Ordering::SeqCst);
+    }
+}
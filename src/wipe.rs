@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use log::{error, info};
+use terrors::OneOf;
+use tokio::fs::File;
+
+use crate::flash::{
+    self, BlockChecksums, FlashPhase, FlashRequest, FlashStatus, ImageReadFailed,
+    ProcessStoppedByUser, Progress, VerificationFailed,
+};
+use crate::source::ImageStream;
+use crate::widgets::device_list;
+use crate::writer::{self, FanOut, ZeroBlocks};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WipeMethod {
+    Zero,
+    Random,
+    /// Random data that is read back afterwards.
+    RandomVerified,
+}
+
+impl WipeMethod {
+    pub const ALL: [Self; 3] = [Self::Zero, Self::Random, Self::RandomVerified];
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Zero => "zero-fill",
+            Self::Random => "random-fill",
+            Self::RandomVerified => "random-fill-verified",
+        }
+    }
+}
+
+/// What happened when a drive was wiped, to be kept as proof of erasure.
+#[derive(Debug, Clone)]
+pub struct ErasureReport {
+    pub device: String,
+    pub serial: String,
+    pub vendor: String,
+    pub model: String,
+    pub capacity: u64,
+    pub method: WipeMethod,
+    pub bytes_written: u64,
+    /// Whether reading the drive back matched what was written.
+    pub verified: bool,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub error: Option<String>,
+}
+
+impl ErasureReport {
+    fn new(method: WipeMethod) -> Self {
+        let now = Local::now();
+
+        Self {
+            device: String::new(),
+            serial: String::new(),
+            vendor: String::new(),
+            model: String::new(),
+            capacity: 0,
+            method,
+            bytes_written: 0,
+            verified: false,
+            started: now,
+            finished: now,
+            error: None,
+        }
+    }
+
+    /// Notes that wiping ended now, failing with `error` if it did.
+    fn finish(&mut self, error: Option<String>) {
+        self.finished = Local::now();
+        self.error = error;
+    }
+
+    fn result(&self) -> String {
+        self.error
+            .as_ref()
+            .map_or_else(|| "erased".to_owned(), |e| format!("failed: {e}"))
+    }
+
+    fn to_json(&self) -> String {
+        let json = serde_json::json!({
+            "device": self.device,
+            "serial": self.serial,
+            "vendor": self.vendor,
+            "model": self.model,
+            "capacity": self.capacity,
+            "method": self.method.as_str(),
+            "bytes_written": self.bytes_written,
+            "verified": self.verified,
+            "started": self.started.to_rfc3339(),
+            "finished": self.finished.to_rfc3339(),
+            "result": if self.error.is_none() { "erased" } else { "failed" },
+            "error": self.error,
+        });
+
+        serde_json::to_string_pretty(&json).unwrap_or_else(|_| json.to_string())
+    }
+
+    fn to_text(&self) -> String {
+        [
+            ("Device", self.device.clone()),
+            ("Serial", self.serial.clone()),
+            ("Vendor", self.vendor.clone()),
+            ("Model", self.model.clone()),
+            ("Capacity", format!("{} bytes", self.capacity)),
+            ("Method", self.method.as_str().to_owned()),
+            ("Written", format!("{} bytes", self.bytes_written)),
+            (
+                "Verified",
+                (if self.verified { "yes" } else { "no" }).to_owned(),
+            ),
+            ("Started", self.started.to_rfc3339()),
+            ("Finished", self.finished.to_rfc3339()),
+            ("Result", self.result()),
+        ]
+        .iter()
+        .map(|(key, value)| format!("{key}: {value}\n"))
+        .fold(String::from("Erasure Report\n\n"), |text, line| {
+            text + &line
+        })
+    }
+
+    /// Saves the report to `path`, as JSON if its extension asks for that
+    /// and as plain text otherwise.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let contents = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            self.to_json()
+        } else {
+            self.to_text()
+        };
+
+        std::fs::write(path, contents)
+    }
+}
+
+/// Overwrites everything on a drive, leaving a report of it behind.
+pub struct WipeRequest {
+    drive: udisks::Object,
+    method: WipeMethod,
+    status: Arc<Mutex<FlashStatus>>,
+    is_running: Arc<AtomicBool>,
+    report: Arc<Mutex<Option<ErasureReport>>>,
+}
+
+impl WipeRequest {
+    pub const fn new(
+        drive: udisks::Object,
+        method: WipeMethod,
+        status: Arc<Mutex<FlashStatus>>,
+        is_running: Arc<AtomicBool>,
+        report: Arc<Mutex<Option<ErasureReport>>>,
+    ) -> Self {
+        Self {
+            drive,
+            method,
+            status,
+            is_running,
+            report,
+        }
+    }
+
+    pub async fn perform(self) {
+        let mut report = ErasureReport::new(self.method);
+        let result = self.perform_job(&mut report).await;
+
+        report.finish(result.as_ref().err().map(ToString::to_string));
+        info!("Erasure report: {report:?}");
+
+        // The report has to be there by the time the job is seen as done
+        if let Ok(mut lock) = self.report.lock() {
+            *lock = Some(report);
+        }
+
+        match result {
+            Ok(()) => self.set_status(FlashStatus::Done(None)),
+            Err(e) => {
+                if let Err(e) = e.narrow::<ProcessStoppedByUser, _>() {
+                    error!("Wiping failed: {e}");
+                    self.set_status(FlashStatus::Done(Some(e.to_string())));
+                }
+            }
+        }
+    }
+
+    fn set_status(&self, status: FlashStatus) {
+        if let Ok(mut lock) = self.status.lock() {
+            *lock = status;
+        }
+    }
+
+    /// Shows writing as wiping, since the data isn't an image.
+    fn set_wipe_status(&self, status: FlashStatus) {
+        self.set_status(match status {
+            FlashStatus::Active(FlashPhase::Copy, progress) => {
+                FlashStatus::Active(FlashPhase::Wipe, progress)
+            }
+            status => status,
+        });
+    }
+
+    async fn perform_job(
+        &self,
+        report: &mut ErasureReport,
+    ) -> Result<
+        (),
+        OneOf<(
+            ProcessStoppedByUser,
+            std::io::Error,
+            udisks::Error,
+            ImageReadFailed,
+            VerificationFailed,
+        )>,
+    > {
+        info!("Wiping {:?} ({:?})", self.drive.object_path(), self.method);
+
+        self.set_status(FlashStatus::Active(FlashPhase::Wipe, Progress::Pulse));
+
+        let client = udisks::Client::new().await.map_err(OneOf::new)?;
+        let block = self.drive.block().await.map_err(OneOf::new)?;
+        let size = block.size().await.map_err(OneOf::new)?;
+
+        report.device = device_list::preferred_device_display_string(&self.drive)
+            .await
+            .unwrap_or_default();
+        report.capacity = size;
+        if let Ok(drive) = client.drive_for_block(&block).await {
+            report.serial = drive.serial().await.unwrap_or_default();
+            drive
+                .vendor()
+                .await
+                .unwrap_or_default()
+                .trim()
+                .clone_into(&mut report.vendor);
+            drive
+                .model()
+                .await
+                .unwrap_or_default()
+                .trim()
+                .clone_into(&mut report.model);
+        }
+
+        if let Err(e) = flash::unmount_partitions(&client, &self.drive).await {
+            error!("Error unmounting partitions, will be ignored: {e}");
+        }
+
+        let mut file = flash::udisks_open(&block).await.map_err(OneOf::new)?;
+
+        let data: Box<dyn Read + Send> = match self.method {
+            WipeMethod::Zero => Box::new(std::io::repeat(0)),
+            WipeMethod::Random | WipeMethod::RandomVerified => {
+                Box::new(std::fs::File::open("/dev/urandom").map_err(OneOf::new)?)
+            }
+        };
+        let data = ImageStream::from_reader(data, size);
+
+        let checksums = overwrite(
+            &mut file,
+            data,
+            |status| self.set_wipe_status(status),
+            self.is_running.clone(),
+        )
+        .await
+        .map_err(OneOf::broaden)?;
+        report.bytes_written = checksums.total();
+
+        if self.method == WipeMethod::RandomVerified {
+            FlashRequest::verify_file(
+                &mut file,
+                &checksums,
+                |status| self.set_status(status),
+                self.is_running.clone(),
+            )
+            .await
+            .map_err(OneOf::broaden)?;
+            report.verified = true;
+        }
+
+        if let Err(e) = block.rescan(HashMap::new()).await {
+            error!("Error rescanning block device, will be ignored: {e}");
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes all of `data` to `file` and makes sure it reached the drive,
+/// returning checksums of what was written.
+async fn overwrite<F: Fn(FlashStatus) + Send>(
+    file: &mut File,
+    data: ImageStream,
+    set_status: F,
+    is_running: Arc<AtomicBool>,
+) -> Result<BlockChecksums, OneOf<(std::io::Error, ProcessStoppedByUser, ImageReadFailed)>> {
+    let (mut fan_out, mut inboxes) = FanOut::new(1);
+    let inbox = inboxes
+        .pop()
+        .ok_or_else(|| OneOf::new(std::io::Error::other("No writer for the drive")))?;
+
+    // Zeroes have to end up on the drive too, not be skipped over
+    let (written, checksums) = futures::join!(
+        async {
+            let result = FlashRequest::load_file(data, &mut fan_out, set_status, is_running).await;
+            drop(fan_out);
+            result
+        },
+        writer::drain(file, ZeroBlocks::Write, inbox, |_| {}),
+    );
+
+    // A failing drive only shows up as a broken pipe on the writing side
+    let checksums = checksums.map_err(OneOf::new)?;
+    written?;
+
+    Ok(checksums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_failed_syncs() {
+        let mut report = ErasureReport::new(WipeMethod::Zero);
+
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build runtime")
+            .block_on(async {
+                // Everything can be written to it, but it can't be synced
+                let mut file = File::options()
+                    .write(true)
+                    .open("/dev/null")
+                    .await
+                    .expect("Failed to open /dev/null");
+                let data = ImageStream::from_reader(std::io::repeat(0), 1024 * 1024);
+
+                overwrite(&mut file, data, |_| {}, Arc::new(AtomicBool::new(true))).await
+            });
+        report.finish(result.err().map(|e| e.to_string()));
+
+        let json =
+            serde_json::from_str::<serde_json::Value>(&report.to_json()).expect("Invalid JSON");
+        assert_eq!(json["result"], "failed");
+        assert!(json["error"].is_string());
+        assert!(report.to_text().contains("Result: failed: "));
+    }
+}
@@ -0,0 +1,485 @@
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+use crate::flash::{BlockChecksums, FlashStatus};
+
+/// Granularity at which blocks of zeroes are detected.
+const ZERO_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Number of writes that a drive may fall behind the fastest one.
+const WRITES_IN_FLIGHT: usize = 4;
+
+/// How long a drive may take no data before it is dropped from a fan-out.
+const STALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How blocks that only hold zeroes end up on the drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroBlocks {
+    /// Written like any other data.
+    Write,
+    /// Zeroed by the drive itself with `BLKZEROOUT`.
+    ZeroOut,
+    /// Not written at all, as the whole drive was zeroed out beforehand.
+    Skip,
+}
+
+impl ZeroBlocks {
+    /// Clears the first `size` bytes of the drive and picks the fastest way
+    /// to get zero blocks on it that still reads them back as zeroes.
+    pub async fn prepare(file: &File, size: u64) -> Self {
+        let discards = queue_attribute(file, "discard_max_bytes")
+            .await
+            .is_some_and(|max| max > 0);
+        let zeroes_out = queue_attribute(file, "write_zeroes_max_bytes")
+            .await
+            .is_some_and(|max| max > 0);
+
+        // Whether discarded blocks read back as zeroes can't be told from
+        // sysfs, `discard_zeroes_data` is always 0. Punching a hole into a
+        // block device does guarantee zeroes though: the drive is asked to
+        // zero out the blocks, which it may do by unmapping them, and it
+        // fails if the kernel would have to write the zeroes instead. Drives
+        // that can't discard are left out, they would write every block.
+        if discards && zeroes_out {
+            match punch_hole(file, size) {
+                Ok(()) => {
+                    info!("Zeroed out the drive, zero blocks will be skipped");
+                    return Self::Skip;
+                }
+                Err(e) => warn!("Failed to zero out the drive: {e}"),
+            }
+        }
+
+        // Every byte gets written when the drive can't zero out blocks itself
+        if discards
+            && zeroes_out
+            && let Err(e) = block_range_ioctl(file, libc::_IO(0x12, 119), 0, size)
+        {
+            warn!("Failed to discard the drive: {e}");
+        }
+
+        let zero_blocks = if zeroes_out {
+            Self::ZeroOut
+        } else {
+            Self::Write
+        };
+
+        info!("Zero blocks will be handled with {zero_blocks:?}");
+
+        zero_blocks
+    }
+}
+
+/// Writes image data to a drive, keeping checksums of everything written for
+/// verification and handling blocks of zeroes as configured.
+pub struct DeviceWriter<'a> {
+    target: BufWriter<&'a mut File>,
+    zero_blocks: ZeroBlocks,
+    /// Where the next write to `target` lands.
+    cursor: u64,
+    /// A run of zero blocks that still has to be zeroed out.
+    pending_zeroes: Option<std::ops::Range<u64>>,
+    checksums: BlockChecksums,
+}
+
+impl<'a> DeviceWriter<'a> {
+    pub fn new(file: &'a mut File, zero_blocks: ZeroBlocks) -> Self {
+        Self {
+            target: BufWriter::with_capacity(1024 * 1024, file),
+            zero_blocks,
+            cursor: 0,
+            pending_zeroes: None,
+            checksums: BlockChecksums::default(),
+        }
+    }
+
+    /// Writes `data` at `offset` bytes into the drive.
+    pub async fn write_at(&mut self, mut offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.checksums.seek(offset);
+        self.checksums.update(data);
+
+        if self.zero_blocks == ZeroBlocks::Write {
+            return self.write_data(offset, data).await;
+        }
+
+        for block in data.chunks(ZERO_BLOCK_SIZE) {
+            let end = offset + block.len() as u64;
+
+            if block.iter().all(|byte| *byte == 0) {
+                if self.zero_blocks == ZeroBlocks::ZeroOut {
+                    match &mut self.pending_zeroes {
+                        Some(run) if run.end == offset => run.end = end,
+                        _ => {
+                            self.flush_zeroes().await?;
+                            self.pending_zeroes = Some(offset..end);
+                        }
+                    }
+                }
+            } else {
+                self.flush_zeroes().await?;
+                self.write_data(offset, block).await?;
+            }
+
+            offset = end;
+        }
+
+        Ok(())
+    }
+
+    async fn write_data(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        if offset != self.cursor {
+            self.target.seek(std::io::SeekFrom::Start(offset)).await?;
+        }
+
+        self.target.write_all(data).await?;
+        self.cursor = offset + data.len() as u64;
+
+        Ok(())
+    }
+
+    async fn flush_zeroes(&mut self) -> std::io::Result<()> {
+        let Some(run) = self.pending_zeroes.take() else {
+            return Ok(());
+        };
+
+        let result = block_range_ioctl(
+            self.target.get_ref(),
+            libc::_IO(0x12, 127),
+            run.start,
+            run.end - run.start,
+        );
+
+        if let Err(e) = result {
+            // Unaligned runs at the end of an image are refused, among others
+            warn!("Failed to zero out {run:?}, writing zeroes instead: {e}");
+
+            let zeroes = vec![0; ZERO_BLOCK_SIZE];
+            let mut offset = run.start;
+            while offset < run.end {
+                let len = usize::try_from(run.end - offset)
+                    .map_or(ZERO_BLOCK_SIZE, |left| left.min(ZERO_BLOCK_SIZE));
+                self.write_data(offset, &zeroes[..len]).await?;
+                offset += len as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes out everything that is still buffered.
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_zeroes().await?;
+        self.target.flush().await
+    }
+
+    /// Checksums of everything written, to be called after [`Self::flush`].
+    pub fn into_checksums(self) -> BlockChecksums {
+        self.checksums.finish()
+    }
+}
+
+/// Runs a block device ioctl like `BLKDISCARD` that takes a byte range.
+fn block_range_ioctl(
+    file: &File,
+    request: libc::Ioctl,
+    start: u64,
+    length: u64,
+) -> std::io::Result<()> {
+    let range = [start, length];
+    // SAFETY: `fd` is a valid open file descriptor borrowed from `file` and
+    // the request reads two u64 values from the pointer, which `range` holds.
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request, range.as_ptr()) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Zeroes the first `size` bytes of a block device without writing zeroes
+/// to it, failing if the drive can't do that itself.
+fn punch_hole(file: &File, size: u64) -> std::io::Result<()> {
+    let size = i64::try_from(size).map_err(std::io::Error::other)?;
+    // SAFETY: `fd` is a valid open file descriptor borrowed from `file`.
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            size,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Reads a numeric attribute of the request queue of the drive behind `file`.
+async fn queue_attribute(file: &File, name: &str) -> Option<u64> {
+    let rdev = file.metadata().await.ok()?.rdev();
+    let path = format!(
+        "/sys/dev/block/{}:{}/queue/{name}",
+        libc::major(rdev),
+        libc::minor(rdev)
+    );
+
+    tokio::fs::read_to_string(path)
+        .await
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// What the writer of a single drive is asked to do.
+pub enum Message {
+    Write(u64, Arc<[u8]>),
+    /// Report `FlashStatus` once everything sent before is written.
+    Status(FlashStatus),
+}
+
+/// What the writer of a single drive receives from a [`FanOut`].
+pub struct Inbox {
+    receiver: mpsc::Receiver<Message>,
+    /// Set when the drive was dropped for taking no data in time.
+    stalled: Arc<AtomicBool>,
+}
+
+impl Inbox {
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+
+    fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::SeqCst)
+    }
+}
+
+/// Hands image data to the writers of several drives, so that the image is
+/// only read once. A drive whose writer fails or stalls drops out and the
+/// others go on.
+pub struct FanOut {
+    senders: Vec<Option<mpsc::Sender<Message>>>,
+    stalled: Vec<Arc<AtomicBool>>,
+    stall_timeout: Duration,
+    /// Where the data written last ended.
+    position: u64,
+}
+
+impl FanOut {
+    /// Creates a fan-out to `count` drives, along with what each of their writers receives.
+    pub fn new(count: usize) -> (Self, Vec<Inbox>) {
+        let mut senders = Vec::with_capacity(count);
+        let mut stalled = Vec::with_capacity(count);
+        let mut inboxes = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let (sender, receiver) = mpsc::channel(WRITES_IN_FLIGHT);
+            let flag = Arc::new(AtomicBool::new(false));
+            senders.push(Some(sender));
+            stalled.push(flag.clone());
+            inboxes.push(Inbox {
+                receiver,
+                stalled: flag,
+            });
+        }
+
+        (
+            Self {
+                senders,
+                stalled,
+                stall_timeout: STALL_TIMEOUT,
+                position: 0,
+            },
+            inboxes,
+        )
+    }
+
+    /// Writes `data` right after the data written last.
+    pub async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_at(self.position, data).await
+    }
+
+    /// Writes `data` at `offset` bytes into the drives.
+    pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.position = offset + data.len() as u64;
+
+        let data = Arc::<[u8]>::from(data);
+        self.send(|| Message::Write(offset, data.clone())).await
+    }
+
+    /// Reports `status` for each drive once it caught up with what was written so far.
+    pub async fn set_status(&mut self, status: FlashStatus) {
+        // Failing here means no drive is left, which the next write reports
+        let _ = self.send(|| Message::Status(status.clone())).await;
+    }
+
+    async fn send<F: Fn() -> Message>(&mut self, message: F) -> std::io::Result<()> {
+        // All drives at once, so that a slow one doesn't hold up the others
+        let sent = futures::future::join_all(self.senders.iter().map(|slot| async {
+            let sender = slot.as_ref()?;
+            Some(tokio::time::timeout(self.stall_timeout, sender.send(message())).await)
+        }))
+        .await;
+
+        for ((slot, stalled), sent) in self.senders.iter_mut().zip(&self.stalled).zip(sent) {
+            match sent {
+                Some(Ok(Ok(()))) | None => {}
+                // The writer hung up, after reporting why itself
+                Some(Ok(Err(_))) => *slot = None,
+                Some(Err(_)) => {
+                    warn!(
+                        "Dropping a drive that took no data for {} seconds",
+                        self.stall_timeout.as_secs()
+                    );
+                    stalled.store(true, Ordering::SeqCst);
+                    *slot = None;
+                }
+            }
+        }
+
+        if self.senders.iter().any(Option::is_some) {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Writing failed on every drive",
+            ))
+        }
+    }
+}
+
+/// Writes everything that `inbox` gets from a [`FanOut`] to `file`,
+/// until the fan-out is dropped, and returns checksums of what was written.
+pub async fn drain<F: Fn(FlashStatus)>(
+    file: &mut File,
+    zero_blocks: ZeroBlocks,
+    mut inbox: Inbox,
+    set_status: F,
+) -> std::io::Result<BlockChecksums> {
+    let mut writer = DeviceWriter::new(file, zero_blocks);
+
+    while let Some(message) = inbox.recv().await
+        && !inbox.is_stalled()
+    {
+        match message {
+            Message::Write(offset, data) => writer.write_at(offset, &data).await?,
+            Message::Status(status) => set_status(status),
+        }
+    }
+
+    if inbox.is_stalled() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!(
+                "The drive took no data for {} seconds",
+                STALL_TIMEOUT.as_secs()
+            ),
+        ));
+    }
+
+    // Without these, nothing says that the data ever made it to the drive
+    writer.flush().await?;
+    let checksums = writer.into_checksums();
+    file.sync_all().await?;
+
+    Ok(checksums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build runtime")
+            .block_on(future)
+    }
+
+    /// Collects the data written through `inbox`, in order.
+    async fn collect(mut inbox: Inbox) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Some(message) = inbox.recv().await {
+            if let Message::Write(_, chunk) = message {
+                data.extend_from_slice(&chunk);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn drops_drives_that_stall() {
+        let (mut fan_out, mut inboxes) = FanOut::new(2);
+        fan_out.stall_timeout = Duration::from_millis(50);
+        let stalled = inboxes.pop().expect("No inbox");
+        let working = inboxes.pop().expect("No inbox");
+        let chunks = (0..=u8::MAX).take(2 * WRITES_IN_FLIGHT).collect::<Vec<_>>();
+
+        let (written, data) = block_on(async {
+            futures::join!(
+                async {
+                    for chunk in &chunks {
+                        fan_out.write(&[*chunk]).await?;
+                    }
+                    drop(fan_out);
+                    std::io::Result::Ok(())
+                },
+                collect(working)
+            )
+        });
+
+        written.expect("Writing failed");
+        assert_eq!(data, chunks);
+        assert!(stalled.is_stalled());
+    }
+
+    #[test]
+    fn returns_sync_errors() {
+        let (mut fan_out, mut inboxes) = FanOut::new(1);
+        let inbox = inboxes.pop().expect("No inbox");
+
+        let (written, drained) = block_on(async {
+            // Everything can be written to it, but it can't be synced
+            let mut file = File::options()
+                .write(true)
+                .open("/dev/null")
+                .await
+                .expect("Failed to open /dev/null");
+
+            futures::join!(
+                async {
+                    let result = fan_out.write(&[1; 1024]).await;
+                    drop(fan_out);
+                    result
+                },
+                drain(&mut file, ZeroBlocks::Write, inbox, |_| {})
+            )
+        });
+
+        written.expect("Writing failed");
+        let error = drained.expect_err("Syncing didn't fail");
+        assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn fails_once_every_drive_is_gone() {
+        let (mut fan_out, inboxes) = FanOut::new(2);
+        drop(inboxes);
+
+        let error = block_on(fan_out.write(b"data")).expect_err("Wrote to no drive");
+
+        assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}